@@ -153,7 +153,7 @@ where
 {
     let secret = get_secret();
     let mut dapol = Dapol::<D, R>::new_blank(tree_height, tree_height);
-    dapol.build(&items, &secret);
+    dapol.build(items, &secret);
     dapol
 }
 
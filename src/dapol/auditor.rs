@@ -0,0 +1,144 @@
+use super::{
+    blinding_from_seed, derive_audit_id, derive_blind_token, derive_index_token, index_to_u64,
+    seed_from_token, shuffle_index, Dapol, LiabilityId, MAX_TREE_HEIGHT,
+};
+use crate::{errors::DapolError, DapolProofNode, RangeProvable, RangeVerifiable};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
+use rayon::prelude::*;
+use smtree::{index::TreeIndex, traits::Serializable};
+use std::collections::HashMap;
+
+// AUDITOR TOKENS
+// ================================================================================================
+
+/// Kinds of tokens derived from the audit ID of a user which can be disclosed to an auditor.
+///
+/// Together with the external ID of a user, an index token lets an auditor check the position of
+/// the user's leaf in the tree, and a blind token lets an auditor check the commitment of the
+/// user's leaf. Neither token reveals the internal ID or the audit ID of the user, and an index
+/// token cannot be used to open the commitment of the leaf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditorTokenKind {
+    /// Index seed subkey of audit_id (see [crate::kdf::SubkeyPurpose::IndexSeed]).
+    Index,
+    /// Blind seed subkey of audit_id (see [crate::kdf::SubkeyPurpose::BlindSeed]).
+    Blind,
+}
+
+impl AuditorTokenKind {
+    fn derive(&self, audit_id: &[u8]) -> Vec<u8> {
+        match self {
+            AuditorTokenKind::Index => derive_index_token(audit_id),
+            AuditorTokenKind::Blind => derive_blind_token(audit_id),
+        }
+    }
+}
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable,
+{
+    /// Returns the auditor token of the specified kind for the liability identified by the
+    /// specified `internal_id`. If a liability for the specified ID does not exist in the tree,
+    /// None is returned.
+    pub fn generate_auditor_token(
+        &self,
+        internal_id: &LiabilityId,
+        kind: AuditorTokenKind,
+    ) -> Option<Vec<u8>> {
+        self.id_to_idx_map.get(internal_id)?;
+        let audit_id = derive_audit_id::<D>(&self.audit_seed, internal_id);
+        Some(kind.derive(&audit_id))
+    }
+
+    /// Returns auditor tokens of the specified kind for all liabilities in the tree, keyed by the
+    /// internal IDs of the liabilities, together with the indexes of their leaves. This way, the
+    /// tokens can be matched with the external IDs of the users before being handed to an
+    /// auditor.
+    pub fn generate_auditor_tokens(
+        &self,
+        kind: AuditorTokenKind,
+    ) -> HashMap<LiabilityId, (TreeIndex, Vec<u8>)> {
+        let audit_seed = &self.audit_seed;
+        self.id_to_idx_map
+            .par_iter()
+            .map(|(internal_id, leaf_index)| {
+                let audit_id = derive_audit_id::<D>(audit_seed, internal_id);
+                (internal_id.clone(), (*leaf_index, kind.derive(&audit_id)))
+            })
+            .collect()
+    }
+}
+
+// AUDITOR VERIFICATION
+// ================================================================================================
+
+/// Verifies that the leaf at the specified index is placed where the user with the specified
+/// external ID and index token (see [AuditorTokenKind::Index]) should be.
+///
+/// The leaf may be placed at any of the positions which the user could have been assigned when
+/// resolving index collisions, and so the check passes if the leaf is at any of them.
+pub fn verify_leaf_index<D: Digest>(
+    index_token: &[u8],
+    external_id: &LiabilityId,
+    leaf_index: &TreeIndex,
+) -> Result<(), DapolError> {
+    check_leaf_index::<D>(&seed_from_token::<D>(index_token, external_id), leaf_index)
+}
+
+/// Verifies that the specified leaf commits to the specified value of the user with the specified
+/// external ID and blind token (see [AuditorTokenKind::Blind]), and that the hash of the leaf is
+/// the hash of its commitment in a tree with the specified context string.
+///
+/// Returns an error if:
+/// * The commitment of the leaf does not open to the specified value with the blinding factor
+///   derived from the blind token.
+/// * The hash of the leaf does not match the hash recomputed from its commitment and the context.
+pub fn verify_leaf_commitment<D>(
+    blind_token: &[u8],
+    external_id: &LiabilityId,
+    value: u64,
+    context: &[u8],
+    leaf: &DapolProofNode<D>,
+) -> Result<(), DapolError>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+{
+    let blinding = blinding_from_seed(&seed_from_token::<D>(blind_token, external_id));
+    let com = PedersenGens::default().commit(Scalar::from(value), blinding);
+    if leaf.get_com() != com {
+        return Err(DapolError::CommitmentMismatch);
+    }
+    if *leaf != DapolProofNode::new_leaf(com, context) {
+        return Err(DapolError::LeafHashMismatch);
+    }
+    Ok(())
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Returns an error if the specified leaf index is not one of the positions which could have been
+/// assigned to a user with the specified index seed.
+pub(super) fn check_leaf_index<D: Digest>(
+    index_seed: &[u8],
+    leaf_index: &TreeIndex,
+) -> Result<(), DapolError> {
+    let tree_height = leaf_index.get_height();
+    if tree_height == 0 || tree_height > MAX_TREE_HEIGHT {
+        return Err(DapolError::UnexpectedLeafIndex(tree_height, 0));
+    }
+    let leaf_pos = index_to_u64(leaf_index);
+    let mut candidates = Vec::new();
+    shuffle_index::<D>(index_seed, tree_height, |index| {
+        candidates.push(index);
+        false
+    });
+    if !candidates.contains(&leaf_pos) {
+        return Err(DapolError::UnexpectedLeafIndex(tree_height, leaf_pos));
+    }
+    Ok(())
+}
@@ -0,0 +1,389 @@
+use super::{parallel::run_in_thread_pool, Dapol, LiabilityId};
+use crate::{
+    errors::DapolError, DapolProof, DapolProofNode, RangeProofContext, RangeProvable,
+    RangeVerifiable,
+};
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
+use smtree::{
+    index::TreeIndex,
+    proof::MerkleProof,
+    traits::{ProofExtractable, Serializable},
+    tree::NodeType,
+};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+// CONSTANTS
+// ================================================================================================
+
+// Subtrees rooted this many levels below the level at which there are at least as many nodes as
+// threads are traversed sequentially; splitting the work further only adds overhead.
+const EXTRA_PARALLEL_LEVELS: usize = 2;
+
+const PROOF_FILE_EXTENSION: &str = "proof";
+
+// PROOF SINKS
+// ================================================================================================
+
+/// A destination for proofs exported via [Dapol::export_all_proofs()]. Proofs are written from
+/// multiple threads concurrently and in no particular order.
+pub trait ProofSink<D, R>: Sync
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    /// Writes the proof for the liability with the specified internal ID.
+    fn write_proof(
+        &self,
+        internal_id: &LiabilityId,
+        proof: &DapolProof<D, R>,
+    ) -> Result<(), DapolError>;
+}
+
+impl<D, R, F> ProofSink<D, R> for F
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+    F: Fn(&LiabilityId, &DapolProof<D, R>) -> Result<(), DapolError> + Sync,
+{
+    fn write_proof(
+        &self,
+        internal_id: &LiabilityId,
+        proof: &DapolProof<D, R>,
+    ) -> Result<(), DapolError> {
+        self(internal_id, proof)
+    }
+}
+
+/// Writes each proof into a separate file in a directory. Files are named after the hex-encoded
+/// internal IDs of liabilities, and contain serialized proofs.
+#[derive(Debug, Clone)]
+pub struct DirectorySink {
+    dir: PathBuf,
+}
+
+impl DirectorySink {
+    /// Returns a sink writing proofs into the specified directory; the directory is created if it
+    /// does not exist.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self, DapolError> {
+        fs::create_dir_all(dir.as_ref()).map_err(export_failed)?;
+        Ok(DirectorySink {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Returns the path of the file holding the proof for the specified internal ID.
+    pub fn proof_path(&self, internal_id: &LiabilityId) -> PathBuf {
+        self.dir
+            .join(to_hex(internal_id.as_bytes()))
+            .with_extension(PROOF_FILE_EXTENSION)
+    }
+}
+
+impl<D, R> ProofSink<D, R> for DirectorySink
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    fn write_proof(
+        &self,
+        internal_id: &LiabilityId,
+        proof: &DapolProof<D, R>,
+    ) -> Result<(), DapolError> {
+        fs::write(self.proof_path(internal_id), proof.serialize()).map_err(export_failed)
+    }
+}
+
+/// Writes all proofs into a single archive file. Each proof is stored as a record consisting of
+/// the 4-byte length of the internal ID, the internal ID, the 8-byte length of the serialized
+/// proof and the serialized proof, with all integers encoded in big-endian order.
+#[derive(Debug)]
+pub struct ArchiveSink {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl ArchiveSink {
+    /// Returns a sink writing proofs into a newly created archive file at the specified path.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, DapolError> {
+        let file = File::create(path).map_err(export_failed)?;
+        Ok(ArchiveSink {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Flushes all buffered records into the archive file.
+    pub fn finish(self) -> Result<(), DapolError> {
+        let mut writer = self.writer.into_inner().map_err(writer_poisoned)?;
+        writer.flush().map_err(export_failed)
+    }
+
+    /// Reads all records of the archive at the specified path, and returns a list of internal IDs
+    /// together with serialized proofs.
+    pub fn read_archive<P: AsRef<Path>>(
+        path: P,
+    ) -> Result<Vec<(LiabilityId, Vec<u8>)>, DapolError> {
+        let file = File::open(path).map_err(export_failed)?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        let mut len_bytes = [0u8; 4];
+        loop {
+            match reader.read_exact(&mut len_bytes) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(export_failed(err)),
+            }
+            let id = read_bytes(&mut reader, u32::from_be_bytes(len_bytes) as u64)?;
+            let mut proof_len = [0u8; 8];
+            reader.read_exact(&mut proof_len).map_err(export_failed)?;
+            let proof = read_bytes(&mut reader, u64::from_be_bytes(proof_len))?;
+            records.push((LiabilityId(id), proof));
+        }
+        Ok(records)
+    }
+}
+
+impl<D, R> ProofSink<D, R> for ArchiveSink
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    fn write_proof(
+        &self,
+        internal_id: &LiabilityId,
+        proof: &DapolProof<D, R>,
+    ) -> Result<(), DapolError> {
+        let id = internal_id.as_bytes();
+        let proof = proof.serialize();
+        let mut record = Vec::with_capacity(4 + id.len() + 8 + proof.len());
+        record.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        record.extend_from_slice(id);
+        record.extend_from_slice(&(proof.len() as u64).to_be_bytes());
+        record.extend_from_slice(&proof);
+
+        let mut writer = self.writer.lock().map_err(writer_poisoned)?;
+        writer.write_all(&record).map_err(export_failed)
+    }
+}
+
+// BULK EXPORT
+// ================================================================================================
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug + Send + Sync,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable + Send + Sync,
+{
+    /// Generates proofs for all liabilities in the tree and writes them into the specified sink,
+    /// using the specified number of threads (or all available cores if not specified). Returns
+    /// the number of exported proofs.
+    ///
+    /// The tree is traversed once in depth-first order. Range proofs of siblings along the path
+    /// to a node are shared by all leaves below the node, and so they are generated only once
+    /// for each subtree rather than once for each leaf.
+    ///
+    /// Returns an error if the sink fails to write a proof, in which case some proofs may have
+    /// already been written.
+    pub fn export_all_proofs<S: ProofSink<D, R>>(
+        &self,
+        sink: &S,
+        num_threads: Option<usize>,
+    ) -> Result<usize, DapolError> {
+        let ids: HashMap<&TreeIndex, &LiabilityId> = self
+            .id_to_idx_map
+            .iter()
+            .map(|(id, idx)| (idx, id))
+            .collect();
+        let num_exported = AtomicUsize::new(0);
+        self.for_each_proof(num_threads, |idx, proof| {
+            // leaves which are not mapped to liabilities cannot be handed out to anyone
+            if let Some(internal_id) = ids.get(idx) {
+                sink.write_proof(internal_id, &proof)?;
+                num_exported.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        })?;
+        Ok(num_exported.into_inner())
+    }
+
+    /// Generates proofs for all leaves of the tree and passes them to `f` together with the
+    /// indexes of the leaves.
+    pub(super) fn for_each_proof<F>(
+        &self,
+        num_threads: Option<usize>,
+        f: F,
+    ) -> Result<(), DapolError>
+    where
+        F: Fn(&TreeIndex, DapolProof<D, R>) -> Result<(), DapolError> + Sync,
+    {
+        run_in_thread_pool(num_threads, || {
+            let num_threads = rayon::current_num_threads();
+            let parallel_height = (num_threads.next_power_of_two().trailing_zeros() as usize
+                + EXTRA_PARALLEL_LEVELS)
+                .min(self.smt.get_height());
+            let traversal = Traversal {
+                tree: self,
+                root_hash: self.root_raw().get_hash(),
+                parallel_height,
+                f: &f,
+            };
+            let mut path = PathState::new(self.bit_size);
+            traversal.visit(TreeIndex::zero(0), self.smt.get_root_ref(), &mut path)
+        })?
+    }
+}
+
+// HELPER TYPES
+// ================================================================================================
+
+/// Siblings along the path from the root to the currently visited node, together with range
+/// proofs for their commitments.
+#[derive(Clone)]
+struct PathState<D, R> {
+    values: Vec<u64>,
+    blindings: Vec<Scalar>,
+    siblings: Vec<DapolProofNode<D>>,
+    positions: Vec<TreeIndex>,
+    range_proof: R,
+}
+
+impl<D, R: RangeProvable> PathState<D, R> {
+    fn new(bit_size: usize) -> Self {
+        PathState {
+            values: Vec::new(),
+            blindings: Vec::new(),
+            siblings: Vec::new(),
+            positions: Vec::new(),
+            range_proof: R::new(&[], &[], bit_size)
+                .expect("bit size of the tree should be supported"),
+        }
+    }
+}
+
+/// Depth-first traversal of a tree which generates proofs for all leaves.
+struct Traversal<'a, D, R, F> {
+    tree: &'a Dapol<D, R>,
+    root_hash: &'a [u8],
+    parallel_height: usize,
+    f: &'a F,
+}
+
+impl<'a, D, R, F> Traversal<'a, D, R, F>
+where
+    D: Digest + Default + Clone + std::fmt::Debug + Send + Sync,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable + Send + Sync,
+    F: Fn(&TreeIndex, DapolProof<D, R>) -> Result<(), DapolError> + Sync,
+{
+    fn visit(
+        &self,
+        idx: TreeIndex,
+        smt_ref: usize,
+        path: &mut PathState<D, R>,
+    ) -> Result<(), DapolError> {
+        let node = self.tree.smt.get_node_by_ref(smt_ref);
+        let (lch, rch) = match node.get_node_type() {
+            NodeType::Leaf => {
+                let mut merkle = MerkleProof::new(idx);
+                merkle.set_siblings(path.siblings.clone());
+                return (self.f)(&idx, DapolProof::new(merkle, path.range_proof.clone()));
+            }
+            NodeType::Padding => return Ok(()),
+            NodeType::Internal => match (node.get_lch(), node.get_rch()) {
+                (Some(lch), Some(rch)) => (lch, rch),
+                _ => return Ok(()),
+            },
+        };
+        let lch_idx = idx.get_lch_index();
+        let rch_idx = idx.get_rch_index();
+
+        // the sibling of each child is the other child
+        if idx.get_height() < self.parallel_height {
+            let mut rch_path = path.clone();
+            let (lch_result, rch_result) = rayon::join(
+                || self.descend(lch_idx, lch, rch_idx, rch, path),
+                || self.descend(rch_idx, rch, lch_idx, lch, &mut rch_path),
+            );
+            lch_result.and(rch_result)
+        } else {
+            self.descend(lch_idx, lch, rch_idx, rch, path)?;
+            self.descend(rch_idx, rch, lch_idx, lch, path)
+        }
+    }
+
+    /// Visits the specified child after adding its sibling to the path.
+    fn descend(
+        &self,
+        idx: TreeIndex,
+        smt_ref: usize,
+        sibling_idx: TreeIndex,
+        sibling_ref: usize,
+        path: &mut PathState<D, R>,
+    ) -> Result<(), DapolError> {
+        let sibling = self.tree.smt.get_node_by_ref(sibling_ref).get_value();
+        path.values.push(sibling.get_value());
+        path.blindings.push(sibling.get_blinding());
+        path.siblings.push(sibling.get_proof_node());
+        path.positions.push(sibling_idx);
+        let context =
+            RangeProofContext::new(self.root_hash, self.tree.smt.get_height(), &path.positions);
+        let aggregation_factor = self.tree.aggregation_factor;
+        path.range_proof.generate_proof_by_new_com(
+            &path.values,
+            &path.blindings,
+            &context,
+            aggregation_factor,
+        );
+
+        let result = self.visit(idx, smt_ref, path);
+
+        path.range_proof
+            .remove_proof_by_last_com(path.values.len(), aggregation_factor);
+        path.values.pop();
+        path.blindings.pop();
+        path.siblings.pop();
+        path.positions.pop();
+        result
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Reads the specified number of bytes, failing if the reader runs out of bytes.
+fn read_bytes<T: Read>(reader: &mut T, num_bytes: u64) -> Result<Vec<u8>, DapolError> {
+    let mut bytes = Vec::new();
+    reader
+        .take(num_bytes)
+        .read_to_end(&mut bytes)
+        .map_err(export_failed)?;
+    if bytes.len() as u64 != num_bytes {
+        return Err(DapolError::ProofExportFailed(
+            "unexpected end of archive".to_string(),
+        ));
+    }
+    Ok(bytes)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn export_failed(err: std::io::Error) -> DapolError {
+    DapolError::ProofExportFailed(err.to_string())
+}
+
+/// A thread panicked while writing into the archive, and so the archive may end with a partially
+/// written record.
+fn writer_poisoned<T>(_: PoisonError<T>) -> DapolError {
+    DapolError::ProofExportFailed(
+        "archive writer lock is poisoned, the archive may hold a partial record".to_string(),
+    )
+}
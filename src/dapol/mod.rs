@@ -1,12 +1,21 @@
-use crate::{errors::DapolError, DapolProof, DapolProofNode, RangeProvable, RangeVerifiable};
+use crate::{
+    errors::DapolError,
+    kdf::{self, SubkeyPurpose},
+    proof::get_sibling_indexes,
+    range::DEFAULT_BIT_SIZE,
+    utils::get_secret,
+    DapolProof, DapolProofNode, RangeProofCache, RangeProofContext, RangeProvable, RangeVerifiable,
+    SolvencyProof, TotalProof,
+};
 use curve25519_dalek_ng::scalar::Scalar;
 use digest::Digest;
+use rayon::prelude::*;
 use smtree::{
     index::TreeIndex,
     pad_secret::Secret,
     proof::MerkleProof,
     traits::{ProofExtractable, Serializable},
-    tree::SparseMerkleTree,
+    tree::{NodeType, SparseMerkleTree},
 };
 use std::{
     collections::{HashMap, HashSet},
@@ -17,15 +26,41 @@ use std::{
 mod node;
 pub use node::DapolNode;
 
+mod multi_asset;
+pub use multi_asset::{MultiAssetDapol, MultiAssetLiability, MultiAssetNode};
+
+mod options;
+pub use options::{DapolOptions, DapolOptionsBuilder};
+
+mod parallel;
+use parallel::{build_tree, run_in_thread_pool, ParallelNode};
+
+mod snapshot;
+
+mod stream;
+
+mod export;
+pub use export::{ArchiveSink, DirectorySink, ProofSink};
+
+mod user;
+pub use user::{verify_user_proof, UserInclusionPackage};
+
+mod auditor;
+use auditor::check_leaf_index;
+pub use auditor::{verify_leaf_commitment, verify_leaf_index, AuditorTokenKind};
+
+mod sample;
+pub use sample::{derive_sample_seed, LeafOpening, SampleOpening};
+
 #[cfg(test)]
 mod tests;
 
 // CONSTANTS
 // ================================================================================================
 
-const MAX_TREE_HEIGHT: usize = 64;
+pub(crate) const MAX_TREE_HEIGHT: usize = 64;
 const MIN_SPARSITY: usize = 2;
-const DIGEST_SIZE: usize = 32;
+const MIN_DIGEST_SIZE: usize = 32;
 const MAX_INDEX_RETRIES: usize = 128;
 
 // SUPPORTING TYPES
@@ -34,22 +69,29 @@ const MAX_INDEX_RETRIES: usize = 128;
 type TreeInputs<D> = Vec<(TreeIndex, DapolNode<D>)>;
 type IdToIndexMap = HashMap<LiabilityId, TreeIndex>;
 
+/// Seeds derived for a single liability during tree construction.
+struct LeafSeeds {
+    audit_id: Vec<u8>,
+    index_seed: Vec<u8>,
+    blind_seed: Vec<u8>,
+    blinding: Scalar,
+}
+
 /// Represents either an internal or external liability ID, which is just a vector of bytes.
+///
+/// With the `serde` feature, the ID is serialized as a hex string.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct LiabilityId(Vec<u8>);
-
-/// Defines options for a specific instance of Dapol tree. `audit_id` could be a concatenation of
-/// a randomly derived seed and a date of th audit. `tree_height` cannot exceed 64.
-pub struct DapolOptions {
-    audit_seed: Vec<u8>,
-    tree_height: usize,
-    aggregation_factor: usize,
-    secret: Secret, // TODO: should we derive this from `audit_seed`?
-}
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+pub struct LiabilityId(#[cfg_attr(feature = "serde", serde(with = "crate::utils::hex"))] Vec<u8>);
 
 /// Represents a single liability. `internal_id` is the unique identifier of an account internal
 /// to the audited system, while `external_id` is a unique identifier known to the user (e.g. an
 /// email address). External and internal IDs can be the same.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Liability {
     pub internal_id: LiabilityId,
     pub external_id: LiabilityId,
@@ -61,6 +103,7 @@ pub struct Liability {
 
 impl LiabilityId {
     /// Returns an new liability ID constructed from the specified string.
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(id: &str) -> Self {
         LiabilityId(id.as_bytes().to_vec())
     }
@@ -74,14 +117,31 @@ impl LiabilityId {
 // DAPOL
 // ================================================================================================
 
-#[derive(Default, Debug)]
 pub struct Dapol<D, R> {
     smt: SparseMerkleTree<DapolNode<D>>,
     id_to_idx_map: IdToIndexMap,
     aggregation_factor: usize,
+    bit_size: usize,
+    audit_seed: Vec<u8>,
+    context: Vec<u8>,
+    secret: Secret,
+    range_proof_cache: Option<RangeProofCache>,
     _phantom_r: PhantomData<R>,
 }
 
+impl<D: std::fmt::Debug, R> std::fmt::Debug for Dapol<D, R> {
+    /// Formats the tree without revealing the audit seed and the padding secret.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dapol")
+            .field("smt", &self.smt)
+            .field("id_to_idx_map", &self.id_to_idx_map)
+            .field("aggregation_factor", &self.aggregation_factor)
+            .field("bit_size", &self.bit_size)
+            .field("context", &self.context)
+            .finish()
+    }
+}
+
 impl<D, R> Dapol<D, R>
 where
     D: Digest + Default + Clone + std::fmt::Debug,
@@ -89,40 +149,52 @@ where
 {
     // CONSTRUCTOR
     // --------------------------------------------------------------------------------------------
-    /// Builds a new instance of DAPOL tree from the provided set of liabilities;
+    /// Builds a new instance of DAPOL tree from the provided set of liabilities; the tree is built
+    /// using the number of threads specified in the options.
     ///
     /// Returns an error if:
-    /// * The specified digest does not output 32-byte hashes.
-    /// * Requested tree height exceeds 64.
+    /// * The specified digest outputs hashes shorter than 32 bytes.
     /// * The number of liabilities is not at least 4 times smaller than the total number of
     ///   of leaves in the tree.
+    /// * Any of the liabilities, or the total of all liabilities, does not fit into the range
+    ///   covered by range proofs.
     /// * List of liabilities contains duplicated internal IDs.
+    /// * The thread pool for building the tree could not be created.
     pub fn new(liabilities: Vec<Liability>, options: DapolOptions) -> Result<Self, DapolError> {
-        if D::output_size() != DIGEST_SIZE {
-            return Err(DapolError::InvalidDigestSize(DIGEST_SIZE, D::output_size()));
-        }
-        if options.tree_height > MAX_TREE_HEIGHT {
-            return Err(DapolError::TreeHeightTooBig(
-                MAX_TREE_HEIGHT,
-                options.tree_height,
-            ));
-        }
-        if 2usize.pow(options.tree_height as u32) < liabilities.len() * MIN_SPARSITY {
+        check_digest_size::<D>()?;
+        if 2u128.pow(options.tree_height as u32) < (liabilities.len() * MIN_SPARSITY) as u128 {
             return Err(DapolError::SparsityTooSmall(
                 liabilities.len(),
                 liabilities.len() * MIN_SPARSITY,
                 options.tree_height,
             ));
         }
+        let bit_size = options.range_proof_bits;
+        for liability in liabilities.iter() {
+            check_value(&liability.internal_id, liability.value, bit_size)?;
+        }
+        check_total(liabilities.iter().map(|l| l.value as u128).sum(), bit_size)?;
+
+        let (smt, id_to_idx_map) = run_in_thread_pool(options.num_threads, || {
+            let (tree_inputs, id_to_idx_map) = build_leaf_nodes(
+                liabilities,
+                &options.audit_seed,
+                &options.context,
+                options.tree_height,
+            )?;
+            let smt = build_tree(options.tree_height, &tree_inputs, &options.secret)?;
+            Ok::<_, DapolError>((smt, id_to_idx_map))
+        })??;
 
-        let mut smt = SparseMerkleTree::<DapolNode<D>>::new(options.tree_height);
-        let (tree_inputs, id_to_idx_map) =
-            build_leaf_nodes(liabilities, &options.audit_seed, options.tree_height)?;
-        smt.build(&tree_inputs, &options.secret);
         Ok(Dapol {
             smt,
             id_to_idx_map,
             aggregation_factor: options.aggregation_factor,
+            bit_size,
+            audit_seed: options.audit_seed,
+            context: options.context,
+            secret: options.secret,
+            range_proof_cache: new_range_proof_cache(options.range_proof_cache_size),
             _phantom_r: PhantomData,
         })
     }
@@ -140,6 +212,150 @@ where
         self.smt.get_root()
     }
 
+    /// Returns the context string bound into the hashes of all leaves of the tree.
+    pub fn context(&self) -> &[u8] {
+        &self.context
+    }
+
+    /// Returns the bit width of range proofs generated for this tree.
+    pub fn bit_size(&self) -> usize {
+        self.bit_size
+    }
+
+    /// Returns the cache of range proofs shared by proof requests, if range proofs are cached.
+    pub fn range_proof_cache(&self) -> Option<&RangeProofCache> {
+        self.range_proof_cache.as_ref()
+    }
+
+    /// Replaces the cache of range proofs with an empty cache holding at most the specified
+    /// number of range proofs; zero disables caching. This is useful for trees loaded from
+    /// snapshots, which are loaded without a cache.
+    pub fn set_range_proof_cache_size(&mut self, range_proof_cache_size: usize) {
+        self.range_proof_cache = new_range_proof_cache(range_proof_cache_size);
+    }
+
+    // LIABILITY UPDATES
+    // --------------------------------------------------------------------------------------------
+
+    /// Adds a new liability to the tree and returns the index of the leaf it was placed at. Only
+    /// the nodes along the path from the new leaf to the root are recomputed.
+    ///
+    /// Returns an error if:
+    /// * A liability with the same internal ID already exists in the tree.
+    /// * The tree would become too dense after adding the liability.
+    /// * The liability, or the total of all liabilities, would not fit into the range covered by
+    ///   range proofs.
+    /// * The liability could not be mapped to an empty leaf.
+    pub fn insert_liability(&mut self, liability: Liability) -> Result<TreeIndex, DapolError> {
+        if self.id_to_idx_map.contains_key(&liability.internal_id) {
+            return Err(DapolError::DuplicatedInternalId(liability.internal_id.0));
+        }
+        let num_liabilities = self.id_to_idx_map.len() + 1;
+        let tree_height = self.smt.get_height();
+        if 2u128.pow(tree_height as u32) < (num_liabilities * MIN_SPARSITY) as u128 {
+            return Err(DapolError::SparsityTooSmall(
+                num_liabilities,
+                num_liabilities * MIN_SPARSITY,
+                tree_height,
+            ));
+        }
+        check_value(&liability.internal_id, liability.value, self.bit_size)?;
+        check_total(
+            self.root_raw().get_value() as u128 + liability.value as u128,
+            self.bit_size,
+        )?;
+
+        let seeds = derive_leaf_seeds::<D>(
+            &self.audit_seed,
+            &liability.internal_id,
+            &liability.external_id,
+        );
+        let index = shuffle_index::<D>(&seeds.index_seed, tree_height, |index| {
+            !self.is_leaf_occupied(index)
+        })
+        .ok_or_else(|| DapolError::FailedToMapIndex(seeds.audit_id.to_vec(), MAX_INDEX_RETRIES))?;
+
+        let node = DapolNode::<D>::new_with_context(liability.value, seeds.blinding, &self.context);
+        self.smt.update(&index, node, &self.secret);
+        self.id_to_idx_map.insert(liability.internal_id, index);
+        self.clear_range_proof_cache();
+        Ok(index)
+    }
+
+    /// Replaces the liability with the same internal ID as the specified liability, and returns
+    /// the index of the leaf the liability is located at. Only the nodes along the path from the
+    /// leaf to the root are recomputed.
+    ///
+    /// The leaf is re-derived from the specified liability, and so, if the external ID of the
+    /// liability has changed, the liability may be moved to a different leaf. In such a case, the
+    /// old leaf is replaced with a padding node.
+    ///
+    /// Returns an error if:
+    /// * A liability with the same internal ID does not exist in the tree.
+    /// * The internal ID is not mapped to a leaf of the tree.
+    /// * The liability, or the total of all liabilities, would not fit into the range covered by
+    ///   range proofs.
+    /// * The liability could not be mapped to an empty leaf.
+    pub fn update_liability(&mut self, liability: Liability) -> Result<TreeIndex, DapolError> {
+        let old_index = *self
+            .id_to_idx_map
+            .get(&liability.internal_id)
+            .ok_or_else(|| DapolError::UnknownInternalId(liability.internal_id.0.clone()))?;
+        let old_value = self
+            .smt
+            .get_leaf_by_index(&old_index)
+            .filter(|node| *node.get_node_type() == NodeType::Leaf)
+            .ok_or_else(|| DapolError::UnmappedInternalId(liability.internal_id.0.clone()))?
+            .get_value()
+            .get_value();
+        check_value(&liability.internal_id, liability.value, self.bit_size)?;
+        check_total(
+            self.root_raw().get_value() as u128 - old_value as u128 + liability.value as u128,
+            self.bit_size,
+        )?;
+
+        let seeds = derive_leaf_seeds::<D>(
+            &self.audit_seed,
+            &liability.internal_id,
+            &liability.external_id,
+        );
+        let tree_height = self.smt.get_height();
+        let index = shuffle_index::<D>(&seeds.index_seed, tree_height, |index| {
+            TreeIndex::from_u64(tree_height, index) == old_index || !self.is_leaf_occupied(index)
+        })
+        .ok_or_else(|| DapolError::FailedToMapIndex(seeds.audit_id.to_vec(), MAX_INDEX_RETRIES))?;
+
+        if index != old_index {
+            let padding = DapolNode::<D>::new_padding(&old_index, &self.secret);
+            self.smt.update(&old_index, padding, &self.secret);
+        }
+
+        let node = DapolNode::<D>::new_with_context(liability.value, seeds.blinding, &self.context);
+        self.smt.update(&index, node, &self.secret);
+        self.id_to_idx_map.insert(liability.internal_id, index);
+        self.clear_range_proof_cache();
+        Ok(index)
+    }
+
+    /// Removes the liability with the specified internal ID from the tree, and returns the index
+    /// of the leaf the liability was located at. The leaf is replaced with a padding node, and
+    /// only the nodes along the path from the leaf to the root are recomputed.
+    ///
+    /// Removed leaves are not reused for liabilities added later on.
+    ///
+    /// Returns an error if a liability with the specified internal ID does not exist in the tree.
+    pub fn remove_liability(&mut self, internal_id: &LiabilityId) -> Result<TreeIndex, DapolError> {
+        let index = self
+            .id_to_idx_map
+            .remove(internal_id)
+            .ok_or_else(|| DapolError::UnknownInternalId(internal_id.0.clone()))?;
+
+        let padding = DapolNode::<D>::new_padding(&index, &self.secret);
+        self.smt.update(&index, padding, &self.secret);
+        self.clear_range_proof_cache();
+        Ok(index)
+    }
+
     // PUBLIC METHODS
     // --------------------------------------------------------------------------------------------
 
@@ -183,12 +399,72 @@ where
             blindings.push(node.get_blinding());
         }
 
+        let positions = get_sibling_indexes(leaf_indexes);
+        let root_hash = self.root_raw().get_hash();
+        let mut context = RangeProofContext::new(root_hash, self.smt.get_height(), &positions);
+        if let Some(cache) = self.range_proof_cache.as_ref() {
+            context = context.with_cache(cache);
+        }
         Some(DapolProof::new(
             merkle_proof,
-            R::generate_proof(&values, &blindings, self.aggregation_factor),
+            R::generate_proof(
+                &values,
+                &blindings,
+                &context,
+                self.aggregation_factor,
+                self.bit_size,
+            ),
         ))
     }
 
+    /// Returns a proof that the commitment of the root opens to the total of all liabilities.
+    /// Publishing the proof discloses the total, but not the blinding factor of the root.
+    pub fn generate_total_proof(&self) -> TotalProof {
+        let root = self.root_raw();
+        TotalProof::generate(root.get_value(), &root.get_blinding())
+    }
+
+    /// Returns a proof that the specified reserves are at least as large as the total of all
+    /// liabilities. The proof is verified against the root and the commitment to the reserves
+    /// with the specified blinding factor, which can be computed via
+    /// [SolvencyProof::commit_reserves()].
+    ///
+    /// Returns an error if the reserves are smaller than the total of all liabilities.
+    pub fn generate_solvency_proof(
+        &self,
+        reserves: u64,
+        reserves_blinding: &Scalar,
+    ) -> Result<SolvencyProof, DapolError> {
+        let root = self.root_raw();
+        SolvencyProof::generate(
+            reserves,
+            reserves_blinding,
+            root.get_value(),
+            &root.get_blinding(),
+        )
+        .ok_or(DapolError::InsufficientReserves(reserves, root.get_value()))
+    }
+
+    // HELPER METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Removes all range proofs from the cache; cached proofs are bound to the root hash, and so
+    /// none of them can be reused once the tree changes.
+    fn clear_range_proof_cache(&self) {
+        if let Some(cache) = self.range_proof_cache.as_ref() {
+            cache.clear();
+        }
+    }
+
+    /// Returns true if the leaf at the specified position is a leaf node; padding nodes which
+    /// replaced removed liabilities are also considered to be leaf nodes.
+    fn is_leaf_occupied(&self, index: u64) -> bool {
+        let index = TreeIndex::from_u64(self.smt.get_height(), index);
+        self.smt
+            .get_leaf_by_index(&index)
+            .is_some_and(|node| *node.get_node_type() == NodeType::Leaf)
+    }
+
     // TEST METHODS
     // --------------------------------------------------------------------------------------------
     // TODO: methods below are used for testing only and should ideally be moved to a test module
@@ -199,12 +475,18 @@ where
             smt,
             id_to_idx_map: HashMap::new(),
             aggregation_factor,
+            bit_size: DEFAULT_BIT_SIZE,
+            audit_seed: Vec::new(),
+            context: Vec::new(),
+            secret: get_secret(),
+            range_proof_cache: None,
             _phantom_r: PhantomData,
         }
     }
 
     pub fn build(&mut self, input: &[(TreeIndex, DapolNode<D>)], secret: &Secret) {
         self.smt.build(input, secret);
+        self.secret = Secret::from_bytes(secret.as_bytes()).expect("failed to copy secret");
     }
 
     #[cfg(test)]
@@ -213,189 +495,195 @@ where
     }
 
     #[cfg(test)]
-    pub fn generate_all_proofs(&self) {
-        let mut secrets: Vec<u64> = Vec::new();
-        let mut blindings: Vec<Scalar> = Vec::new();
-        let mut merkle_siblings: Vec<DapolProofNode<D>> = Vec::new();
-        let mut range_proof: R = R::new(&[], &[]);
-        self.dfs(
-            TreeIndex::zero(0),
-            self.smt.get_root_ref(),
-            &mut secrets,
-            &mut blindings,
-            &mut merkle_siblings,
-            &mut range_proof,
-        );
-    }
-
-    #[cfg(test)]
-    fn dfs(
-        &self,
-        idx: TreeIndex,
-        smt_ref: usize,
-        secrets: &mut Vec<u64>,
-        blindings: &mut Vec<Scalar>,
-        merkle_siblings: &mut Vec<DapolProofNode<D>>,
-        range_proof: &mut R,
-    ) {
-        if idx.get_height() > self.smt.get_height() {
+    pub fn generate_all_proofs(&self)
+    where
+        D: Send + Sync,
+        R: Send + Sync,
+    {
+        let root = self.root();
+        self.for_each_proof(None, |idx, proof| {
             let leaf = self
                 .smt
-                .get_node_by_ref(smt_ref)
+                .get_leaf_by_index(idx)
+                .expect("proofs should be generated for leaves only")
                 .get_value()
                 .get_proof_node();
-            let mut merkle = MerkleProof::new(idx);
-            merkle.set_siblings(merkle_siblings.to_vec());
-            let proof = DapolProof::new(merkle, range_proof.clone());
-            // TODO: write the proofs to a local file or send them out.
-            if cfg!(test) {
-                let serialized_proof = proof.serialize();
-                let deserialized_proof =
-                    DapolProof::<D, R>::deserialize(&serialized_proof).unwrap();
-                assert!(deserialized_proof.verify(&self.root(), &leaf));
-            }
-            return;
-        }
-
-        let lch = self.smt.get_node_by_ref(smt_ref).get_lch();
-        if let Some(x) = lch {
-            self.dfs_handle_child(
-                idx.get_lch_index(),
-                x,
-                secrets,
-                blindings,
-                merkle_siblings,
-                range_proof,
-            );
-        }
-
-        let rch = self.smt.get_node_by_ref(smt_ref).get_rch();
-        if let Some(x) = rch {
-            self.dfs_handle_child(
-                idx.get_rch_index(),
-                x,
-                secrets,
-                blindings,
-                merkle_siblings,
-                range_proof,
-            );
-        }
-    }
-
-    #[cfg(test)]
-    fn dfs_handle_child(
-        &self,
-        idx: TreeIndex,
-        smt_ref: usize,
-        secrets: &mut Vec<u64>,
-        blindings: &mut Vec<Scalar>,
-        merkle_siblings: &mut Vec<DapolProofNode<D>>,
-        range_proof: &mut R,
-    ) {
-        let node = self.smt.get_node_by_ref(smt_ref).get_value();
-        secrets.push(node.get_value());
-        blindings.push(node.get_blinding());
-        merkle_siblings.push(node.get_proof_node());
-        range_proof.generate_proof_by_new_com(secrets, blindings, self.aggregation_factor);
-
-        self.dfs(
-            idx,
-            smt_ref,
-            secrets,
-            blindings,
-            merkle_siblings,
-            range_proof,
-        );
-
-        range_proof.remove_proof_by_last_com(secrets.len(), self.aggregation_factor);
-        secrets.pop();
-        blindings.pop();
-        merkle_siblings.pop();
+            let serialized_proof = proof.serialize();
+            let deserialized_proof = DapolProof::<D, R>::deserialize(&serialized_proof).unwrap();
+            assert!(deserialized_proof.verify(&root, &leaf));
+            Ok(())
+        })
+        .unwrap();
     }
 }
 
 // HELPER FUNCTIONS
 // ================================================================================================
 
-/// Converts a list of liabilities into a list of (TreeIndex, DapolNode) tuples. Tree index is
-/// derived from audit_id = hash(audit_seed || internal_id). The blinding factor is derived from
-/// user_audit_id = hash(audit_id || external_id).
+/// Converts a list of liabilities into a list of (TreeIndex, DapolNode) tuples. Tree index and
+/// the blinding factor are derived from audit_id = hash(audit_seed || internal_id) and the
+/// external ID of the liability (see [derive_index_seed] and [derive_blind_seed]).
 fn build_leaf_nodes<D: Digest>(
     liabilities: Vec<Liability>,
     audit_seed: &[u8],
+    context: &[u8],
     tree_height: usize,
 ) -> Result<(TreeInputs<D>, IdToIndexMap), DapolError> {
-    let mut hasher = D::new();
-
-    let mut internal_id_map = HashMap::with_capacity(liabilities.len());
+    let mut id_to_idx_map = HashMap::with_capacity(liabilities.len());
     let mut tree_index_set = HashSet::with_capacity(liabilities.len());
+    let result = add_leaf_nodes(
+        liabilities,
+        audit_seed,
+        context,
+        tree_height,
+        &mut id_to_idx_map,
+        &mut tree_index_set,
+    )?;
+    Ok((result, id_to_idx_map))
+}
 
-    let mut result = Vec::with_capacity(liabilities.len());
-    for Liability {
-        internal_id,
-        external_id,
-        value,
-    } in liabilities.into_iter()
-    {
-        // make sure all internal IDs are unique
-        if internal_id_map.contains_key(&internal_id) {
-            return Err(DapolError::DuplicatedInternalId(internal_id.0));
+/// Converts a list of liabilities into a list of (TreeIndex, DapolNode) tuples sorted by tree
+/// index, skipping tree indexes already present in `tree_index_set`. Internal IDs of all
+/// liabilities are added to `id_to_idx_map`, and their tree indexes are added to
+/// `tree_index_set`.
+///
+/// Seeds and commitments are computed in parallel, but tree indexes are assigned sequentially
+/// in the order of the liabilities. This way, index collisions are always resolved the same way
+/// regardless of the number of threads.
+fn add_leaf_nodes<D: Digest>(
+    liabilities: Vec<Liability>,
+    audit_seed: &[u8],
+    context: &[u8],
+    tree_height: usize,
+    id_to_idx_map: &mut IdToIndexMap,
+    tree_index_set: &mut HashSet<u64>,
+) -> Result<TreeInputs<D>, DapolError> {
+    // make sure all internal IDs are unique
+    let mut internal_ids = HashSet::with_capacity(liabilities.len());
+    for liability in liabilities.iter() {
+        if id_to_idx_map.contains_key(&liability.internal_id)
+            || !internal_ids.insert(&liability.internal_id)
+        {
+            return Err(DapolError::DuplicatedInternalId(
+                liability.internal_id.0.clone(),
+            ));
         }
+    }
+    drop(internal_ids);
+
+    let seeds: Vec<LeafSeeds> = liabilities
+        .par_iter()
+        .map(|liability| {
+            derive_leaf_seeds::<D>(audit_seed, &liability.internal_id, &liability.external_id)
+        })
+        .collect();
 
-        // compute audit ID as hash(audit_seed || internal_id); expect() should never be
-        // triggered because we make sure that hash digest is 32 bytes in the constructor
-        hasher.update(audit_seed);
-        hasher.update(&internal_id.0);
-        let audit_id: [u8; 32] = hasher
-            .finalize_reset()
-            .as_slice()
-            .try_into()
-            .expect("could not convert hash into a 32-byte value");
-
-        // derive tree index from index_seed = hash(audit_id || "index_seed" || external_id). This
-        // arrangement gives us the following properties:
-        // - the user can compute index_seed directly from audit_id without any additional info;
-        // - an auditor can be given hash(audit_di || "index_seed") and external_id and they will,
-        //   be able to compute index_seed, but this info will not allow them to compute the
-        //   blinding factor (computed below).
-        hasher.update(audit_id);
-        hasher.update("index_seed");
-        hasher.update(&external_id.0);
-        let index_seed = hasher
-            .finalize_reset()
-            .as_slice()
-            .try_into()
-            .expect("could not convert hash into a 32-byte value");
-        let index = shuffle_index::<D>(index_seed, tree_height, &mut tree_index_set)
-            .ok_or_else(|| DapolError::FailedToMapIndex(audit_id.to_vec(), MAX_INDEX_RETRIES))?;
-
-        // derive blinding factor from blind_seed = hash(audit_id || "blind_seed" || external_id).
-        // This arrangement gives us the following properties:
-        // - the user can compute blinding factor directly from audit_id without any additional info;
-        // - an auditor can be given the blind_seed, but this info won't be sufficient to
-        //   learn user's identity (i.e. external_id).
-        hasher.update(audit_id);
-        hasher.update("blind_seed");
-        hasher.update(&external_id.0);
-        let blind_seed: [u8; 32] = hasher
-            .finalize_reset()
-            .as_slice()
-            .try_into()
-            .expect("could not convert hash into a 32-byte value");
-        let blinding_factor = Scalar::from_bits(blind_seed);
-
-        // create a mapping between internal_id and tree index
-        internal_id_map.insert(internal_id, index);
-
-        // create the node and add it to the results
-        let node = DapolNode::<D>::new(value, blinding_factor);
-        result.push((index, node));
+    let mut indexes = Vec::with_capacity(liabilities.len());
+    for seed in seeds.iter() {
+        let index = shuffle_index::<D>(&seed.index_seed, tree_height, |index| {
+            tree_index_set.insert(index)
+        })
+        .ok_or_else(|| DapolError::FailedToMapIndex(seed.audit_id.to_vec(), MAX_INDEX_RETRIES))?;
+        indexes.push(index);
     }
 
+    // create the nodes
+    let mut result: TreeInputs<D> = liabilities
+        .par_iter()
+        .zip(seeds.par_iter())
+        .zip(indexes.par_iter())
+        .map(|((liability, seed), index)| {
+            let node = DapolNode::<D>::new_with_context(liability.value, seed.blinding, context);
+            (*index, node)
+        })
+        .collect();
+
+    // record the mapping between internal_id and tree index
+    id_to_idx_map.extend(
+        liabilities
+            .into_iter()
+            .zip(indexes)
+            .map(|(liability, index)| (liability.internal_id, index)),
+    );
+
     // sort by index as smtree requires inputs to be sorted
-    result.sort_by_key(|(index, _)| *index);
+    result.par_sort_unstable_by_key(|(index, _)| *index);
 
-    Ok((result, internal_id_map))
+    Ok(result)
+}
+
+/// Derives the audit ID, the index seed and the blinding factor for the liability with the
+/// specified internal and external IDs.
+fn derive_leaf_seeds<D: Digest>(
+    audit_seed: &[u8],
+    internal_id: &LiabilityId,
+    external_id: &LiabilityId,
+) -> LeafSeeds {
+    let audit_id = derive_audit_id::<D>(audit_seed, internal_id);
+    let index_seed = derive_index_seed::<D>(&audit_id, external_id);
+    let blind_seed = derive_blind_seed::<D>(&audit_id, external_id);
+    let blinding = blinding_from_seed(&blind_seed);
+
+    LeafSeeds {
+        audit_id,
+        index_seed,
+        blind_seed,
+        blinding,
+    }
+}
+
+/// Derives the audit ID of a liability as hash(audit_seed || internal_id); all seeds are full
+/// digests, and so their size is the output size of the hash function.
+fn derive_audit_id<D: Digest>(audit_seed: &[u8], internal_id: &LiabilityId) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(audit_seed);
+    hasher.update(&internal_id.0);
+    hasher.finalize().to_vec()
+}
+
+/// Derives the index seed of a user as hash(index_token || external_id), where index_token is
+/// the index seed subkey derived from audit_id (see [kdf::derive_subkey]).
+///
+/// This arrangement gives us the following properties:
+/// - the user can compute index_seed directly from audit_id without any additional info;
+/// - an auditor can be given index_token and external_id and they will be able to compute
+///   index_seed, but this info will not allow them to compute the blinding factor.
+fn derive_index_seed<D: Digest>(audit_id: &[u8], external_id: &LiabilityId) -> Vec<u8> {
+    seed_from_token::<D>(&derive_index_token(audit_id), external_id)
+}
+
+/// Derives the blind seed of a user as hash(blind_token || external_id), where blind_token is
+/// the blind seed subkey derived from audit_id (see [kdf::derive_subkey]).
+///
+/// This arrangement gives us the following properties:
+/// - the user can compute the blinding factor directly from audit_id without any additional info;
+/// - an auditor can be given the blind_seed, or blind_token and external_id, but this info won't
+///   be sufficient to learn user's audit_id or the index seed.
+fn derive_blind_seed<D: Digest>(audit_id: &[u8], external_id: &LiabilityId) -> Vec<u8> {
+    seed_from_token::<D>(&derive_blind_token(audit_id), external_id)
+}
+
+/// Derives the index token of a user as the index seed subkey of audit_id.
+fn derive_index_token(audit_id: &[u8]) -> Vec<u8> {
+    kdf::derive_subkey(SubkeyPurpose::IndexSeed, audit_id).to_vec()
+}
+
+/// Derives the blind token of a user as the blind seed subkey of audit_id.
+fn derive_blind_token(audit_id: &[u8]) -> Vec<u8> {
+    kdf::derive_subkey(SubkeyPurpose::BlindSeed, audit_id).to_vec()
+}
+
+/// Derives a seed of a user as hash(token || external_id).
+fn seed_from_token<D: Digest>(token: &[u8], external_id: &LiabilityId) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(token);
+    hasher.update(&external_id.0);
+    hasher.finalize().to_vec()
+}
+
+/// Converts a blind seed into the blinding factor of a leaf.
+fn blinding_from_seed(blind_seed: &[u8]) -> Scalar {
+    scalar_from_digest(blind_seed, Scalar::from_bits)
 }
 
 /// Tries find an index for a node based on the provided index_seed. The algorithm works as
@@ -405,33 +693,32 @@ fn build_leaf_nodes<D: Digest>(
 ///   and check again.
 /// - Repeat the previous steps until we find an empty slot, or exhaust the number of allowed
 ///   tries.
+///
+/// A position is considered available if `claim_index` returns true for it.
 fn shuffle_index<D: Digest>(
-    mut index_seed: [u8; 32],
+    index_seed: &[u8],
     tree_height: usize,
-    tree_index_set: &mut HashSet<u64>,
+    mut claim_index: impl FnMut(u64) -> bool,
 ) -> Option<TreeIndex> {
     let mut hasher = D::new();
+    let mut index_seed = index_seed.to_vec();
     let mut tree_index: Option<TreeIndex> = None;
 
     for _ in 0..MAX_INDEX_RETRIES {
         // compute hash(index_seed || external_id); on the first iteration, it is the same
         // as hash(audit_id || external_id)
         hasher.update(&index_seed);
-        index_seed = hasher
-            .finalize_reset()
-            .as_slice()
-            .try_into()
-            .expect("could not convert hash into a 32-byte value");
-
-        // convert the first 8 bytes of the seed into a potential tree index;
-        // unwrap is OK here because we know for sure that seed is at least 8 bytes.
+        index_seed = hasher.finalize_reset().to_vec();
+
+        // convert the first 8 bytes of the seed into a potential tree index; unwrap is OK here
+        // because digests used with DAPOL are always at least 32 bytes long.
         let index = u64::from_be_bytes(index_seed[..8].try_into().unwrap());
 
         // get rid of extra bits to make sure our index is within the bounds of the tree
         let index = index >> (64 - tree_height);
 
-        // if the index is not in the set, build a tree index from it and break the loop
-        if tree_index_set.insert(index) {
+        // if the index is available, build a tree index from it and break the loop
+        if claim_index(index) {
             tree_index = Some(TreeIndex::from_u64(tree_height, index));
             break;
         }
@@ -439,3 +726,64 @@ fn shuffle_index<D: Digest>(
 
     tree_index
 }
+
+/// Converts a digest into a scalar. Digests of at least 64 bytes are reduced modulo the group
+/// order from their first 64 bytes, which keeps the scalar uniformly distributed. Shorter digests
+/// are truncated to 32 bytes and converted via `from_bytes`; this keeps scalars derived from
+/// 32-byte digests unchanged.
+fn scalar_from_digest(digest: &[u8], from_bytes: fn([u8; 32]) -> Scalar) -> Scalar {
+    if digest.len() >= 64 {
+        Scalar::from_bytes_mod_order_wide(digest[..64].try_into().unwrap())
+    } else {
+        from_bytes(digest[..32].try_into().unwrap())
+    }
+}
+
+/// Returns an error if the specified digest outputs hashes shorter than 32 bytes.
+fn check_digest_size<D: Digest>() -> Result<(), DapolError> {
+    if D::output_size() < MIN_DIGEST_SIZE {
+        return Err(DapolError::DigestTooShort(
+            MIN_DIGEST_SIZE,
+            D::output_size(),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns an error if the specified total of liabilities does not fit into the range covered by
+/// range proofs. Every node of a tree holds the sum of a subset of liabilities, and so if the
+/// total fits, none of the sums can overflow.
+fn check_total(total: u128, bit_size: usize) -> Result<(), DapolError> {
+    if total >> bit_size != 0 {
+        return Err(DapolError::TotalOutOfRange(total, bit_size));
+    }
+    Ok(())
+}
+
+/// Returns an error if the value of the liability with the specified internal ID does not fit
+/// into the range covered by range proofs.
+fn check_value(internal_id: &LiabilityId, value: u64, bit_size: usize) -> Result<(), DapolError> {
+    if (value as u128) >> bit_size != 0 {
+        return Err(DapolError::LiabilityOutOfRange(
+            internal_id.0.clone(),
+            value,
+            bit_size,
+        ));
+    }
+    Ok(())
+}
+
+/// Returns a cache holding at most the specified number of range proofs, or None if the size is
+/// zero.
+fn new_range_proof_cache(range_proof_cache_size: usize) -> Option<RangeProofCache> {
+    if range_proof_cache_size == 0 {
+        None
+    } else {
+        Some(RangeProofCache::new(range_proof_cache_size))
+    }
+}
+
+/// Converts a tree index of a tree of at most 64 levels into its position within its level.
+fn index_to_u64(idx: &TreeIndex) -> u64 {
+    (0..idx.get_height()).fold(0, |pos, i| (pos << 1) | idx.get_bit(i) as u64)
+}
@@ -0,0 +1,288 @@
+use super::{
+    check_digest_size, check_total, check_value, derive_leaf_seeds,
+    parallel::{build_tree, run_in_thread_pool},
+    scalar_from_digest, shuffle_index, DapolOptions, IdToIndexMap, LiabilityId, MAX_INDEX_RETRIES,
+    MIN_SPARSITY,
+};
+use crate::{
+    errors::DapolError, proof::get_sibling_indexes, MultiAssetProof, MultiAssetProofNode,
+    RangeProofContext, RangeProvable, RangeVerifiable, TotalProof,
+};
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
+use rayon::prelude::*;
+use smtree::{
+    index::TreeIndex,
+    proof::MerkleProof,
+    traits::{ProofExtractable, Serializable},
+    tree::SparseMerkleTree,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+};
+
+mod node;
+pub use node::MultiAssetNode;
+
+#[cfg(test)]
+mod tests;
+
+type MultiAssetTreeInputs<D, const N: usize> = Vec<(TreeIndex, MultiAssetNode<D, N>)>;
+
+// MULTI-ASSET LIABILITY
+// ================================================================================================
+
+/// Represents a single liability held in `N` assets. `internal_id` and `external_id` have the
+/// same meaning as for a [Liability](super::Liability), and `values` contains the amount owed in
+/// each of the assets; the order of assets must be the same for all liabilities in a tree.
+pub struct MultiAssetLiability<const N: usize> {
+    pub internal_id: LiabilityId,
+    pub external_id: LiabilityId,
+    pub values: [u64; N],
+}
+
+// MULTI-ASSET DAPOL
+// ================================================================================================
+
+/// A DAPOL tree in which every leaf holds liabilities in `N` assets. Each node commits to the
+/// value of every asset separately, and so a single tree proves per-asset totals without
+/// revealing which assets a user holds.
+pub struct MultiAssetDapol<D, R, const N: usize> {
+    smt: SparseMerkleTree<MultiAssetNode<D, N>>,
+    id_to_idx_map: IdToIndexMap,
+    aggregation_factor: usize,
+    bit_size: usize,
+    _phantom_r: PhantomData<R>,
+}
+
+impl<D, R, const N: usize> MultiAssetDapol<D, R, N>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable + Send,
+{
+    // CONSTRUCTOR
+    // --------------------------------------------------------------------------------------------
+
+    /// Builds a new instance of multi-asset DAPOL tree from the provided set of liabilities.
+    ///
+    /// Tree indexes are derived the same way as for [Dapol](super::Dapol), and the blinding
+    /// factor of each asset is derived from blind_seed as hash(blind_seed || asset_idx). Hashes
+    /// of the leaves are bound to the context string from the options. Padding and internal
+    /// nodes are computed in parallel, the same way as for [Dapol](super::Dapol).
+    ///
+    /// Returns an error if:
+    /// * The specified digest outputs hashes shorter than 32 bytes.
+    /// * The number of liabilities is not at least 2 times smaller than the total number of
+    ///   leaves in the tree.
+    /// * The value of any asset of any liability, or the total of any asset, does not fit into
+    ///   the range covered by range proofs.
+    /// * List of liabilities contains duplicated internal IDs.
+    /// * The thread pool for building the tree could not be created.
+    /// * The sum of values of any asset in any subtree overflows.
+    pub fn new(
+        liabilities: Vec<MultiAssetLiability<N>>,
+        options: DapolOptions,
+    ) -> Result<Self, DapolError> {
+        check_digest_size::<D>()?;
+        if 2u128.pow(options.tree_height as u32) < (liabilities.len() * MIN_SPARSITY) as u128 {
+            return Err(DapolError::SparsityTooSmall(
+                liabilities.len(),
+                liabilities.len() * MIN_SPARSITY,
+                options.tree_height,
+            ));
+        }
+        let bit_size = options.range_proof_bits;
+        for liability in liabilities.iter() {
+            for &value in liability.values.iter() {
+                check_value(&liability.internal_id, value, bit_size)?;
+            }
+        }
+        for asset_idx in 0..N {
+            check_total(
+                liabilities
+                    .iter()
+                    .map(|l| l.values[asset_idx] as u128)
+                    .sum(),
+                bit_size,
+            )?;
+        }
+
+        let (smt, id_to_idx_map) = run_in_thread_pool(options.num_threads, || {
+            let (tree_inputs, id_to_idx_map) = build_leaf_nodes::<D, N>(
+                liabilities,
+                &options.audit_seed,
+                &options.context,
+                options.tree_height,
+            )?;
+            let smt = build_tree(options.tree_height, &tree_inputs, &options.secret)?;
+            Ok::<_, DapolError>((smt, id_to_idx_map))
+        })??;
+
+        Ok(MultiAssetDapol {
+            smt,
+            id_to_idx_map,
+            aggregation_factor: options.aggregation_factor,
+            bit_size,
+            _phantom_r: PhantomData,
+        })
+    }
+
+    // PUBLIC ACCESSORS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns the root of the tree; values of the root are the per-asset totals.
+    pub fn root_raw(&self) -> &MultiAssetNode<D, N> {
+        self.smt.get_root_raw()
+    }
+
+    /// Returns the root of the tree as a proof node.
+    pub fn root(&self) -> MultiAssetProofNode<D, N> {
+        self.smt.get_root()
+    }
+
+    // PUBLIC METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a proof for a single liability identified by the specified `internal_id`. If a
+    /// liability for the specified ID does not exist in the tree, None is returned.
+    pub fn generate_proof_for_id(
+        &self,
+        internal_id: &LiabilityId,
+    ) -> Option<MultiAssetProof<D, R, N>> {
+        let idx = self.id_to_idx_map.get(internal_id)?;
+        self.generate_proof(idx)
+    }
+
+    /// Returns a proof for a single liability located at the specified leaf.
+    pub fn generate_proof(&self, leaf_idx: &TreeIndex) -> Option<MultiAssetProof<D, R, N>> {
+        self.generate_proof_batch(&[*leaf_idx])
+    }
+
+    /// Returns a batch proof for a list of liabilities located at the specified leaves. Range
+    /// proofs for different assets are generated in parallel.
+    pub fn generate_proof_batch(
+        &self,
+        leaf_indexes: &[TreeIndex],
+    ) -> Option<MultiAssetProof<D, R, N>> {
+        let refs = self.smt.get_merkle_path_ref_batch(leaf_indexes)?;
+
+        let mut merkle_proof: MerkleProof<MultiAssetNode<D, N>> =
+            MerkleProof::new_batch(leaf_indexes);
+        let mut siblings = Vec::new();
+        for item in refs.iter().skip(leaf_indexes.len()) {
+            let node = self.smt.get_node_by_ref(*item).get_value();
+            merkle_proof.add_sibling(node.get_proof_node());
+            siblings.push(node);
+        }
+
+        let positions = get_sibling_indexes(leaf_indexes);
+        let root_hash = self.smt.get_root_raw().get_hash();
+        let context = RangeProofContext::new(root_hash, self.smt.get_height(), &positions);
+        let aggregation_factor = self.aggregation_factor;
+        let bit_size = self.bit_size;
+        let range_proofs = (0..N)
+            .into_par_iter()
+            .map(|asset_idx| {
+                let values: Vec<u64> = siblings.iter().map(|n| n.get_values()[asset_idx]).collect();
+                let blindings: Vec<Scalar> = siblings
+                    .iter()
+                    .map(|n| n.get_blindings()[asset_idx])
+                    .collect();
+                R::generate_proof(&values, &blindings, &context, aggregation_factor, bit_size)
+            })
+            .collect();
+
+        Some(MultiAssetProof::new(merkle_proof, range_proofs))
+    }
+
+    /// Returns proofs that the commitments of the root open to the per-asset totals, one proof
+    /// per asset in the order of assets. Each proof is verified against the root via
+    /// [TotalProof::verify_asset()].
+    pub fn generate_total_proofs(&self) -> Vec<TotalProof> {
+        let root = self.root_raw();
+        root.get_values()
+            .iter()
+            .zip(root.get_blindings().iter())
+            .map(|(&total, blinding)| TotalProof::generate(total, blinding))
+            .collect()
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Converts a list of multi-asset liabilities into a list of (TreeIndex, MultiAssetNode) tuples
+/// sorted by tree index, and builds a mapping between internal IDs and tree indexes.
+fn build_leaf_nodes<D: Digest, const N: usize>(
+    liabilities: Vec<MultiAssetLiability<N>>,
+    audit_seed: &[u8],
+    context: &[u8],
+    tree_height: usize,
+) -> Result<(MultiAssetTreeInputs<D, N>, IdToIndexMap), DapolError> {
+    // make sure all internal IDs are unique
+    let mut internal_ids = HashSet::with_capacity(liabilities.len());
+    for liability in liabilities.iter() {
+        if !internal_ids.insert(&liability.internal_id) {
+            return Err(DapolError::DuplicatedInternalId(
+                liability.internal_id.0.clone(),
+            ));
+        }
+    }
+    drop(internal_ids);
+
+    let seeds: Vec<_> = liabilities
+        .par_iter()
+        .map(|liability| {
+            derive_leaf_seeds::<D>(audit_seed, &liability.internal_id, &liability.external_id)
+        })
+        .collect();
+
+    // tree indexes are assigned sequentially so that collisions are resolved deterministically
+    let mut tree_index_set = HashSet::with_capacity(liabilities.len());
+    let mut indexes = Vec::with_capacity(liabilities.len());
+    for seed in seeds.iter() {
+        let index = shuffle_index::<D>(&seed.index_seed, tree_height, |index| {
+            tree_index_set.insert(index)
+        })
+        .ok_or_else(|| DapolError::FailedToMapIndex(seed.audit_id.to_vec(), MAX_INDEX_RETRIES))?;
+        indexes.push(index);
+    }
+
+    // create the nodes
+    let mut result: MultiAssetTreeInputs<D, N> = liabilities
+        .par_iter()
+        .zip(seeds.par_iter())
+        .zip(indexes.par_iter())
+        .map(|((liability, seed), index)| {
+            let blindings = derive_asset_blindings::<D, N>(&seed.blind_seed);
+            (
+                *index,
+                MultiAssetNode::new_with_context(liability.values, blindings, context),
+            )
+        })
+        .collect();
+
+    let id_to_idx_map: HashMap<_, _> = liabilities
+        .into_iter()
+        .zip(indexes)
+        .map(|(liability, index)| (liability.internal_id, index))
+        .collect();
+
+    // sort by index as smtree requires inputs to be sorted
+    result.par_sort_unstable_by_key(|(index, _)| *index);
+
+    Ok((result, id_to_idx_map))
+}
+
+/// Derives blinding factors for each of `N` assets as hash(blind_seed || asset_idx).
+fn derive_asset_blindings<D: Digest, const N: usize>(blind_seed: &[u8]) -> [Scalar; N] {
+    let mut blindings = [Scalar::zero(); N];
+    let mut hasher = D::new();
+    for (asset_idx, blinding) in blindings.iter_mut().enumerate() {
+        hasher.update(blind_seed);
+        hasher.update((asset_idx as u32).to_be_bytes());
+        *blinding = scalar_from_digest(&hasher.finalize_reset(), Scalar::from_bits);
+    }
+    blindings
+}
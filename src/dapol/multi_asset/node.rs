@@ -0,0 +1,212 @@
+use super::super::{parallel::ParallelNode, scalar_from_digest};
+use crate::{
+    hashing::{hash_internal, hash_leaf, hash_padding},
+    MultiAssetProofNode,
+};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::{ristretto::RistrettoPoint, scalar::Scalar};
+use digest::Digest;
+use smtree::{
+    index::TreeIndex,
+    pad_secret::Secret,
+    traits::{Mergeable, Paddable, ProofExtractable, TypeName},
+};
+use std::marker::PhantomData;
+
+// MULTI-ASSET NODE
+// ================================================================================================
+
+/// A node of the multi-asset DAPOL tree, consisting of the values, the blinding factors and the
+/// Pedersen commitments for each of the `N` assets, and the hash.
+#[derive(Clone, Debug)]
+pub struct MultiAssetNode<D, const N: usize> {
+    values: Vec<u64>,                               // The values of each asset.
+    blindings: Vec<Scalar>,                         // The blinding factors of each asset.
+    coms: Vec<RistrettoPoint>,                      // The Pedersen commitments of each asset.
+    hash: Vec<u8>,                                  // The hash.
+    _phantom_hash_function: PhantomData<fn() -> D>, // The hash function.
+}
+
+impl<D: Digest, const N: usize> MultiAssetNode<D, N> {
+    /// The constructor; returns a leaf node of a tree with an empty context string.
+    pub fn new(values: [u64; N], blindings: [Scalar; N]) -> MultiAssetNode<D, N> {
+        Self::new_with_context(values, blindings, &[])
+    }
+
+    /// Returns a leaf node of a tree with the specified context string; the hash of the node is
+    /// computed from the context string and the Pedersen commitments to the values of all assets.
+    pub fn new_with_context(
+        values: [u64; N],
+        blindings: [Scalar; N],
+        context: &[u8],
+    ) -> MultiAssetNode<D, N> {
+        // compute the Pedersen commitments to the values of all assets
+        let pc_gens = PedersenGens::default();
+        let coms: Vec<RistrettoPoint> = values
+            .iter()
+            .zip(blindings.iter())
+            .map(|(&value, &blinding)| pc_gens.commit(Scalar::from(value), blinding))
+            .collect();
+
+        // compute the hash of a leaf from the context string and all commitments
+        let hash = hash_leaf::<D>(context, &coms);
+
+        MultiAssetNode {
+            values: values.to_vec(),
+            blindings: blindings.to_vec(),
+            coms,
+            hash,
+            _phantom_hash_function: PhantomData,
+        }
+    }
+
+    /// Returns the values of all assets of the node.
+    pub fn get_values(&self) -> &[u64] {
+        &self.values
+    }
+
+    /// Returns the blinding factors of all assets of the node.
+    pub fn get_blindings(&self) -> &[Scalar] {
+        &self.blindings
+    }
+
+    /// Returns the Pedersen commitments of all assets of the node.
+    pub fn get_commitments(&self) -> &[RistrettoPoint] {
+        &self.coms
+    }
+
+    /// Returns the hash of the node.
+    pub fn get_hash(&self) -> &[u8] {
+        &self.hash
+    }
+}
+
+impl<D, const N: usize> Default for MultiAssetNode<D, N> {
+    fn default() -> Self {
+        MultiAssetNode {
+            values: vec![0; N],
+            blindings: vec![Scalar::zero(); N],
+            coms: vec![RistrettoPoint::default(); N],
+            hash: Vec::new(),
+            _phantom_hash_function: PhantomData,
+        }
+    }
+}
+
+impl<D: Digest, const N: usize> Mergeable for MultiAssetNode<D, N> {
+    /// Returns the parent node by merging two child nodes.
+    ///
+    /// The values, blinding factors and commitments of the parent are the sums of the values,
+    /// blinding factors and commitments of the same asset of the two children. The hash of the
+    /// parent is computed by hashing the concatenated commitments and hashes of two children,
+    /// prefixed by the domain-separation tag of internal nodes.
+    ///
+    /// Multi-asset trees compute parents with checked arithmetic and report overflows as errors
+    /// before linking the tree together. When nodes are merged directly, the sum of the values of
+    /// an asset saturates at u64::MAX instead, in which case the value of the parent no longer
+    /// matches its commitment.
+    fn merge(lch: &MultiAssetNode<D, N>, rch: &MultiAssetNode<D, N>) -> MultiAssetNode<D, N> {
+        let values = lch
+            .values
+            .iter()
+            .zip(rch.values.iter())
+            .map(|(&l, &r)| l.saturating_add(r))
+            .collect();
+        merge_with_values(lch, rch, values)
+    }
+}
+
+impl<D: Digest, const N: usize> Paddable for MultiAssetNode<D, N> {
+    /// Returns a padding node with all values set to 0. The blinding factor of each asset is
+    /// derived from the padding secret, the position of the node and the index of the asset as
+    /// hash(secret || "padding" || idx || asset_idx). The hash of the node is prefixed by the
+    /// domain-separation tag of padding nodes.
+    fn padding(idx: &TreeIndex, secret: &Secret) -> MultiAssetNode<D, N> {
+        Self::new_padding(idx, secret)
+    }
+}
+
+impl<D, const N: usize> ProofExtractable for MultiAssetNode<D, N> {
+    type ProofNode = MultiAssetProofNode<D, N>;
+    fn get_proof_node(&self) -> Self::ProofNode {
+        MultiAssetProofNode::from_parts(self.coms.clone(), self.hash.clone())
+    }
+}
+
+impl<D: TypeName, const N: usize> TypeName for MultiAssetNode<D, N> {
+    /// Returns the type name of multi-asset DAPOL nodes with corresponding hash function (for
+    /// logging purpose).
+    fn get_name() -> String {
+        format!("DAPOL Multi-Asset Node ({}, {} assets)", D::get_name(), N)
+    }
+}
+
+impl<D, const N: usize> PartialEq for MultiAssetNode<D, N> {
+    /// Two multi-asset nodes are considered equal iff the values of all assets are equal.
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+    }
+}
+
+impl<D, const N: usize> Eq for MultiAssetNode<D, N> {}
+
+// PARALLEL CONSTRUCTION
+// ================================================================================================
+
+impl<D: Digest, const N: usize> ParallelNode for MultiAssetNode<D, N> {
+    /// Computes the parent node from two child nodes; see [Mergeable::merge] for details.
+    fn merge_children(
+        lch: &MultiAssetNode<D, N>,
+        rch: &MultiAssetNode<D, N>,
+    ) -> Option<MultiAssetNode<D, N>> {
+        let values = lch
+            .values
+            .iter()
+            .zip(rch.values.iter())
+            .map(|(&l, &r)| l.checked_add(r))
+            .collect::<Option<_>>()?;
+        Some(merge_with_values(lch, rch, values))
+    }
+
+    /// Computes the padding node at the specified position; see [Paddable::padding] for details.
+    fn new_padding(idx: &TreeIndex, secret: &Secret) -> MultiAssetNode<D, N> {
+        let mut blindings = [Scalar::zero(); N];
+        for (asset_idx, blinding) in blindings.iter_mut().enumerate() {
+            let mut hasher = D::new();
+            hasher.update(secret.as_bytes());
+            hasher.update("padding");
+            hasher.update(TreeIndex::serialize(&[*idx]));
+            hasher.update((asset_idx as u32).to_be_bytes());
+            *blinding = scalar_from_digest(&hasher.finalize(), Scalar::from_bytes_mod_order);
+        }
+        let mut node = MultiAssetNode::new([0; N], blindings);
+        node.hash = hash_padding::<D>(&node.coms);
+        node
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Returns the parent of the specified children with the specified values; blinding factors,
+/// commitments and the hash of the parent are computed from the children.
+fn merge_with_values<D: Digest, const N: usize>(
+    lch: &MultiAssetNode<D, N>,
+    rch: &MultiAssetNode<D, N>,
+    values: Vec<u64>,
+) -> MultiAssetNode<D, N> {
+    // H(parent) = Hash("DAPOL/v2/internal" || C(L)_1 || ... || C(L)_N || C(R)_1 || ... ||
+    //                  C(R)_N || H(L) || H(R))
+    let hash = hash_internal::<D>(&lch.coms, &rch.coms, &lch.hash, &rch.hash);
+
+    // B/C(parent)_i = B/C(L)_i + B/C(R)_i
+    MultiAssetNode {
+        values,
+        blindings: (0..N)
+            .map(|i| lch.blindings[i] + rch.blindings[i])
+            .collect(),
+        coms: (0..N).map(|i| lch.coms[i] + rch.coms[i]).collect(),
+        hash,
+        _phantom_hash_function: PhantomData,
+    }
+}
@@ -0,0 +1,182 @@
+use super::{build_leaf_nodes, MultiAssetDapol, MultiAssetLiability, MultiAssetNode};
+use crate::{
+    errors::DapolError, DapolOptions, LiabilityId, MultiAssetProof, MultiAssetProofNode,
+    RangeProofPadding,
+};
+use curve25519_dalek_ng::{ristretto::RistrettoPoint, scalar::Scalar};
+use smtree::{
+    pad_secret::Secret,
+    traits::{Mergeable, ProofExtractable},
+    tree::SparseMerkleTree,
+};
+
+// TREE CONSTRUCTION
+// ================================================================================================
+
+#[test]
+fn build_multi_asset_tree() {
+    let tree = MultiAssetDapol::<blake2::Blake2s, RangeProofPadding, 3>::new(
+        build_test_liabilities(),
+        build_test_options(),
+    )
+    .unwrap();
+
+    // values of the root should be the per-asset totals
+    assert_eq!(&[14, 0, 26], tree.root_raw().get_values());
+}
+
+#[test]
+fn build_multi_asset_tree_in_parallel() {
+    // the tree should be the same as the one built by smtree alone
+    let secret = Secret::from_bytes(&[7u8; 32]).unwrap();
+    let options = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(8)
+        .aggregation_factor(2)
+        .secret(Secret::from_bytes(secret.as_bytes()).unwrap())
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let tree = MultiAssetDapol::<blake2::Blake2s, RangeProofPadding, 3>::new(
+        build_test_liabilities(),
+        options,
+    )
+    .unwrap();
+
+    let (leaves, _) =
+        build_leaf_nodes::<blake2::Blake2s, 3>(build_test_liabilities(), b"test", &[], 8).unwrap();
+    let mut smt = SparseMerkleTree::new(8);
+    smt.build(&leaves, &secret);
+    assert_eq!(smt.get_root(), tree.root());
+}
+
+#[test]
+fn build_multi_asset_tree_with_context() {
+    let build_tree = |context: &[u8]| {
+        let options = DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .context(context)
+            .tree_height(8)
+            .aggregation_factor(2)
+            .secret(Secret::from_bytes(&[7u8; 32]).unwrap())
+            .build()
+            .unwrap();
+        MultiAssetDapol::<blake2::Blake2s, RangeProofPadding, 3>::new(
+            build_test_liabilities(),
+            options,
+        )
+        .unwrap()
+    };
+
+    // the context string should be bound into the hashes of the leaves
+    let tree = build_tree(b"exchange 2021-11-01");
+    let idx = tree.id_to_idx_map[&LiabilityId::from_str("b")];
+    let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    let expected = MultiAssetNode::<blake2::Blake2s, 3>::new_with_context(
+        [5, 0, 6],
+        [
+            leaf.get_blindings()[0],
+            leaf.get_blindings()[1],
+            leaf.get_blindings()[2],
+        ],
+        b"exchange 2021-11-01",
+    );
+    assert_eq!(expected.get_hash(), leaf.get_hash());
+    assert_ne!(build_tree(b"").root(), tree.root());
+}
+
+#[test]
+fn merge_multi_asset_nodes() {
+    // sums which overflow should saturate instead of panicking
+    let lch = MultiAssetNode::<blake2::Blake2s, 2>::new([u64::MAX, 1], [Scalar::one(); 2]);
+    let rch = MultiAssetNode::<blake2::Blake2s, 2>::new([1, 2], [Scalar::one(); 2]);
+    let parent = MultiAssetNode::merge(&lch, &rch);
+    assert_eq!(&[u64::MAX, 3], parent.get_values());
+
+    // proof nodes with the wrong number of commitments should be rejected
+    let result =
+        MultiAssetProofNode::<blake2::Blake2s, 3>::new(vec![RistrettoPoint::default(); 2], vec![]);
+    assert!(matches!(result, Err(DapolError::InvalidAssetCount(3, 2))));
+    let node =
+        MultiAssetProofNode::<blake2::Blake2s, 3>::new(vec![RistrettoPoint::default(); 3], vec![]);
+    assert!(node.is_ok());
+}
+
+// PROOF GENERATION
+// ================================================================================================
+
+#[test]
+fn generate_and_verify_multi_asset_proof() {
+    let tree = MultiAssetDapol::<blake2::Blake2s, RangeProofPadding, 3>::new(
+        build_test_liabilities(),
+        build_test_options(),
+    )
+    .unwrap();
+
+    let id = LiabilityId::from_str("b");
+    let idx = tree.id_to_idx_map[&id];
+    let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    assert_eq!(&[5, 0, 6], leaf.get_values());
+
+    // the proof should survive serialization and verify against the root
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    let proof =
+        MultiAssetProof::<blake2::Blake2s, RangeProofPadding, 3>::deserialize(&proof.serialize())
+            .unwrap();
+    assert_eq!(3, proof.get_range_proofs().len());
+    assert!(proof.verify(&tree.root(), &leaf.get_proof_node()));
+
+    // the proof should not verify for a different leaf
+    let other_idx = tree.id_to_idx_map[&LiabilityId::from_str("a")];
+    let other_leaf = tree.smt.get_leaf_by_index(&other_idx).unwrap().get_value();
+    assert!(!proof.verify(&tree.root(), &other_leaf.get_proof_node()));
+}
+
+#[test]
+fn generate_and_verify_total_proofs() {
+    let tree = MultiAssetDapol::<blake2::Blake2s, RangeProofPadding, 3>::new(
+        build_test_liabilities(),
+        build_test_options(),
+    )
+    .unwrap();
+
+    // each proof should disclose the total of its asset and verify only against that asset
+    let root = tree.root();
+    let proofs = tree.generate_total_proofs();
+    assert_eq!(3, proofs.len());
+    for (asset_idx, (proof, &total)) in proofs.iter().zip(&[14, 0, 26]).enumerate() {
+        assert_eq!(total, proof.get_total());
+        assert!(proof.verify_asset(&root, asset_idx));
+    }
+    assert!(!proofs[0].verify_asset(&root, 2));
+    assert!(!proofs[0].verify_asset(&root, 3));
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+fn build_test_options() -> DapolOptions {
+    DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(8)
+        .aggregation_factor(2)
+        .build()
+        .unwrap()
+}
+
+fn build_test_liabilities() -> Vec<MultiAssetLiability<3>> {
+    let internal_ids = vec!["a", "b", "c", "d"];
+    let external_ids = vec!["w", "x", "y", "z"];
+    let values = vec![[3u64, 0, 1], [5, 0, 6], [0, 0, 8], [6, 0, 11]];
+
+    internal_ids
+        .into_iter()
+        .zip(external_ids)
+        .zip(values)
+        .map(|((i_id, e_id), values)| MultiAssetLiability {
+            internal_id: LiabilityId::from_str(i_id),
+            external_id: LiabilityId::from_str(e_id),
+            values,
+        })
+        .collect()
+}
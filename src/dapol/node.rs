@@ -1,4 +1,8 @@
-use crate::DapolProofNode;
+use super::{parallel::ParallelNode, scalar_from_digest};
+use crate::{
+    hashing::{hash_internal, hash_leaf, hash_padding},
+    DapolProofNode,
+};
 use bulletproofs::PedersenGens;
 use curve25519_dalek_ng::{ristretto::RistrettoPoint, scalar::Scalar};
 use digest::Digest;
@@ -17,26 +21,37 @@ use std::marker::PhantomData;
 /// the Pedersen commitment and the hash.
 #[derive(Default, Clone, Debug)]
 pub struct DapolNode<D> {
-    v: u64,                                 // The value.
-    v_blinding: Scalar,                     // The blinding factor.
-    com: RistrettoPoint,                    // The Pedersen commitment.
-    hash: Vec<u8>,                          // The hash.
-    _phantom_hash_function: PhantomData<D>, // The hash function.
+    v: u64,                                         // The value.
+    v_blinding: Scalar,                             // The blinding factor.
+    com: RistrettoPoint,                            // The Pedersen commitment.
+    hash: Vec<u8>,                                  // The hash.
+    _phantom_hash_function: PhantomData<fn() -> D>, // The hash function.
 }
 
 impl<D: Digest> DapolNode<D> {
-    /// The constructor.
+    /// The constructor; returns a leaf node of a tree with an empty context string.
     pub fn new(value: u64, v_blinding: Scalar) -> DapolNode<D> {
-        // compute the Pedersen commitment to the value
-        let com = PedersenGens::default().commit(Scalar::from(value), v_blinding);
+        Self::new_with_context(value, v_blinding, &[])
+    }
 
-        // compute the hash as the hashing of the commitment
-        let mut hasher = D::new();
-        hasher.update(&(com.compress().as_bytes().to_vec()));
-        let hash = hasher.finalize().to_vec();
+    /// Returns a leaf node of a tree with the specified context string; the hash of the node is
+    /// computed from the context string and the Pedersen commitment to the value.
+    pub fn new_with_context(value: u64, v_blinding: Scalar, context: &[u8]) -> DapolNode<D> {
+        let com = PedersenGens::default().commit(Scalar::from(value), v_blinding);
+        let hash = hash_leaf::<D>(context, &[com]);
+        Self::from_parts(value, v_blinding, com, hash)
+    }
 
+    /// Returns a node assembled from the specified value, blinding factor, commitment and hash;
+    /// the parts are not checked against each other.
+    pub(super) fn from_parts(
+        v: u64,
+        v_blinding: Scalar,
+        com: RistrettoPoint,
+        hash: Vec<u8>,
+    ) -> DapolNode<D> {
         DapolNode {
-            v: value,
+            v,
             v_blinding,
             com,
             hash,
@@ -53,6 +68,16 @@ impl<D: Digest> DapolNode<D> {
     pub fn get_blinding(&self) -> Scalar {
         self.v_blinding
     }
+
+    /// Returns the Pedersen commitment of the DAPOL node.
+    pub fn get_commitment(&self) -> &RistrettoPoint {
+        &self.com
+    }
+
+    /// Returns the hash of the DAPOL node.
+    pub fn get_hash(&self) -> &[u8] {
+        &self.hash
+    }
 }
 
 impl<D: Digest> Mergeable for DapolNode<D> {
@@ -60,31 +85,25 @@ impl<D: Digest> Mergeable for DapolNode<D> {
     ///
     /// The value and blinding factor of the parent are the sums of the two children respectively.
     /// The commitment of the parent is the homomorphic sum of the two children.
-    /// The hash of the parent is computed by hashing the concatenated commitments and hashes of two children.
+    /// The hash of the parent is computed by hashing the concatenated commitments and hashes of two
+    /// children, prefixed by the domain-separation tag of internal nodes.
+    ///
+    /// Panics if the sum of the values of the two children overflows. Trees make sure that the
+    /// total of all liabilities fits into the range covered by range proofs before any nodes are
+    /// merged, and so this should never happen for nodes of a tree.
     fn merge(lch: &DapolNode<D>, rch: &DapolNode<D>) -> DapolNode<D> {
-        // H(parent) = Hash(C(L) || C(R) || H(L) || H(R))
-        let mut hasher = D::new();
-        hasher.update(lch.com.compress().as_bytes());
-        hasher.update(rch.com.compress().as_bytes());
-        hasher.update(&lch.hash);
-        hasher.update(&rch.hash);
-
-        // V/B/C(parent) = V/B/C(L) + V/B/C(R)
-        DapolNode {
-            v: lch.v + rch.v,
-            v_blinding: lch.v_blinding + rch.v_blinding,
-            com: lch.com + rch.com,
-            hash: hasher.finalize().to_vec(),
-            _phantom_hash_function: PhantomData,
-        }
+        Self::merge_children(lch, rch).expect("sum of node values overflows")
     }
 }
 
 impl<D: Digest> Paddable for DapolNode<D> {
-    /// Returns a padding node with value 0 and a random blinding factor.
-    /// TODO: check with Kostas if this padding is ok.
-    fn padding(_idx: &TreeIndex, _secret: &Secret) -> DapolNode<D> {
-        DapolNode::<D>::new(0, Scalar::random(&mut thread_rng()))
+    /// Returns a padding node with value 0 and a blinding factor derived from the padding secret
+    /// and the position of the node as hash(secret || "padding" || idx). This way, the same set
+    /// of liabilities and the same secret always result in the same tree. The hash of the node is
+    /// prefixed by the domain-separation tag of padding nodes, and so padding nodes cannot be
+    /// passed off as leaves.
+    fn padding(idx: &TreeIndex, secret: &Secret) -> DapolNode<D> {
+        Self::new_padding(idx, secret)
     }
 }
 
@@ -120,3 +139,37 @@ impl<D> PartialEq for DapolNode<D> {
 }
 
 impl<D> Eq for DapolNode<D> {}
+
+// PARALLEL CONSTRUCTION
+// ================================================================================================
+
+impl<D: Digest> ParallelNode for DapolNode<D> {
+    /// Computes the parent node from two child nodes; see [Mergeable::merge] for details.
+    fn merge_children(lch: &DapolNode<D>, rch: &DapolNode<D>) -> Option<DapolNode<D>> {
+        let v = lch.v.checked_add(rch.v)?;
+
+        // H(parent) = Hash("DAPOL/v2/internal" || C(L) || C(R) || H(L) || H(R))
+        let hash = hash_internal::<D>(&[lch.com], &[rch.com], &lch.hash, &rch.hash);
+
+        // V/B/C(parent) = V/B/C(L) + V/B/C(R)
+        Some(DapolNode {
+            v,
+            v_blinding: lch.v_blinding + rch.v_blinding,
+            com: lch.com + rch.com,
+            hash,
+            _phantom_hash_function: PhantomData,
+        })
+    }
+
+    /// Computes the padding node at the specified position; see [Paddable::padding] for details.
+    fn new_padding(idx: &TreeIndex, secret: &Secret) -> DapolNode<D> {
+        let mut hasher = D::new();
+        hasher.update(secret.as_bytes());
+        hasher.update("padding");
+        hasher.update(TreeIndex::serialize(&[*idx]));
+        let blinding = scalar_from_digest(&hasher.finalize(), Scalar::from_bytes_mod_order);
+        let com = PedersenGens::default().commit(Scalar::zero(), blinding);
+        let hash = hash_padding::<D>(&[com]);
+        Self::from_parts(0, blinding, com, hash)
+    }
+}
@@ -0,0 +1,272 @@
+use crate::{
+    errors::DapolError,
+    kdf::MasterSecret,
+    range::{is_valid_bit_size, DEFAULT_BIT_SIZE},
+    utils::get_secret,
+};
+use smtree::pad_secret::Secret;
+use std::path::{Path, PathBuf};
+
+use super::MAX_TREE_HEIGHT;
+
+// CONSTANTS
+// ================================================================================================
+
+const DEFAULT_TREE_HEIGHT: usize = 32;
+const DEFAULT_AGGREGATION_FACTOR: usize = 1;
+const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+// DAPOL OPTIONS
+// ================================================================================================
+
+/// Defines options for a specific instance of Dapol tree. `audit_id` could be a concatenation of
+/// a randomly derived seed and a date of th audit. `tree_height` cannot exceed 64, and
+/// `range_proof_bits` must be one of 8, 16, 32 or 64.
+///
+/// Options can be constructed only via [DapolOptionsBuilder], which makes sure that all of the
+/// options are valid.
+pub struct DapolOptions {
+    pub(super) audit_seed: Vec<u8>,
+    pub(super) context: Vec<u8>,
+    pub(super) tree_height: usize,
+    pub(super) aggregation_factor: usize,
+    pub(super) range_proof_bits: usize,
+    pub(super) range_proof_cache_size: usize,
+    pub(super) secret: Secret,
+    pub(super) num_threads: Option<usize>,
+    pub(super) chunk_size: usize,
+    pub(super) temp_dir: PathBuf,
+}
+
+impl DapolOptions {
+    /// Returns a new builder for DAPOL options.
+    pub fn builder() -> DapolOptionsBuilder {
+        DapolOptionsBuilder::new()
+    }
+
+    /// Returns the audit seed of the DAPOL tree.
+    pub fn audit_seed(&self) -> &[u8] {
+        &self.audit_seed
+    }
+
+    /// Returns the context string bound into the hashes of all leaves of the DAPOL tree.
+    pub fn context(&self) -> &[u8] {
+        &self.context
+    }
+
+    /// Returns the height of the DAPOL tree.
+    pub fn tree_height(&self) -> usize {
+        self.tree_height
+    }
+
+    /// Returns the number of top-most tree levels for which range proofs are aggregated.
+    pub fn aggregation_factor(&self) -> usize {
+        self.aggregation_factor
+    }
+
+    /// Returns the bit width of range proofs; every liability and every node sum must be within
+    /// [0, 2^range_proof_bits).
+    pub fn range_proof_bits(&self) -> usize {
+        self.range_proof_bits
+    }
+
+    /// Returns the maximum number of range proofs cached for reuse across proof requests; zero
+    /// means that range proofs are not cached.
+    pub fn range_proof_cache_size(&self) -> usize {
+        self.range_proof_cache_size
+    }
+
+    /// Returns the number of threads used to build the tree, if one was specified.
+    pub fn num_threads(&self) -> Option<usize> {
+        self.num_threads
+    }
+
+    /// Returns the maximum number of liabilities kept in memory at once when a tree is built
+    /// from a stream of liabilities.
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns the directory for temporary files created when a tree is built from a stream of
+    /// liabilities.
+    pub fn temp_dir(&self) -> &Path {
+        &self.temp_dir
+    }
+}
+
+// DAPOL OPTIONS BUILDER
+// ================================================================================================
+
+/// Builds [DapolOptions] and validates them before a tree is constructed.
+///
+/// If not set explicitly, the context string is empty, tree height defaults to 32, aggregation
+/// factor defaults to 1, range proofs cover 64 bits and are not cached, the secret used for
+/// generating padding nodes is sampled randomly, and the tree is built using all available cores.
+/// Audit seed must always be set, either directly or by deriving it from a master secret.
+///
+/// When a tree is built from a stream of liabilities, at most 2^20 liabilities are kept in memory
+/// at once by default, and temporary files are placed into the system temporary directory.
+pub struct DapolOptionsBuilder {
+    audit_seed: Vec<u8>,
+    context: Vec<u8>,
+    tree_height: usize,
+    aggregation_factor: usize,
+    range_proof_bits: usize,
+    range_proof_cache_size: usize,
+    secret: Option<Secret>,
+    num_threads: Option<usize>,
+    chunk_size: usize,
+    temp_dir: Option<PathBuf>,
+}
+
+impl DapolOptionsBuilder {
+    // CONSTRUCTOR
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a new builder initialized with default options.
+    pub fn new() -> Self {
+        DapolOptionsBuilder {
+            audit_seed: Vec::new(),
+            context: Vec::new(),
+            tree_height: DEFAULT_TREE_HEIGHT,
+            aggregation_factor: DEFAULT_AGGREGATION_FACTOR,
+            range_proof_bits: DEFAULT_BIT_SIZE,
+            range_proof_cache_size: 0,
+            secret: None,
+            num_threads: None,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            temp_dir: None,
+        }
+    }
+
+    // PUBLIC METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Sets the seed from which audit IDs of all liabilities are derived.
+    pub fn audit_seed(mut self, audit_seed: &[u8]) -> Self {
+        self.audit_seed = audit_seed.to_vec();
+        self
+    }
+
+    /// Sets the context string bound into the hashes of all leaves, e.g. the name of the audited
+    /// entity and the date of the audit. Proofs for leaves of trees with different context strings
+    /// cannot be exchanged for one another.
+    pub fn context(mut self, context: &[u8]) -> Self {
+        self.context = context.to_vec();
+        self
+    }
+
+    /// Sets the height of the tree.
+    pub fn tree_height(mut self, tree_height: usize) -> Self {
+        self.tree_height = tree_height;
+        self
+    }
+
+    /// Sets the number of top-most tree levels for which range proofs are aggregated.
+    pub fn aggregation_factor(mut self, aggregation_factor: usize) -> Self {
+        self.aggregation_factor = aggregation_factor;
+        self
+    }
+
+    /// Sets the bit width of range proofs, which must be one of 8, 16, 32 or 64. Narrower range
+    /// proofs are smaller and faster to verify, but limit the total of all liabilities.
+    pub fn range_proof_bits(mut self, range_proof_bits: usize) -> Self {
+        self.range_proof_bits = range_proof_bits;
+        self
+    }
+
+    /// Sets the maximum number of range proofs cached for reuse across proof requests. Range
+    /// proofs for siblings near the root are shared by the proofs of many liabilities, and so
+    /// caching them saves regenerating them for every request. Zero disables the cache.
+    pub fn range_proof_cache_size(mut self, range_proof_cache_size: usize) -> Self {
+        self.range_proof_cache_size = range_proof_cache_size;
+        self
+    }
+
+    /// Sets the secret used for generating padding nodes.
+    pub fn secret(mut self, secret: Secret) -> Self {
+        self.secret = Some(secret);
+        self
+    }
+
+    /// Sets both the audit seed and the padding secret to the keys derived from the specified
+    /// master secret.
+    pub fn master_secret(mut self, master_secret: &MasterSecret) -> Self {
+        self.audit_seed = master_secret.derive_audit_seed();
+        self.secret = Some(master_secret.derive_padding_secret());
+        self
+    }
+
+    /// Sets the number of threads used to build the tree. If not set, all available cores are
+    /// used.
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = Some(num_threads);
+        self
+    }
+
+    /// Sets the maximum number of liabilities kept in memory at once when a tree is built from a
+    /// stream of liabilities; see [Dapol::from_stream()](super::Dapol::from_stream) for details.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets the directory for temporary files created when a tree is built from a stream of
+    /// liabilities.
+    pub fn temp_dir<P: Into<PathBuf>>(mut self, temp_dir: P) -> Self {
+        self.temp_dir = Some(temp_dir.into());
+        self
+    }
+
+    /// Validates the options and returns them.
+    ///
+    /// Returns an error if:
+    /// * Audit seed is empty.
+    /// * Tree height exceeds 64.
+    /// * Aggregation factor is zero or greater than the tree height.
+    /// * Range proof bit width is not one of 8, 16, 32 or 64.
+    /// * Chunk size is zero.
+    pub fn build(self) -> Result<DapolOptions, DapolError> {
+        if self.audit_seed.is_empty() {
+            return Err(DapolError::EmptyAuditSeed);
+        }
+        if self.tree_height > MAX_TREE_HEIGHT {
+            return Err(DapolError::TreeHeightTooBig(
+                MAX_TREE_HEIGHT,
+                self.tree_height,
+            ));
+        }
+        if self.aggregation_factor == 0 || self.aggregation_factor > self.tree_height {
+            return Err(DapolError::InvalidAggregationFactor(
+                self.tree_height,
+                self.aggregation_factor,
+            ));
+        }
+        if !is_valid_bit_size(self.range_proof_bits) {
+            return Err(DapolError::InvalidRangeProofBits(self.range_proof_bits));
+        }
+
+        if self.chunk_size == 0 {
+            return Err(DapolError::InvalidChunkSize);
+        }
+
+        Ok(DapolOptions {
+            audit_seed: self.audit_seed,
+            context: self.context,
+            tree_height: self.tree_height,
+            aggregation_factor: self.aggregation_factor,
+            range_proof_bits: self.range_proof_bits,
+            range_proof_cache_size: self.range_proof_cache_size,
+            secret: self.secret.unwrap_or_else(get_secret),
+            num_threads: self.num_threads,
+            chunk_size: self.chunk_size,
+            temp_dir: self.temp_dir.unwrap_or_else(std::env::temp_dir),
+        })
+    }
+}
+
+impl Default for DapolOptionsBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,399 @@
+use super::index_to_u64;
+use crate::errors::DapolError;
+use rayon::prelude::*;
+use smtree::{
+    index::TreeIndex,
+    pad_secret::Secret,
+    traits::{Mergeable, Paddable, ProofExtractable, Serializable},
+    tree::SparseMerkleTree,
+};
+use std::{cell::RefCell, collections::VecDeque};
+
+// PARALLEL NODE
+// ================================================================================================
+
+/// A node of a tree which can be built in parallel via [build_tree()].
+pub(super) trait ParallelNode: Mergeable + Paddable + Send + Sync + Sized {
+    /// Returns the parent of the specified children, or None if the sum of values of the
+    /// children overflows.
+    fn merge_children(lch: &Self, rch: &Self) -> Option<Self>;
+
+    /// Returns the padding node at the specified position.
+    fn new_padding(idx: &TreeIndex, secret: &Secret) -> Self;
+}
+
+// PARALLEL TREE CONSTRUCTION
+// ================================================================================================
+
+/// Builds a sparse Merkle tree from the provided list of leaves sorted by tree index.
+///
+/// smtree links the nodes of the tree together in a single thread. To speed things up, values of
+/// all padding and internal nodes are computed beforehand, level by level, using all available
+/// threads. smtree then takes the computed nodes via
+/// [SparseMerkleTree::construct_smt_nodes_with()] instead of computing them itself, and so the
+/// resulting tree is exactly the same as the one built by smtree alone.
+///
+/// Each layer is moved into [PrecomputedNodes] as soon as its parents have been computed, and
+/// smtree takes the nodes out again while linking the tree together. This way, every internal
+/// node is held in memory only once, and at most one layer is held twice.
+///
+/// Returns an error if the sum of values in any subtree overflows.
+pub(super) fn build_tree<N>(
+    tree_height: usize,
+    leaves: &[(TreeIndex, N)],
+    secret: &Secret,
+) -> Result<SparseMerkleTree<N>, DapolError>
+where
+    N: ParallelNode + Clone + Default + ProofExtractable,
+    N::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+{
+    build_tree_with_paddings(tree_height, leaves, |idx| Ok(N::new_padding(idx, secret)))
+}
+
+/// Builds a sparse Merkle tree in the same way as [build_tree()], but takes padding nodes from
+/// the specified function instead of deriving them from the padding secret. Any error returned
+/// by the function is propagated.
+pub(super) fn build_tree_with_paddings<N, F>(
+    tree_height: usize,
+    leaves: &[(TreeIndex, N)],
+    padding: F,
+) -> Result<SparseMerkleTree<N>, DapolError>
+where
+    N: ParallelNode + Clone + Default + ProofExtractable,
+    N::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+    F: Fn(&TreeIndex) -> Result<N, DapolError> + Sync,
+{
+    let mut smt = SparseMerkleTree::<N>::new(tree_height);
+    if leaves.is_empty() {
+        return Ok(smt);
+    }
+
+    let (parents, paddings) = build_parent_layer(leaves, &padding)?;
+    let precomputed = precompute_nodes(tree_height, parents, paddings, &padding)?;
+    if !link_nodes(&mut smt, leaves.iter().cloned(), precomputed) {
+        panic!("leaves should be sorted by tree index");
+    }
+    Ok(smt)
+}
+
+/// Builds a sparse Merkle tree in the same way as [build_tree()], but reads leaves sorted by tree
+/// index from the iterators returned by `open_leaves` instead of taking them from a slice, and so
+/// the leaves are never all held in memory at once.
+///
+/// Leaves are read twice: first, to compute their parents in batches of `batch_size` leaves, and
+/// then to link the tree together. Both reads must return the same leaves in the same order. Any
+/// error returned while reading the leaves is propagated.
+pub(super) fn build_tree_from_sorted_leaves<N, I, O>(
+    tree_height: usize,
+    open_leaves: O,
+    batch_size: usize,
+    secret: &Secret,
+) -> Result<SparseMerkleTree<N>, DapolError>
+where
+    N: ParallelNode + Clone + Default + ProofExtractable,
+    N::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+    I: Iterator<Item = Result<(TreeIndex, N), DapolError>>,
+    O: Fn() -> Result<I, DapolError>,
+{
+    let padding = |idx: &TreeIndex| Ok(N::new_padding(idx, secret));
+    let mut smt = SparseMerkleTree::<N>::new(tree_height);
+
+    // a left child at the end of a batch is carried over to the next batch, as its sibling may be
+    // the first leaf of that batch
+    let mut leaves = open_leaves()?;
+    let mut batch = Vec::with_capacity(batch_size + 1);
+    let mut parents = Vec::new();
+    let mut paddings = Vec::new();
+    let mut has_more = true;
+    while has_more {
+        for _ in 0..batch_size {
+            match leaves.next() {
+                Some(leaf) => batch.push(leaf?),
+                None => {
+                    has_more = false;
+                    break;
+                }
+            }
+        }
+        let carried = match batch.last() {
+            Some((idx, _)) if has_more && idx.get_last_bit() == 0 => batch.pop(),
+            _ => None,
+        };
+        let (mut batch_parents, mut batch_paddings) = build_parent_layer(&batch, &padding)?;
+        parents.append(&mut batch_parents);
+        paddings.append(&mut batch_paddings);
+        batch.clear();
+        batch.extend(carried);
+    }
+    drop(leaves);
+    if parents.is_empty() {
+        return Ok(smt);
+    }
+
+    let precomputed = precompute_nodes(tree_height, parents, paddings, &padding)?;
+
+    // reading stops at the first error, in which case the tree is incomplete and is discarded
+    let mut error = None;
+    let leaves = open_leaves()?.map_while(|leaf| leaf.map_err(|err| error = Some(err)).ok());
+    let is_linked = link_nodes(&mut smt, leaves, precomputed);
+    match error {
+        Some(err) => Err(err),
+        None if !is_linked => {
+            panic!("leaves should be sorted by tree index and read the same way twice")
+        }
+        None => Ok(smt),
+    }
+}
+
+/// Executes `f` in a thread pool with the specified number of threads. If the number of threads
+/// is not specified, `f` is executed in the global thread pool which uses all available cores.
+pub(super) fn run_in_thread_pool<F, R>(num_threads: Option<usize>, f: F) -> Result<R, DapolError>
+where
+    F: FnOnce() -> R + Send,
+    R: Send,
+{
+    Ok(Workers::new(num_threads)?.install(f))
+}
+
+// WORKERS
+// ================================================================================================
+
+/// A thread pool which can be reused for several parallel computations. If the number of threads
+/// is not specified, the global thread pool which uses all available cores is used instead.
+pub(super) struct Workers(Option<rayon::ThreadPool>);
+
+impl Workers {
+    /// Returns a new thread pool with the specified number of threads.
+    pub fn new(num_threads: Option<usize>) -> Result<Self, DapolError> {
+        match num_threads {
+            Some(num_threads) => {
+                let pool = rayon::ThreadPoolBuilder::new()
+                    .num_threads(num_threads)
+                    .build()
+                    .map_err(|err| DapolError::ThreadPoolBuildFailed(err.to_string()))?;
+                Ok(Workers(Some(pool)))
+            }
+            None => Ok(Workers(None)),
+        }
+    }
+
+    /// Executes `f` in this thread pool.
+    pub fn install<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R + Send,
+        R: Send,
+    {
+        match &self.0 {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+}
+
+// PRECOMPUTED NODES
+// ================================================================================================
+
+/// Padding and internal nodes computed ahead of time so that smtree does not need to recompute
+/// them while linking the tree together.
+///
+/// smtree builds a tree layer by layer, starting from the leaves. Within a layer, it visits the
+/// nodes from left to right, creates the missing sibling of each node and then the parent (see
+/// [SparseMerkleTree::construct_smt_nodes_with()]). Precomputed nodes are stored in exactly this
+/// order, and so each request is served from the front of the queue without keeping any lookup
+/// tables; a layer is dropped as soon as all of its nodes have been handed out.
+///
+/// If a requested node does not match the next precomputed node, a default node is handed out
+/// instead, and the queue is marked as mismatched; the tree built from it must be discarded.
+struct PrecomputedNodes<N> {
+    layers: VecDeque<VecDeque<PrecomputedParent<N>>>,
+    is_mismatched: bool,
+}
+
+/// A precomputed parent node, together with the padding node created as one of its children.
+struct PrecomputedParent<N> {
+    padding: Option<(TreeIndex, N)>,
+    parent: (TreeIndex, N),
+}
+
+impl<N: Default> PrecomputedNodes<N> {
+    /// Appends a layer of parent nodes in the order in which smtree creates them, together with
+    /// the padding nodes created as their children.
+    fn add_layer(&mut self, parents: Vec<(TreeIndex, N)>, paddings: Vec<Option<(TreeIndex, N)>>) {
+        let layer = parents
+            .into_iter()
+            .zip(paddings)
+            .map(|(parent, padding)| PrecomputedParent { padding, parent })
+            .collect::<VecDeque<_>>();
+        if !layer.is_empty() {
+            self.layers.push_back(layer);
+        }
+    }
+
+    /// Removes and returns the next precomputed node if it is the padding node at the specified
+    /// index.
+    fn take_padding(&mut self, idx: &TreeIndex) -> N {
+        let padding = match self.layers.front_mut().and_then(|layer| layer.front_mut()) {
+            Some(next) if matches!(&next.padding, Some((padding_idx, _)) if padding_idx == idx) => {
+                next.padding.take()
+            }
+            _ => None,
+        };
+        match padding {
+            Some((_, node)) => node,
+            None => self.mismatch(),
+        }
+    }
+
+    /// Removes and returns the next precomputed node if it is the parent at the specified index,
+    /// and its padding child has already been taken.
+    fn take_parent(&mut self, idx: &TreeIndex) -> N {
+        let layer = match self.layers.front_mut() {
+            Some(layer) => layer,
+            None => return self.mismatch(),
+        };
+        match layer.front() {
+            Some(next) if next.padding.is_none() && next.parent.0 == *idx => (),
+            _ => return self.mismatch(),
+        }
+        let next = layer.pop_front().expect("layer should not be empty");
+        if layer.is_empty() {
+            self.layers.pop_front();
+        }
+        next.parent.1
+    }
+
+    /// Returns true if all nodes have been handed out, and all of them matched the requests.
+    fn is_complete(&self) -> bool {
+        !self.is_mismatched && self.layers.is_empty()
+    }
+
+    fn mismatch(&mut self) -> N {
+        self.is_mismatched = true;
+        N::default()
+    }
+}
+
+impl<N> Default for PrecomputedNodes<N> {
+    fn default() -> Self {
+        PrecomputedNodes {
+            layers: VecDeque::new(),
+            is_mismatched: false,
+        }
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Computes all layers above the specified parents of leaves and returns them, together with
+/// the parents of leaves, in the order in which smtree links them together.
+fn precompute_nodes<N, F>(
+    tree_height: usize,
+    mut layer: Vec<(TreeIndex, N)>,
+    mut paddings: Vec<Option<(TreeIndex, N)>>,
+    padding: &F,
+) -> Result<PrecomputedNodes<N>, DapolError>
+where
+    N: ParallelNode + Default,
+    F: Fn(&TreeIndex) -> Result<N, DapolError> + Sync,
+{
+    let mut precomputed = PrecomputedNodes::default();
+    for _ in 1..tree_height {
+        let (parents, parent_paddings) = build_parent_layer(&layer, padding)?;
+        precomputed.add_layer(
+            std::mem::replace(&mut layer, parents),
+            std::mem::replace(&mut paddings, parent_paddings),
+        );
+    }
+    precomputed.add_layer(layer, paddings);
+    Ok(precomputed)
+}
+
+/// Links the specified leaves, sorted by tree index, and the precomputed padding and internal
+/// nodes together into the specified tree.
+///
+/// Returns false if the leaves are not sorted, or if they are not the leaves the nodes were
+/// computed from; the tree must be discarded in such a case.
+fn link_nodes<N, I>(
+    smt: &mut SparseMerkleTree<N>,
+    leaves: I,
+    precomputed: PrecomputedNodes<N>,
+) -> bool
+where
+    N: Clone + Default + Mergeable + Paddable + ProofExtractable,
+    N::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+    I: IntoIterator<Item = (TreeIndex, N)>,
+{
+    // both closures below take nodes from the same queue, one at a time
+    let precomputed = RefCell::new(precomputed);
+    let error = smt.construct_smt_nodes_with(
+        leaves,
+        |idx| precomputed.borrow_mut().take_padding(idx),
+        |idx, _, _| precomputed.borrow_mut().take_parent(idx),
+    );
+    error.is_none() && precomputed.into_inner().is_complete()
+}
+
+/// A layer of nodes sorted by tree index, together with the padding nodes created as children
+/// of each node in the layer.
+type Layer<N> = (Vec<(TreeIndex, N)>, Vec<Option<(TreeIndex, N)>>);
+
+/// Computes the layer of nodes directly above the specified layer. Padding nodes are created for
+/// all nodes in the layer which do not have a sibling, and are returned alongside their parents.
+///
+/// Returns an error if the value of any parent overflows, in which case the error identifies the
+/// subtree rooted at the parent, or if a padding node could not be created.
+fn build_parent_layer<N, F>(layer: &[(TreeIndex, N)], padding: &F) -> Result<Layer<N>, DapolError>
+where
+    N: ParallelNode,
+    F: Fn(&TreeIndex) -> Result<N, DapolError> + Sync,
+{
+    // pair up the nodes with their siblings; since the layer is sorted, the sibling of a left
+    // child can only be the next node in the layer
+    let mut pairs = Vec::with_capacity(layer.len());
+    let mut pos = 0;
+    while pos < layer.len() {
+        let idx = &layer[pos].0;
+        if idx.get_last_bit() == 0
+            && pos + 1 < layer.len()
+            && layer[pos + 1].0 == idx.get_sibling_index()
+        {
+            pairs.push((pos, Some(pos + 1)));
+            pos += 2;
+        } else {
+            pairs.push((pos, None));
+            pos += 1;
+        }
+    }
+
+    // compute parents and missing siblings in parallel
+    pairs
+        .into_par_iter()
+        .map(|(pos, sibling_pos)| {
+            let (idx, node) = &layer[pos];
+            let parent_idx = idx.get_parent_index();
+            let overflow = || {
+                DapolError::SubtreeSumOverflow(parent_idx.get_height(), index_to_u64(&parent_idx))
+            };
+            match sibling_pos {
+                Some(sibling_pos) => {
+                    let parent =
+                        N::merge_children(node, &layer[sibling_pos].1).ok_or_else(overflow)?;
+                    Ok(((parent_idx, parent), None))
+                }
+                None => {
+                    let sibling_idx = idx.get_sibling_index();
+                    let sibling = padding(&sibling_idx)?;
+                    let parent = if idx.get_last_bit() == 0 {
+                        N::merge_children(node, &sibling)
+                    } else {
+                        N::merge_children(&sibling, node)
+                    }
+                    .ok_or_else(overflow)?;
+                    Ok(((parent_idx, parent), Some((sibling_idx, sibling))))
+                }
+            }
+        })
+        .collect::<Result<Vec<_>, DapolError>>()
+        .map(|layer| layer.into_iter().unzip())
+}
@@ -0,0 +1,594 @@
+use super::{
+    index_to_u64,
+    snapshot::{serialize_index, ByteReader},
+    Dapol, MAX_TREE_HEIGHT,
+};
+use crate::{errors::DapolError, DapolProof, DapolProofNode, RangeProvable, RangeVerifiable};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
+use smtree::{index::TreeIndex, traits::Serializable};
+use std::{
+    collections::BTreeSet,
+    convert::{TryFrom, TryInto},
+};
+
+// CONSTANTS
+// ================================================================================================
+
+const SAMPLE_SEED_LABEL: &[u8] = b"DAPOL sample seed";
+
+// Domain-separation labels for the hash tree over the positions of occupied leaves.
+const LEAF_LIST_LABEL: &[u8] = b"DAPOL sample leaf list";
+const LIST_ENTRY_LABEL: &[u8] = b"DAPOL sample list entry";
+const LIST_PADDING_LABEL: &[u8] = b"DAPOL sample list padding";
+const LIST_NODE_LABEL: &[u8] = b"DAPOL sample list node";
+
+const OPENING_MAGIC: &[u8; 8] = b"DAPOLSMP";
+const OPENING_VERSION: u32 = 1;
+
+// SAMPLE SELECTION
+// ================================================================================================
+
+/// Derives a sampling seed from the root of a tree, the commitment to its list of occupied
+/// leaves (see [Dapol::leaf_list_commitment()]) and a nonce, such as the date of the audit, as
+/// hash("DAPOL sample seed" || root_hash || leaf_list_commitment || nonce).
+///
+/// As the seed depends on the root and on the list of leaves, the sample is fixed only after both
+/// are committed to, and so the producer of the tree cannot arrange liabilities with the sample
+/// in mind.
+pub fn derive_sample_seed<D>(
+    root: &DapolProofNode<D>,
+    leaf_list_commitment: &[u8],
+    nonce: &[u8],
+) -> Vec<u8>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+{
+    let mut hasher = D::new();
+    hasher.update(SAMPLE_SEED_LABEL);
+    hasher.update(root.get_hash());
+    hasher.update(leaf_list_commitment);
+    hasher.update(nonce);
+    hasher.finalize().to_vec()
+}
+
+/// Returns `sample_size` distinct ranks of leaves in a list of `num_leaves` occupied leaves
+/// sorted by index, selected with the specified seed and sorted in increasing order; the sample
+/// size must not exceed the number of leaves.
+///
+/// Each hash(seed || counter) selects the rank given by its top 128 bits modulo the number of
+/// leaves, with a negligible bias, and counters are tried until enough distinct ranks are
+/// selected. Ranks depend only on public data, and so anyone can recompute the sample from the
+/// seed.
+pub(super) fn select_sample_ranks<D: Digest>(
+    seed: &[u8],
+    num_leaves: u64,
+    sample_size: usize,
+) -> Vec<u64> {
+    debug_assert!(sample_size as u64 <= num_leaves);
+    let mut ranks = BTreeSet::new();
+    let mut counter = 0u64;
+    while ranks.len() < sample_size {
+        let mut hasher = D::new();
+        hasher.update(seed);
+        hasher.update(counter.to_be_bytes());
+        let digest = hasher.finalize();
+        // unwrap is OK here because digests used with DAPOL are at least 32 bytes long
+        let bits = u128::from_be_bytes(digest[..16].try_into().unwrap());
+        ranks.insert((bits % num_leaves as u128) as u64);
+        counter += 1;
+    }
+    ranks.into_iter().collect()
+}
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable,
+{
+    /// Returns a commitment to the number of occupied leaves of this tree and to their positions,
+    /// which must be published together with the root so that sample openings of this tree can
+    /// be verified (see [SampleOpening::verify()]).
+    ///
+    /// The commitment is hash("DAPOL sample leaf list" || tree_height || num_leaves ||
+    /// list_root), where `list_root` is the root of a hash tree over the positions of occupied
+    /// leaves sorted in increasing order. The commitment reveals the number of liabilities in
+    /// the tree to whoever can test guesses against it.
+    pub fn leaf_list_commitment(&self) -> Vec<u8> {
+        let positions = self.occupied_leaf_positions();
+        let (list_root, _) = hash_leaf_list::<D>(&positions, &[]);
+        commit_leaf_list::<D>(self.smt.get_height(), positions.len() as u64, &list_root)
+    }
+
+    /// Returns an opening of `sample_size` distinct leaves selected among the occupied leaves of
+    /// this tree with the seed derived from the root, the leaf list commitment and the specified
+    /// nonce (see [derive_sample_seed]), which can be handed out to an auditor. None is returned
+    /// if the sample size is zero or exceeds the number of liabilities in the tree.
+    ///
+    /// Leaves are selected by their rank among occupied leaves sorted by index, and each opened
+    /// leaf comes with a proof that it is at its rank in the committed list of leaves.
+    pub fn generate_sample_opening(
+        &self,
+        nonce: &[u8],
+        sample_size: usize,
+    ) -> Option<SampleOpening<D, R>> {
+        let positions = self.occupied_leaf_positions();
+        if sample_size == 0 || sample_size > positions.len() {
+            return None;
+        }
+        let tree_height = self.smt.get_height();
+        let num_leaves = positions.len() as u64;
+
+        // the list is hashed twice, as the paths to extract depend on its root
+        let (list_root, _) = hash_leaf_list::<D>(&positions, &[]);
+        let commitment = commit_leaf_list::<D>(tree_height, num_leaves, &list_root);
+        let seed = derive_sample_seed(&self.root(), &commitment, nonce);
+        let ranks = select_sample_ranks::<D>(&seed, num_leaves, sample_size);
+        let (_, list_paths) = hash_leaf_list::<D>(&positions, &ranks);
+
+        let mut indexes = Vec::with_capacity(sample_size);
+        let mut leaves = Vec::with_capacity(sample_size);
+        for (rank, list_path) in ranks.into_iter().zip(list_paths) {
+            let index = TreeIndex::from_u64(tree_height, positions[rank as usize]);
+            let leaf = self.smt.get_leaf_by_index(&index)?.get_value();
+            leaves.push(LeafOpening {
+                index,
+                value: leaf.get_value(),
+                blinding: leaf.get_blinding(),
+                rank,
+                list_path,
+            });
+            indexes.push(index);
+        }
+        let leaf_proof = self.generate_proof_batch(&indexes)?;
+
+        Some(SampleOpening {
+            nonce: nonce.to_vec(),
+            sample_size,
+            tree_height,
+            context: self.context.clone(),
+            num_leaves,
+            list_root,
+            leaves,
+            leaf_proof,
+        })
+    }
+
+    /// Returns positions of all occupied leaves sorted in increasing order.
+    fn occupied_leaf_positions(&self) -> Vec<u64> {
+        let mut positions: Vec<u64> = self.id_to_idx_map.values().map(index_to_u64).collect();
+        positions.sort_unstable();
+        positions
+    }
+}
+
+// SAMPLE OPENING
+// ================================================================================================
+
+/// The opening of a single leaf: its index, the value of the liability and the blinding factor
+/// of the commitment, together with the rank of the leaf among occupied leaves sorted by index
+/// and the path proving the position of the leaf at this rank in the committed list of leaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafOpening {
+    index: TreeIndex,
+    value: u64,
+    blinding: Scalar,
+    rank: u64,
+    list_path: Vec<Vec<u8>>,
+}
+
+impl LeafOpening {
+    /// Returns the index of the leaf.
+    pub fn index(&self) -> &TreeIndex {
+        &self.index
+    }
+
+    /// Returns the value of the liability at the leaf.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Returns the blinding factor of the commitment of the leaf.
+    pub fn blinding(&self) -> &Scalar {
+        &self.blinding
+    }
+
+    /// Returns the rank of the leaf among occupied leaves sorted by index.
+    pub fn rank(&self) -> u64 {
+        self.rank
+    }
+}
+
+/// Openings of a random sample of occupied leaves of a tree, together with the nonce and the size
+/// of the sample, the height and the context string of the tree, and the number of occupied
+/// leaves and the root of the hash tree over their positions.
+///
+/// Leaves are selected by their rank among occupied leaves sorted by index, with the seed derived
+/// from the root of the tree, the leaf list commitment and the nonce (see [derive_sample_seed]),
+/// and so the verifier recomputes the selection from public data only. Each leaf is opened to
+/// let an auditor check the liability against the ledger of the audited system, and all of them
+/// are proven with a single batch proof.
+///
+/// The list of leaves is bound to the tree only at the sampled ranks: a list omitting some of
+/// the occupied leaves still verifies, but it is fixed by the published commitment before the
+/// sample is drawn.
+#[derive(Debug)]
+pub struct SampleOpening<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    nonce: Vec<u8>,
+    sample_size: usize,
+    tree_height: usize,
+    context: Vec<u8>,
+    num_leaves: u64,
+    list_root: Vec<u8>,
+    leaves: Vec<LeafOpening>,
+    leaf_proof: DapolProof<D, R>,
+}
+
+impl<D, R> SampleOpening<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    // PUBLIC ACCESSORS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns the nonce the sampling seed was derived with.
+    pub fn nonce(&self) -> &[u8] {
+        &self.nonce
+    }
+
+    /// Returns the number of leaves in the sample.
+    pub fn sample_size(&self) -> usize {
+        self.sample_size
+    }
+
+    /// Returns the height of the tree.
+    pub fn tree_height(&self) -> usize {
+        self.tree_height
+    }
+
+    /// Returns the context string of the tree.
+    pub fn context(&self) -> &[u8] {
+        &self.context
+    }
+
+    /// Returns the number of occupied leaves of the tree the sample was drawn from.
+    pub fn num_leaves(&self) -> u64 {
+        self.num_leaves
+    }
+
+    /// Returns openings of the sampled leaves, sorted by leaf index.
+    pub fn leaves(&self) -> &[LeafOpening] {
+        &self.leaves
+    }
+
+    /// Returns the batch proof of inclusion of the sampled leaves.
+    pub fn leaf_proof(&self) -> &DapolProof<D, R> {
+        &self.leaf_proof
+    }
+
+    // VERIFICATION
+    // --------------------------------------------------------------------------------------------
+
+    /// Verifies that the opened leaves are exactly the leaves sampled for the specified root and
+    /// leaf list commitment, which must be the ones published by the audited system, and that all
+    /// of them are included in the tree with this root.
+    ///
+    /// Returns an error if:
+    /// * The opening is malformed, e.g. the number of opened leaves differs from the sample size,
+    ///   its leaves are not sorted by distinct indexes or the leaf proof is not for the opened
+    ///   leaves.
+    /// * The opening does not match the leaf list commitment, the opened leaves are not at the
+    ///   sampled ranks, or their positions are not in the committed list at these ranks.
+    /// * The batch proof of inclusion does not verify against the root.
+    pub fn verify(
+        &self,
+        published_root: &DapolProofNode<D>,
+        published_leaf_list_commitment: &[u8],
+    ) -> Result<(), DapolError> {
+        // the size of the sample is checked against the openings before anything is hashed
+        if self.sample_size == 0 {
+            return Err(malformed("sample is empty"));
+        }
+        if self.sample_size != self.leaves.len() {
+            return Err(malformed(
+                "number of opened leaves does not match the sample size",
+            ));
+        }
+        if self.sample_size as u64 > self.num_leaves {
+            return Err(malformed("sample is larger than the list of leaves"));
+        }
+        if self.tree_height == 0 || self.tree_height > MAX_TREE_HEIGHT {
+            return Err(malformed("invalid tree height"));
+        }
+        if self
+            .leaves
+            .windows(2)
+            .any(|pair| pair[0].index >= pair[1].index)
+        {
+            return Err(malformed("openings are not sorted by distinct indexes"));
+        }
+        if self
+            .leaves
+            .iter()
+            .any(|leaf| leaf.index.get_height() != self.tree_height)
+        {
+            return Err(malformed("openings are not located in the tree"));
+        }
+        let list_height = leaf_list_height(self.num_leaves);
+        if self
+            .leaves
+            .iter()
+            .any(|leaf| leaf.list_path.len() != list_height)
+        {
+            return Err(malformed("invalid length of leaf list path"));
+        }
+
+        // recompute the sample and make sure that the opened leaves are in the list at the
+        // sampled ranks
+        let commitment = commit_leaf_list::<D>(self.tree_height, self.num_leaves, &self.list_root);
+        if commitment != published_leaf_list_commitment {
+            return Err(DapolError::SampleMismatch);
+        }
+        let seed = derive_sample_seed(published_root, &commitment, &self.nonce);
+        let ranks = select_sample_ranks::<D>(&seed, self.num_leaves, self.sample_size);
+        for (leaf, rank) in self.leaves.iter().zip(ranks) {
+            if leaf.rank != rank
+                || verify_leaf_list_path::<D>(index_to_u64(&leaf.index), rank, &leaf.list_path)
+                    != self.list_root
+            {
+                return Err(DapolError::SampleMismatch);
+            }
+        }
+
+        // recompute the leaves from the openings and verify their inclusion
+        let indexes = self.leaf_proof.get_merkle_path().get_indexes();
+        if indexes.len() != self.leaves.len()
+            || indexes
+                .iter()
+                .zip(self.leaves.iter())
+                .any(|(index, leaf)| *index != leaf.index)
+        {
+            return Err(malformed("leaf proof is not for the opened leaves"));
+        }
+        let pc_gens = PedersenGens::default();
+        let leaves: Vec<DapolProofNode<D>> = self
+            .leaves
+            .iter()
+            .map(|leaf| {
+                let com = pc_gens.commit(Scalar::from(leaf.value), leaf.blinding);
+                DapolProofNode::new_leaf(com, &self.context)
+            })
+            .collect();
+        if !self.leaf_proof.verify_batch(published_root, &leaves) {
+            return Err(DapolError::InvalidProof);
+        }
+        Ok(())
+    }
+
+    // SERIALIZATION / DESERIALIZATION
+    // --------------------------------------------------------------------------------------------
+
+    /// Serializes the opening with all integers encoded in big-endian order as:
+    /// * magic bytes "DAPOLSMP" and a 4-byte format version;
+    /// * 4-byte digest size, 1-byte tree height and 4-byte sample size;
+    /// * length-prefixed nonce and length-prefixed context string;
+    /// * 8-byte number of occupied leaves and the root of the hash tree over their positions;
+    /// * 4-byte number of opened leaves, each encoded as its index, 8-byte value, 32-byte
+    ///   blinding factor, 8-byte rank, 1-byte length of its leaf list path and the hashes of the
+    ///   path;
+    /// * 8-byte length of the leaf proof and the serialized leaf proof.
+    ///
+    /// Indexes are encoded as their height followed by the first 8 bytes of their path.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(OPENING_MAGIC);
+        bytes.extend_from_slice(&OPENING_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&(D::output_size() as u32).to_be_bytes());
+        bytes.push(self.tree_height as u8);
+        bytes.extend_from_slice(&(self.sample_size as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.nonce.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes.extend_from_slice(&(self.context.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.context);
+        bytes.extend_from_slice(&self.num_leaves.to_be_bytes());
+        bytes.extend_from_slice(&self.list_root);
+
+        bytes.extend_from_slice(&(self.leaves.len() as u32).to_be_bytes());
+        for leaf in self.leaves.iter() {
+            bytes.extend_from_slice(&serialize_index(&leaf.index));
+            bytes.extend_from_slice(&leaf.value.to_be_bytes());
+            bytes.extend_from_slice(leaf.blinding.as_bytes());
+            bytes.extend_from_slice(&leaf.rank.to_be_bytes());
+            bytes.push(leaf.list_path.len() as u8);
+            for hash in leaf.list_path.iter() {
+                bytes.extend_from_slice(hash);
+            }
+        }
+        append_with_length(&mut bytes, self.leaf_proof.serialize());
+        bytes
+    }
+
+    /// Deserializes an opening serialized via [SampleOpening::serialize()].
+    ///
+    /// Returns an error if the opening was serialized by an unsupported version of the format or
+    /// for a digest with a different output size, if the number of opened leaves differs from
+    /// the sample size, or if the bytes do not describe a valid opening.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DapolError> {
+        let mut reader = ByteReader::new(bytes, malformed);
+        if reader.read_bytes(OPENING_MAGIC.len())? != OPENING_MAGIC {
+            return Err(malformed("opening does not start with magic bytes"));
+        }
+        let version = reader.read_u32()?;
+        if version != OPENING_VERSION {
+            return Err(malformed(&format!("unsupported version {}", version)));
+        }
+        let digest_size = reader.read_u32()? as usize;
+        if digest_size != D::output_size() {
+            return Err(DapolError::InvalidDigestSize(D::output_size(), digest_size));
+        }
+        let tree_height = reader.read_u8()? as usize;
+        if tree_height == 0 || tree_height > MAX_TREE_HEIGHT {
+            return Err(malformed("invalid tree height"));
+        }
+        let sample_size = reader.read_u32()? as usize;
+        let nonce_len = reader.read_u32()? as usize;
+        let nonce = reader.read_bytes(nonce_len)?.to_vec();
+        let context_len = reader.read_u32()? as usize;
+        let context = reader.read_bytes(context_len)?.to_vec();
+        let num_leaves = reader.read_u64()?;
+        let list_root = reader.read_bytes(digest_size)?.to_vec();
+
+        let num_openings = reader.read_u32()? as usize;
+        if num_openings != sample_size {
+            return Err(malformed(
+                "number of opened leaves does not match the sample size",
+            ));
+        }
+        if sample_size as u64 > num_leaves {
+            return Err(malformed("sample is larger than the list of leaves"));
+        }
+        let mut leaves = Vec::new();
+        for _ in 0..num_openings {
+            let index = reader.read_index()?;
+            let value = reader.read_u64()?;
+            // blinding factors of leaves are derived via Scalar::from_bits(), and so they need
+            // not be reduced
+            let blinding = Scalar::from_bits(reader.read_array()?);
+            let rank = reader.read_u64()?;
+            let path_len = reader.read_u8()? as usize;
+            let mut list_path = Vec::with_capacity(path_len);
+            for _ in 0..path_len {
+                list_path.push(reader.read_bytes(digest_size)?.to_vec());
+            }
+            leaves.push(LeafOpening {
+                index,
+                value,
+                blinding,
+                rank,
+                list_path,
+            });
+        }
+        let leaf_proof = DapolProof::<D, R>::deserialize(read_with_length(&mut reader)?)
+            .map_err(|err| DapolError::ProofDecodingFailed(format!("{:?}", err)))?;
+        if !reader.is_empty() {
+            return Err(malformed("unexpected bytes at the end of opening"));
+        }
+
+        Ok(SampleOpening {
+            nonce,
+            sample_size,
+            tree_height,
+            context,
+            num_leaves,
+            list_root,
+            leaves,
+            leaf_proof,
+        })
+    }
+}
+
+// LEAF LIST
+// ================================================================================================
+
+/// Computes the root of the hash tree over the specified positions of occupied leaves, which must
+/// be sorted, together with the paths of the entries at the specified ranks.
+///
+/// Entries are hashed as hash("DAPOL sample list entry" || position), and the last level is
+/// padded up to the next power of two with hash("DAPOL sample list padding"); parents are hashed
+/// as hash("DAPOL sample list node" || left || right). Only a single level of the tree is held in
+/// memory at a time.
+fn hash_leaf_list<D: Digest>(positions: &[u64], ranks: &[u64]) -> (Vec<u8>, Vec<Vec<Vec<u8>>>) {
+    let mut layer: Vec<Vec<u8>> = positions
+        .iter()
+        .map(|position| hash_list_entry::<D>(*position))
+        .collect();
+    let mut padding = D::digest(LIST_PADDING_LABEL).to_vec();
+    let mut paths = vec![Vec::new(); ranks.len()];
+    let mut height = 0;
+    while layer.len() > 1 {
+        if layer.len() % 2 == 1 {
+            layer.push(padding.clone());
+        }
+        for (path, rank) in paths.iter_mut().zip(ranks.iter()) {
+            path.push(layer[((rank >> height) ^ 1) as usize].clone());
+        }
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_list_node::<D>(&pair[0], &pair[1]))
+            .collect();
+        padding = hash_list_node::<D>(&padding, &padding);
+        height += 1;
+    }
+    (layer.pop().unwrap_or(padding), paths)
+}
+
+/// Returns the root of the hash tree over a list of leaves computed from the entry for the
+/// specified position at the specified rank and its path.
+fn verify_leaf_list_path<D: Digest>(position: u64, rank: u64, path: &[Vec<u8>]) -> Vec<u8> {
+    let mut hash = hash_list_entry::<D>(position);
+    for (height, sibling) in path.iter().enumerate() {
+        hash = if (rank >> height) & 1 == 0 {
+            hash_list_node::<D>(&hash, sibling)
+        } else {
+            hash_list_node::<D>(sibling, &hash)
+        };
+    }
+    hash
+}
+
+/// Returns the height of the hash tree over a list of the specified number of leaves.
+fn leaf_list_height(num_leaves: u64) -> usize {
+    match num_leaves {
+        0 | 1 => 0,
+        n => (64 - (n - 1).leading_zeros()) as usize,
+    }
+}
+
+/// Computes the commitment to a list of leaves as hash("DAPOL sample leaf list" || tree_height ||
+/// num_leaves || list_root).
+fn commit_leaf_list<D: Digest>(tree_height: usize, num_leaves: u64, list_root: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(LEAF_LIST_LABEL);
+    hasher.update([tree_height as u8]);
+    hasher.update(num_leaves.to_be_bytes());
+    hasher.update(list_root);
+    hasher.finalize().to_vec()
+}
+
+fn hash_list_entry<D: Digest>(position: u64) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(LIST_ENTRY_LABEL);
+    hasher.update(position.to_be_bytes());
+    hasher.finalize().to_vec()
+}
+
+fn hash_list_node<D: Digest>(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(LIST_NODE_LABEL);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+fn malformed(reason: &str) -> DapolError {
+    DapolError::MalformedSampleOpening(reason.to_string())
+}
+
+fn append_with_length(bytes: &mut Vec<u8>, mut item: Vec<u8>) {
+    bytes.extend_from_slice(&(item.len() as u64).to_be_bytes());
+    bytes.append(&mut item);
+}
+
+fn read_with_length<'a>(reader: &mut ByteReader<'a>) -> Result<&'a [u8], DapolError> {
+    let len = usize::try_from(reader.read_u64()?).map_err(|_| malformed("item is too long"))?;
+    reader.read_bytes(len)
+}
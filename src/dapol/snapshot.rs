@@ -0,0 +1,557 @@
+use super::{
+    parallel::{build_tree_with_paddings, run_in_thread_pool},
+    Dapol, DapolNode, IdToIndexMap, LiabilityId, MAX_TREE_HEIGHT,
+};
+use crate::{
+    errors::DapolError,
+    hashing::{hash_padding, HASH_LAYOUT_VERSION},
+    range::is_valid_bit_size,
+    RangeProvable, RangeVerifiable,
+};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
+use digest::Digest;
+use rayon::prelude::*;
+use smtree::{
+    index::TreeIndex,
+    pad_secret::{Secret, SECRET_LENGTH},
+    traits::Serializable,
+    tree::{NodeType, SparseMerkleTree},
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// CONSTANTS
+// ================================================================================================
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DAPOLSNP";
+const SNAPSHOT_VERSION: u32 = 3;
+const CHECKSUM_SIZE: usize = 32;
+
+// Number of nodes and liabilities to reserve memory for up front; larger snapshots grow their
+// buffers as nodes are actually read, and so a forged count cannot exhaust memory.
+const MAX_INITIAL_CAPACITY: u64 = 1 << 16;
+
+// Size of a serialized tree index: height followed by the first 8 bytes of the path.
+const INDEX_SIZE: usize = 9;
+
+// Types of nodes stored in a snapshot.
+const PADDING_NODE: u8 = 1;
+const LEAF_NODE: u8 = 2;
+
+// SNAPSHOTS
+// ================================================================================================
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable,
+{
+    /// Writes a snapshot of this tree into the specified writer. A tree loaded from the snapshot
+    /// is identical to this tree, and so it can serve proofs and accept liability updates without
+    /// access to the original liabilities.
+    ///
+    /// A snapshot has the following layout, with all integers encoded in big-endian order:
+    /// * magic bytes "DAPOLSNP" and a 4-byte format version;
+    /// * 1-byte hash layout version, 4-byte digest size, 1-byte tree height, 4-byte aggregation
+    ///   factor and 1-byte bit width of range proofs;
+    /// * length-prefixed audit seed, length-prefixed context string and the 32-byte padding
+    ///   secret;
+    /// * hash of the tree root;
+    /// * 8-byte number of nodes, followed by all leaves and padding nodes of the tree in BFS
+    ///   order; each node is encoded as node type, tree index, value, blinding factor,
+    ///   commitment and hash; internal nodes are recomputed when the snapshot is read;
+    /// * 8-byte number of liabilities, followed by length-prefixed internal IDs of all
+    ///   liabilities together with tree indexes of their leaves;
+    /// * BLAKE3 checksum of all preceding bytes.
+    ///
+    /// The snapshot contains the audit seed, the padding secret and the blinding factors of all
+    /// nodes, and so it must be kept as confidential as the liabilities themselves.
+    pub fn write_snapshot<W: Write>(&self, writer: &mut W) -> Result<(), DapolError> {
+        let mut writer = ChecksumWriter::new(writer);
+
+        // header
+        writer.write(SNAPSHOT_MAGIC)?;
+        writer.write(&SNAPSHOT_VERSION.to_be_bytes())?;
+        writer.write(&[HASH_LAYOUT_VERSION])?;
+        writer.write(&(D::output_size() as u32).to_be_bytes())?;
+        writer.write(&[self.smt.get_height() as u8])?;
+        writer.write(&(self.aggregation_factor as u32).to_be_bytes())?;
+        writer.write(&[self.bit_size as u8])?;
+        writer.write(&(self.audit_seed.len() as u32).to_be_bytes())?;
+        writer.write(&self.audit_seed)?;
+        writer.write(&(self.context.len() as u32).to_be_bytes())?;
+        writer.write(&self.context)?;
+        writer.write(self.secret.as_bytes())?;
+        writer.write(self.smt.get_root_raw().get_hash())?;
+
+        // leaves and padding nodes
+        let nodes: Vec<_> = self
+            .smt
+            .get_index_node_pairs()
+            .into_iter()
+            .filter(|(_, node)| *node.get_node_type() != NodeType::Internal)
+            .collect();
+        writer.write(&(nodes.len() as u64).to_be_bytes())?;
+        for (idx, node) in nodes.iter() {
+            let node_type = match node.get_node_type() {
+                NodeType::Padding => PADDING_NODE,
+                _ => LEAF_NODE,
+            };
+            let value = node.get_value();
+            writer.write(&[node_type])?;
+            writer.write(&serialize_index(idx))?;
+            writer.write(&value.get_value().to_be_bytes())?;
+            writer.write(value.get_blinding().as_bytes())?;
+            writer.write(value.get_commitment().compress().as_bytes())?;
+            writer.write(value.get_hash())?;
+        }
+
+        // liability ID mapping
+        writer.write(&(self.id_to_idx_map.len() as u64).to_be_bytes())?;
+        for (id, idx) in self.id_to_idx_map.iter() {
+            writer.write(&(id.0.len() as u32).to_be_bytes())?;
+            writer.write(&id.0)?;
+            writer.write(&serialize_index(idx))?;
+        }
+
+        writer.finish()
+    }
+
+    /// Reads a tree from a snapshot created by [Dapol::write_snapshot()]. Leaves and padding nodes
+    /// are taken from the snapshot, and so loading a tree is much faster than building it; only
+    /// the commitments and hashes of stored nodes and the internal nodes are recomputed, and the
+    /// resulting root must match the root stored in the snapshot.
+    ///
+    /// The snapshot is parsed as it is read, and memory is allocated only for the bytes which
+    /// have actually been read; the checksum is verified once the whole snapshot has been read.
+    ///
+    /// Returns an error if:
+    /// * The snapshot could not be read.
+    /// * The checksum of the snapshot does not match its contents.
+    /// * The snapshot was created by an unsupported version of the format.
+    /// * The snapshot was created for a digest with a different output size.
+    /// * The contents of the snapshot do not describe a valid tree, e.g. the commitment of a node
+    ///   does not match its value, a padding node is not part of the tree, or a liability is not
+    ///   mapped to a leaf.
+    /// * The root of the tree does not match the root stored in the snapshot.
+    pub fn read_snapshot<T: Read>(reader: &mut T) -> Result<Self, DapolError> {
+        let mut reader = ChecksumReader::new(reader);
+
+        // header
+        if reader.read_array::<8>()? != *SNAPSHOT_MAGIC {
+            return Err(malformed("snapshot does not start with magic bytes"));
+        }
+        let version = reader.read_u32()?;
+        if version != SNAPSHOT_VERSION {
+            return Err(DapolError::UnsupportedSnapshotVersion(
+                SNAPSHOT_VERSION,
+                version,
+            ));
+        }
+        let hash_layout_version = reader.read_u8()?;
+        if hash_layout_version != HASH_LAYOUT_VERSION {
+            return Err(malformed(&format!(
+                "unsupported hash layout version {}",
+                hash_layout_version
+            )));
+        }
+        let digest_size = reader.read_u32()? as usize;
+        if digest_size != D::output_size() {
+            return Err(DapolError::InvalidDigestSize(D::output_size(), digest_size));
+        }
+        let tree_height = reader.read_u8()? as usize;
+        if tree_height > MAX_TREE_HEIGHT {
+            return Err(DapolError::TreeHeightTooBig(MAX_TREE_HEIGHT, tree_height));
+        }
+        let aggregation_factor = reader.read_u32()? as usize;
+        if aggregation_factor == 0 || aggregation_factor > tree_height {
+            return Err(DapolError::InvalidAggregationFactor(
+                tree_height,
+                aggregation_factor,
+            ));
+        }
+        let bit_size = reader.read_u8()? as usize;
+        if !is_valid_bit_size(bit_size) {
+            return Err(DapolError::InvalidRangeProofBits(bit_size));
+        }
+        let audit_seed_len = reader.read_u32()? as usize;
+        let audit_seed = reader.read_vec(audit_seed_len)?;
+        if audit_seed.is_empty() {
+            return Err(DapolError::EmptyAuditSeed);
+        }
+        let context_len = reader.read_u32()? as usize;
+        let context = reader.read_vec(context_len)?;
+        let secret = Secret::from_bytes(&reader.read_array::<SECRET_LENGTH>()?)
+            .map_err(|_| malformed("invalid padding secret"))?;
+        let root_hash = reader.read_vec(digest_size)?;
+
+        // leaves and padding nodes
+        let num_nodes = reader.read_u64()?;
+        let mut leaves = Vec::with_capacity(num_nodes.min(MAX_INITIAL_CAPACITY) as usize);
+        let mut paddings = HashMap::new();
+        for _ in 0..num_nodes {
+            let node_type = reader.read_u8()?;
+            let idx = reader.read_index()?;
+            let value = reader.read_u64()?;
+            let blinding = Scalar::from_bits(reader.read_array()?);
+            let com = CompressedRistretto(reader.read_array()?)
+                .decompress()
+                .ok_or_else(|| malformed("invalid node commitment"))?;
+            let hash = reader.read_vec(digest_size)?;
+            let node = DapolNode::<D>::from_parts(value, blinding, com, hash);
+            match node_type {
+                LEAF_NODE => leaves.push((idx, node)),
+                PADDING_NODE => {
+                    if paddings.insert(idx, node).is_some() {
+                        return Err(malformed("duplicated padding node"));
+                    }
+                }
+                _ => return Err(malformed("unknown node type")),
+            }
+        }
+
+        // liability ID mapping
+        let num_ids = reader.read_u64()?;
+        let mut id_to_idx_map =
+            IdToIndexMap::with_capacity(num_ids.min(MAX_INITIAL_CAPACITY) as usize);
+        for _ in 0..num_ids {
+            let id_len = reader.read_u32()? as usize;
+            let id = LiabilityId(reader.read_vec(id_len)?);
+            let idx = reader.read_index()?;
+            if idx.get_height() != tree_height {
+                return Err(malformed("liability is not mapped to a leaf"));
+            }
+            if id_to_idx_map.insert(id, idx).is_some() {
+                return Err(malformed("duplicated liability ID"));
+            }
+        }
+        reader.finish()?;
+
+        // recompute commitments and hashes of leaves and padding nodes, as well as internal
+        // nodes, and make sure that they add up to the stored root
+        leaves.sort_unstable_by_key(|(idx, _)| *idx);
+        if let Some(err) =
+            SparseMerkleTree::<DapolNode<D>>::new(tree_height).check_index_list_validity(&leaves)
+        {
+            return Err(malformed(&err.to_string()));
+        }
+        let used_paddings = AtomicUsize::new(0);
+        let (smt, holds_liability) = run_in_thread_pool(None, || {
+            let holds_liability = leaves
+                .par_iter()
+                .map(|(_, node)| leaf_holds_liability(node, &context))
+                .collect::<Result<Vec<_>, DapolError>>()?;
+            if !paddings
+                .par_iter()
+                .all(|(_, node)| is_consistent_padding(node))
+            {
+                return Err(malformed(
+                    "padding node does not match its value and blinding factor",
+                ));
+            }
+            let smt = build_tree_with_paddings(tree_height, &leaves, |idx| {
+                let padding = paddings
+                    .get(idx)
+                    .cloned()
+                    .ok_or_else(|| malformed("padding node is missing"))?;
+                used_paddings.fetch_add(1, Ordering::Relaxed);
+                Ok(padding)
+            })?;
+            Ok((smt, holds_liability))
+        })??;
+        if used_paddings.into_inner() != paddings.len() {
+            return Err(malformed("padding node is not part of the tree"));
+        }
+        if smt.get_root_raw().get_hash() != root_hash {
+            return Err(malformed(
+                "tree root does not match the root in the snapshot",
+            ));
+        }
+
+        // every liability must be mapped to a leaf of its own, and every leaf which holds a
+        // liability must be mapped to one; leaves vacated by removed liabilities hold padding
+        // values and must not be mapped
+        let mut mapped_indexes = Vec::with_capacity(id_to_idx_map.len());
+        for idx in id_to_idx_map.values() {
+            let is_leaf = smt
+                .get_leaf_by_index(idx)
+                .is_some_and(|node| *node.get_node_type() == NodeType::Leaf);
+            let pos = leaves.binary_search_by_key(idx, |(leaf_idx, _)| *leaf_idx);
+            if !is_leaf || !pos.is_ok_and(|pos| holds_liability[pos]) {
+                return Err(malformed("liability is not mapped to a leaf"));
+            }
+            mapped_indexes.push(*idx);
+        }
+        mapped_indexes.sort_unstable();
+        mapped_indexes.dedup();
+        if mapped_indexes.len() != id_to_idx_map.len() {
+            return Err(malformed("several liabilities are mapped to the same leaf"));
+        }
+        if mapped_indexes.len() != holds_liability.iter().filter(|&&holds| holds).count() {
+            return Err(malformed(
+                "leaf holds a liability which is not mapped to it",
+            ));
+        }
+
+        Ok(Dapol {
+            smt,
+            id_to_idx_map,
+            aggregation_factor,
+            bit_size,
+            audit_seed,
+            context,
+            secret,
+            range_proof_cache: None,
+            _phantom_r: PhantomData,
+        })
+    }
+
+    /// Writes a snapshot of this tree into the file at the specified path; see
+    /// [Dapol::write_snapshot()] for details.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), DapolError> {
+        let file =
+            File::create(path).map_err(|err| DapolError::SnapshotIoFailed(err.to_string()))?;
+        self.write_snapshot(&mut BufWriter::new(file))
+    }
+
+    /// Reads a tree from the snapshot file at the specified path; see [Dapol::read_snapshot()]
+    /// for details.
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> Result<Self, DapolError> {
+        let file = File::open(path).map_err(|err| DapolError::SnapshotIoFailed(err.to_string()))?;
+        Self::read_snapshot(&mut BufReader::new(file))
+    }
+}
+
+// HELPER TYPES
+// ================================================================================================
+
+/// Writes bytes into the underlying writer while computing the checksum of all written bytes.
+struct ChecksumWriter<'a, W: Write> {
+    inner: &'a mut W,
+    hasher: blake3::Hasher,
+}
+
+impl<'a, W: Write> ChecksumWriter<'a, W> {
+    fn new(inner: &'a mut W) -> Self {
+        ChecksumWriter {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), DapolError> {
+        self.hasher.update(bytes);
+        self.inner
+            .write_all(bytes)
+            .map_err(|err| DapolError::SnapshotIoFailed(err.to_string()))
+    }
+
+    /// Writes the checksum and flushes the underlying writer.
+    fn finish(self) -> Result<(), DapolError> {
+        let checksum = blake3::Hasher::finalize(&self.hasher);
+        self.inner
+            .write_all(checksum.as_bytes())
+            .and_then(|_| self.inner.flush())
+            .map_err(|err| DapolError::SnapshotIoFailed(err.to_string()))
+    }
+}
+
+/// Reads values from the underlying reader while computing the checksum of all read bytes.
+/// Errors are reported as I/O failures, except for running out of bytes, which means that the
+/// snapshot is malformed.
+struct ChecksumReader<'a, R: Read> {
+    inner: &'a mut R,
+    hasher: blake3::Hasher,
+}
+
+impl<'a, R: Read> ChecksumReader<'a, R> {
+    fn new(inner: &'a mut R) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DapolError> {
+        let mut bytes = [0u8; N];
+        self.inner.read_exact(&mut bytes).map_err(read_error)?;
+        self.hasher.update(&bytes);
+        Ok(bytes)
+    }
+
+    /// Reads the specified number of bytes; memory is allocated as the bytes are read, and so a
+    /// forged length cannot exhaust memory.
+    fn read_vec(&mut self, num_bytes: usize) -> Result<Vec<u8>, DapolError> {
+        let mut bytes = Vec::new();
+        self.inner
+            .by_ref()
+            .take(num_bytes as u64)
+            .read_to_end(&mut bytes)
+            .map_err(read_error)?;
+        if bytes.len() != num_bytes {
+            return Err(malformed("unexpected end of snapshot"));
+        }
+        self.hasher.update(&bytes);
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DapolError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DapolError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, DapolError> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    fn read_index(&mut self) -> Result<TreeIndex, DapolError> {
+        deserialize_index(&self.read_array()?).ok_or_else(|| malformed("tree index is too high"))
+    }
+
+    /// Reads the checksum, and makes sure that it matches all bytes read so far and that no
+    /// bytes follow it.
+    fn finish(self) -> Result<(), DapolError> {
+        let mut checksum = [0u8; CHECKSUM_SIZE];
+        self.inner.read_exact(&mut checksum).map_err(read_error)?;
+        if blake3::Hasher::finalize(&self.hasher).as_bytes()[..] != checksum[..] {
+            return Err(DapolError::SnapshotChecksumMismatch);
+        }
+        let mut trailing = [0u8; 1];
+        match self.inner.read(&mut trailing).map_err(read_error)? {
+            0 => Ok(()),
+            _ => Err(malformed("unexpected bytes at the end of snapshot")),
+        }
+    }
+}
+
+/// Reads values from serialized bytes, failing instead of panicking when bytes run out. Errors
+/// are built by the specified function, which describes the kind of data being read.
+pub(super) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    malformed: fn(&str) -> DapolError,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8], malformed: fn(&str) -> DapolError) -> Self {
+        ByteReader {
+            bytes,
+            pos: 0,
+            malformed,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    pub fn read_bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], DapolError> {
+        if self.bytes.len() - self.pos < num_bytes {
+            return Err((self.malformed)("unexpected end of bytes"));
+        }
+        let result = &self.bytes[self.pos..self.pos + num_bytes];
+        self.pos += num_bytes;
+        Ok(result)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DapolError> {
+        Ok(self
+            .read_bytes(N)?
+            .try_into()
+            .expect("slice length should match array length"))
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DapolError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DapolError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DapolError> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_index(&mut self) -> Result<TreeIndex, DapolError> {
+        deserialize_index(&self.read_array()?)
+            .ok_or_else(|| (self.malformed)("tree index is too high"))
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Serializes a tree index as its height followed by the first 8 bytes of its path; this is
+/// sufficient because DAPOL trees are at most 64 levels high.
+pub(super) fn serialize_index(idx: &TreeIndex) -> [u8; INDEX_SIZE] {
+    let mut result = [0u8; INDEX_SIZE];
+    result[0] = idx.get_height() as u8;
+    result[1..].copy_from_slice(&idx.get_path()[..8]);
+    result
+}
+
+/// Deserializes a tree index serialized via [serialize_index()]; returns None if the index is
+/// higher than the highest supported tree.
+fn deserialize_index(bytes: &[u8; INDEX_SIZE]) -> Option<TreeIndex> {
+    let height = bytes[0] as usize;
+    if height > MAX_TREE_HEIGHT {
+        return None;
+    }
+    let mut path = [0u8; 32];
+    path[..8].copy_from_slice(&bytes[1..]);
+    Some(TreeIndex::new(height, path))
+}
+
+/// Returns true if the commitment and the hash of the specified leaf are computed from its value,
+/// blinding factor and the context string of the tree, and false if the leaf was vacated by a
+/// removed liability and holds a padding node instead.
+///
+/// Returns an error if the leaf is neither.
+fn leaf_holds_liability<D: Digest>(
+    node: &DapolNode<D>,
+    context: &[u8],
+) -> Result<bool, DapolError> {
+    let expected = DapolNode::<D>::new_with_context(node.get_value(), node.get_blinding(), context);
+    if expected.get_commitment() == node.get_commitment() && expected.get_hash() == node.get_hash()
+    {
+        Ok(true)
+    } else if is_consistent_padding(node) {
+        Ok(false)
+    } else {
+        Err(malformed(
+            "leaf does not match its value and blinding factor",
+        ))
+    }
+}
+
+/// Returns true if the value of the specified padding node is 0, and its commitment and hash are
+/// computed from its blinding factor.
+fn is_consistent_padding<D: Digest>(node: &DapolNode<D>) -> bool {
+    let com = PedersenGens::default().commit(Scalar::zero(), node.get_blinding());
+    node.get_value() == 0
+        && com == *node.get_commitment()
+        && hash_padding::<D>(&[com]) == node.get_hash()
+}
+
+fn read_error(err: std::io::Error) -> DapolError {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof => malformed("unexpected end of snapshot"),
+        _ => DapolError::SnapshotIoFailed(err.to_string()),
+    }
+}
+
+fn malformed(reason: &str) -> DapolError {
+    DapolError::MalformedSnapshot(reason.to_string())
+}
@@ -0,0 +1,306 @@
+use super::{
+    add_leaf_nodes, check_digest_size, check_total, check_value, index_to_u64,
+    new_range_proof_cache,
+    parallel::{build_tree, build_tree_from_sorted_leaves, Workers},
+    Dapol, DapolNode, DapolOptions, IdToIndexMap, Liability, TreeInputs, MIN_SPARSITY,
+};
+use crate::{errors::DapolError, RangeProvable, RangeVerifiable};
+use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
+use digest::Digest;
+use smtree::{index::TreeIndex, traits::Serializable};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashSet},
+    convert::TryInto,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// CONSTANTS
+// ================================================================================================
+
+// Size of a leaf record in a temporary file without the hash: tree index, value, blinding factor
+// and commitment. The hash follows, and its size depends on the digest.
+const RECORD_PREFIX_SIZE: usize = 8 + 8 + 32 + 32;
+
+// Counter used to generate unique names of temporary files.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// STREAMING CONSTRUCTOR
+// ================================================================================================
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable,
+{
+    /// Builds a new instance of DAPOL tree from a stream of liabilities without holding all of
+    /// the raw liabilities in memory at once.
+    ///
+    /// Liabilities are read in chunks of the size specified in the options. Leaf nodes for each
+    /// chunk are computed in parallel, sorted by tree index and written into a temporary file
+    /// in the temporary directory specified in the options. Once all liabilities are read, the
+    /// sorted files are merged, and the tree is built from the merged leaves in batches of the
+    /// chunk size, so that the full list of leaves is never held in memory. If all liabilities
+    /// fit into a single chunk, no temporary files are created. The tree is the same as the one
+    /// built by [Dapol::new()] from the same liabilities in the same order.
+    ///
+    /// Streaming does not bound the memory needed for the tree itself, which keeps all of its
+    /// nodes in memory once it is built. For n liabilities and a tree of height h, peak memory
+    /// is O(n * h) nodes for the tree, plus the mapping of internal IDs to tree indexes, one
+    /// chunk of liabilities and their leaves, and a read buffer for each temporary file.
+    ///
+    /// Returns an error if:
+    /// * The specified digest outputs hashes shorter than 32 bytes.
+    /// * Any of the liabilities could not be read.
+    /// * The number of liabilities is not at least 2 times smaller than the total number of
+    ///   leaves in the tree.
+    /// * Any of the liabilities, or the total of all liabilities, does not fit into the range
+    ///   covered by range proofs.
+    /// * Liabilities contain duplicated internal IDs.
+    /// * Temporary files could not be written or read.
+    /// * The thread pool for building the tree could not be created.
+    pub fn from_stream<I, E>(liabilities: I, options: DapolOptions) -> Result<Self, DapolError>
+    where
+        I: IntoIterator<Item = Result<Liability, E>>,
+        E: std::fmt::Display,
+    {
+        check_digest_size::<D>()?;
+
+        let workers = Workers::new(options.num_threads)?;
+        let tree_height = options.tree_height;
+        let bit_size = options.range_proof_bits;
+        let mut id_to_idx_map = IdToIndexMap::new();
+        let mut tree_index_set = HashSet::new();
+        let mut total = 0u128;
+
+        // the most recent chunk is kept in memory, and is spilled to disk only if there is
+        // another chunk after it
+        let mut runs: Vec<TempFile> = Vec::new();
+        let mut pending: Option<TreeInputs<D>> = None;
+
+        let mut liabilities = liabilities.into_iter().enumerate();
+        loop {
+            let mut chunk = Vec::new();
+            for (i, liability) in liabilities.by_ref().take(options.chunk_size) {
+                let liability = liability
+                    .map_err(|err| DapolError::LiabilityIngestFailed(i, err.to_string()))?;
+                chunk.push(liability);
+            }
+            if chunk.is_empty() {
+                break;
+            }
+
+            let num_liabilities = id_to_idx_map.len() + chunk.len();
+            if 2u128.pow(tree_height as u32) < (num_liabilities * MIN_SPARSITY) as u128 {
+                return Err(DapolError::SparsityTooSmall(
+                    num_liabilities,
+                    num_liabilities * MIN_SPARSITY,
+                    tree_height,
+                ));
+            }
+            for liability in chunk.iter() {
+                check_value(&liability.internal_id, liability.value, bit_size)?;
+            }
+            total += chunk.iter().map(|l| l.value as u128).sum::<u128>();
+            check_total(total, bit_size)?;
+
+            let leaves = workers.install(|| {
+                add_leaf_nodes::<D>(
+                    chunk,
+                    &options.audit_seed,
+                    &options.context,
+                    tree_height,
+                    &mut id_to_idx_map,
+                    &mut tree_index_set,
+                )
+            })?;
+            if let Some(previous) = pending.replace(leaves) {
+                runs.push(write_run(&options.temp_dir, &previous)?);
+            }
+        }
+        drop(tree_index_set);
+
+        let smt = match pending {
+            Some(leaves) if !runs.is_empty() => {
+                runs.push(write_run(&options.temp_dir, &leaves)?);
+                drop(leaves);
+                workers.install(|| {
+                    build_tree_from_sorted_leaves(
+                        tree_height,
+                        || RunMerger::<D>::open(&runs, tree_height),
+                        options.chunk_size,
+                        &options.secret,
+                    )
+                })?
+            }
+            leaves => {
+                let leaves = leaves.unwrap_or_default();
+                workers.install(|| build_tree(tree_height, &leaves, &options.secret))?
+            }
+        };
+        drop(runs);
+
+        Ok(Dapol {
+            smt,
+            id_to_idx_map,
+            aggregation_factor: options.aggregation_factor,
+            bit_size,
+            audit_seed: options.audit_seed,
+            context: options.context,
+            secret: options.secret,
+            range_proof_cache: new_range_proof_cache(options.range_proof_cache_size),
+            _phantom_r: PhantomData,
+        })
+    }
+}
+
+// TEMPORARY FILES
+// ================================================================================================
+
+/// A temporary file which is deleted when it goes out of scope.
+struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    /// Creates a new empty file with a unique name in the specified directory.
+    fn create(dir: &Path) -> Result<(Self, File), DapolError> {
+        let name = format!(
+            "dapol-{}-{}.tmp",
+            process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(temp_file_error)?;
+        Ok((TempFile { path }, file))
+    }
+
+    fn open(&self) -> Result<File, DapolError> {
+        File::open(&self.path).map_err(temp_file_error)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // the file may be left behind if it cannot be removed, but there is nothing else to do
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// RUN MERGER
+// ================================================================================================
+
+/// Merges the leaves from temporary files, each sorted by tree index, into a single sequence
+/// sorted by tree index, holding only the next leaf of each file in memory.
+///
+/// The sequence ends after the first error.
+struct RunMerger<D> {
+    tree_height: usize,
+    readers: Vec<BufReader<File>>,
+    heads: Vec<Option<DapolNode<D>>>,
+    // tree indexes of the heads, with the smallest one on top
+    heap: BinaryHeap<Reverse<(u64, usize)>>,
+}
+
+impl<D: Digest> RunMerger<D> {
+    fn open(runs: &[TempFile], tree_height: usize) -> Result<Self, DapolError> {
+        let mut merger = RunMerger {
+            tree_height,
+            readers: Vec::with_capacity(runs.len()),
+            heads: Vec::with_capacity(runs.len()),
+            heap: BinaryHeap::with_capacity(runs.len()),
+        };
+        for (i, run) in runs.iter().enumerate() {
+            let mut reader = BufReader::new(run.open()?);
+            let head = read_record::<_, D>(&mut reader)?.map(|(index, node)| {
+                merger.heap.push(Reverse((index, i)));
+                node
+            });
+            merger.readers.push(reader);
+            merger.heads.push(head);
+        }
+        Ok(merger)
+    }
+}
+
+impl<D: Digest> Iterator for RunMerger<D> {
+    type Item = Result<(TreeIndex, DapolNode<D>), DapolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((index, i)) = self.heap.pop()?;
+        let node = self.heads[i].take().expect("run head should be present");
+        match read_record::<_, D>(&mut self.readers[i]) {
+            Ok(Some((next_index, next_node))) => {
+                self.heap.push(Reverse((next_index, i)));
+                self.heads[i] = Some(next_node);
+            }
+            Ok(None) => (),
+            Err(err) => {
+                self.heap.clear();
+                return Some(Err(err));
+            }
+        }
+        Some(Ok((TreeIndex::from_u64(self.tree_height, index), node)))
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Writes the specified leaves, which must be sorted by tree index, into a new temporary file.
+fn write_run<D: Digest>(
+    dir: &Path,
+    leaves: &[(TreeIndex, DapolNode<D>)],
+) -> Result<TempFile, DapolError> {
+    let (run, file) = TempFile::create(dir)?;
+    let mut writer = BufWriter::new(file);
+    for (idx, node) in leaves.iter() {
+        let mut record = Vec::with_capacity(RECORD_PREFIX_SIZE + D::output_size());
+        record.extend_from_slice(&index_to_u64(idx).to_be_bytes());
+        record.extend_from_slice(&node.get_value().to_be_bytes());
+        record.extend_from_slice(node.get_blinding().as_bytes());
+        record.extend_from_slice(node.get_commitment().compress().as_bytes());
+        record.extend_from_slice(node.get_hash());
+        writer.write_all(&record).map_err(temp_file_error)?;
+    }
+    writer.flush().map_err(temp_file_error)?;
+    Ok(run)
+}
+
+/// Reads a single leaf record from the specified temporary file reader; returns None if the end
+/// of the file has been reached.
+fn read_record<T: Read, D: Digest>(
+    reader: &mut T,
+) -> Result<Option<(u64, DapolNode<D>)>, DapolError> {
+    let mut record = vec![0u8; RECORD_PREFIX_SIZE + D::output_size()];
+    match reader.read_exact(&mut record) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(temp_file_error(err)),
+    }
+
+    let index = u64::from_be_bytes(record[..8].try_into().unwrap());
+    let value = u64::from_be_bytes(record[8..16].try_into().unwrap());
+    let blinding = Scalar::from_bits(record[16..48].try_into().unwrap());
+    let com = CompressedRistretto::from_slice(&record[48..80])
+        .decompress()
+        .ok_or_else(|| DapolError::TempFileIoFailed("invalid leaf commitment".to_string()))?;
+    let hash = record[RECORD_PREFIX_SIZE..].to_vec();
+    Ok(Some((
+        index,
+        DapolNode::from_parts(value, blinding, com, hash),
+    )))
+}
+
+fn temp_file_error(err: io::Error) -> DapolError {
+    DapolError::TempFileIoFailed(err.to_string())
+}
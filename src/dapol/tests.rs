@@ -1,7 +1,80 @@
-use super::{Dapol, DapolOptions, Liability, LiabilityId};
-use crate::{utils::get_secret, RangeProofPadding};
+use super::{
+    derive_sample_seed, parallel::ParallelNode, sample::select_sample_ranks,
+    verify_leaf_commitment, verify_leaf_index, verify_user_proof, ArchiveSink, AuditorTokenKind,
+    Dapol, DapolNode, DapolOptions, DirectorySink, Liability, LiabilityId, SampleOpening,
+    UserInclusionPackage,
+};
+use crate::{
+    errors::DapolError,
+    kdf::{self, MasterSecret, SubkeyPurpose},
+    proof::get_sibling_indexes,
+    DapolProof, DapolProofNode, RangeProofContext, RangeProofPadding, RangeProofSplitting,
+    RangeVerifiable, SolvencyProof,
+};
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
 
-use smtree::{index::TreeIndex, traits::Serializable};
+use smtree::{
+    index::TreeIndex,
+    pad_secret::Secret,
+    traits::{ProofExtractable, Serializable},
+};
+use std::convert::TryInto;
+
+// OPTIONS
+// ================================================================================================
+
+#[test]
+fn build_options() {
+    let options = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(16)
+        .aggregation_factor(4)
+        .build()
+        .unwrap();
+    assert_eq!("test".as_bytes(), options.audit_seed());
+    assert_eq!(16, options.tree_height());
+    assert_eq!(4, options.aggregation_factor());
+    assert_eq!(64, options.range_proof_bits());
+}
+
+#[test]
+fn build_options_with_invalid_params() {
+    let result = DapolOptions::builder().tree_height(4).build();
+    assert!(matches!(result, Err(DapolError::EmptyAuditSeed)));
+
+    let result = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(65)
+        .build();
+    assert!(matches!(result, Err(DapolError::TreeHeightTooBig(64, 65))));
+
+    let result = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(4)
+        .aggregation_factor(0)
+        .build();
+    assert!(matches!(
+        result,
+        Err(DapolError::InvalidAggregationFactor(4, 0))
+    ));
+
+    let result = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(4)
+        .aggregation_factor(5)
+        .build();
+    assert!(matches!(
+        result,
+        Err(DapolError::InvalidAggregationFactor(4, 5))
+    ));
+
+    let result = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .range_proof_bits(24)
+        .build();
+    assert!(matches!(result, Err(DapolError::InvalidRangeProofBits(24))));
+}
 
 // TREE CONSTRUCTION
 // ================================================================================================
@@ -10,7 +83,7 @@ use smtree::{index::TreeIndex, traits::Serializable};
 fn build_leaf_nodes() {
     let liabilities = build_test_liabilities();
     let (nodes, _) =
-        super::build_leaf_nodes::<blake2::Blake2s>(liabilities, "test".as_bytes(), 4).unwrap();
+        super::build_leaf_nodes::<blake2::Blake2s>(liabilities, "test".as_bytes(), &[], 4).unwrap();
     assert_eq!(4, nodes.len());
 }
 
@@ -24,6 +97,599 @@ fn build_dapol_tree() {
     assert_eq!(root_node.get_value(), 26);
 }
 
+#[test]
+fn build_dapol_tree_deterministically() {
+    let secret = [7u8; 32];
+    let build_options = || {
+        DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .tree_height(8)
+            .aggregation_factor(2)
+            .secret(Secret::from_bytes(&secret).unwrap())
+            .build()
+            .unwrap()
+    };
+
+    let tree1 =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), build_options())
+            .unwrap();
+    let tree2 =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), build_options())
+            .unwrap();
+    assert_eq!(tree1.root(), tree2.root());
+
+    // a different padding secret should result in a different root
+    let options = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(8)
+        .aggregation_factor(2)
+        .secret(Secret::from_bytes(&[8u8; 32]).unwrap())
+        .build()
+        .unwrap();
+    let tree3 = Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options)
+        .unwrap();
+    assert_ne!(tree1.root(), tree3.root());
+}
+
+#[test]
+fn build_dapol_tree_from_master_secret() {
+    let master_secret = MasterSecret::from_bytes(&[9u8; 32]).unwrap();
+    let build_options = || {
+        DapolOptions::builder()
+            .master_secret(&master_secret)
+            .tree_height(8)
+            .aggregation_factor(2)
+            .build()
+            .unwrap()
+    };
+
+    let options = build_options();
+    assert_eq!(master_secret.derive_audit_seed(), options.audit_seed());
+    assert_ne!(
+        master_secret.derive_audit_seed(),
+        master_secret.derive_padding_secret().as_bytes().to_vec()
+    );
+
+    // subkeys for different purposes should be independent of each other
+    let audit_id = blake2::Blake2s::digest(b"test").to_vec();
+    assert_ne!(
+        kdf::derive_subkey(SubkeyPurpose::IndexSeed, &audit_id),
+        kdf::derive_subkey(SubkeyPurpose::BlindSeed, &audit_id)
+    );
+
+    // both the audit seed and the padding secret are derived from the master secret, so the
+    // tree can be rebuilt from the master secret alone
+    let tree1 = Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options)
+        .unwrap();
+    let tree2 =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), build_options())
+            .unwrap();
+    assert_eq!(tree1.root(), tree2.root());
+}
+
+#[test]
+fn build_dapol_tree_in_parallel() {
+    let tree_height = 8;
+    let secret = [7u8; 32];
+
+    // build the tree using smtree only
+    let (leaves, _) = super::build_leaf_nodes::<blake2::Blake2s>(
+        build_test_liabilities(),
+        "test".as_bytes(),
+        &[],
+        tree_height,
+    )
+    .unwrap();
+    let mut expected = Dapol::<blake2::Blake2s, RangeProofPadding>::new_blank(tree_height, 2);
+    expected.build(&leaves, &Secret::from_bytes(&secret).unwrap());
+
+    // trees built using any number of threads should be the same as the one above
+    for &num_threads in [1, 4].iter() {
+        let options = DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .tree_height(tree_height)
+            .aggregation_factor(2)
+            .secret(Secret::from_bytes(&secret).unwrap())
+            .num_threads(num_threads)
+            .build()
+            .unwrap();
+        let tree =
+            Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options)
+                .unwrap();
+        assert_eq!(expected.root(), tree.root());
+
+        for (idx, _) in leaves.iter() {
+            assert_eq!(
+                expected
+                    .generate_proof(idx)
+                    .unwrap()
+                    .get_merkle_path()
+                    .serialize(),
+                tree.generate_proof(idx)
+                    .unwrap()
+                    .get_merkle_path()
+                    .serialize()
+            );
+        }
+    }
+}
+
+#[test]
+fn build_dapol_tree_with_overflowing_total() {
+    let mut liabilities = build_test_liabilities();
+    liabilities[0].value = u64::MAX;
+    let result =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(liabilities, build_test_options(8, 2));
+    assert!(matches!(result, Err(DapolError::TotalOutOfRange(_, 64))));
+
+    // adding a liability should not push the total out of range either
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+    let result = tree.insert_liability(build_liability("e", "v", u64::MAX - 10));
+    assert!(matches!(result, Err(DapolError::TotalOutOfRange(_, 64))));
+    let result = tree.update_liability(build_liability("a", "w", u64::MAX - 20));
+    assert!(matches!(result, Err(DapolError::TotalOutOfRange(_, 64))));
+    assert_eq!(26, tree.root_raw().get_value());
+
+    // overflows in subtrees should be reported together with the position of the subtree
+    let leaves = vec![
+        (
+            TreeIndex::from_u64(2, 2),
+            DapolNode::<blake2::Blake2s>::new(u64::MAX, Scalar::one()),
+        ),
+        (
+            TreeIndex::from_u64(2, 3),
+            DapolNode::<blake2::Blake2s>::new(1, Scalar::one()),
+        ),
+    ];
+    let secret = Secret::from_bytes(&[7u8; 32]).unwrap();
+    let result = super::parallel::build_tree(2, &leaves, &secret);
+    assert!(matches!(result, Err(DapolError::SubtreeSumOverflow(1, 1))));
+}
+
+#[test]
+fn build_dapol_tree_with_narrow_range_proofs() {
+    let options = || {
+        DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .tree_height(8)
+            .aggregation_factor(2)
+            .range_proof_bits(32)
+            .build()
+            .unwrap()
+    };
+
+    // liabilities which do not fit into 32 bits should be rejected
+    let mut liabilities = build_test_liabilities();
+    liabilities[1].value = 1 << 32;
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::new(liabilities, options());
+    assert!(matches!(
+        result,
+        Err(DapolError::LiabilityOutOfRange(_, 4294967296, 32))
+    ));
+
+    let mut liabilities = build_test_liabilities();
+    liabilities[0].value = u32::MAX as u64;
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::new(liabilities, options());
+    assert!(matches!(result, Err(DapolError::TotalOutOfRange(_, 32))));
+
+    let mut tree =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options())
+            .unwrap();
+    assert_eq!(32, tree.bit_size());
+    let result = tree.insert_liability(build_liability("e", "v", 1 << 40));
+    assert!(matches!(
+        result,
+        Err(DapolError::LiabilityOutOfRange(_, 1099511627776, 32))
+    ));
+
+    // proofs should record the bit width, and verify with it after deserialization
+    let id = LiabilityId::from_str("c");
+    let idx = tree.id_to_idx_map[&id];
+    let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    let proof =
+        DapolProof::<blake2::Blake2s, RangeProofPadding>::deserialize(&proof.serialize()).unwrap();
+    assert_eq!(32, proof.get_range_proofs().get_bit_size());
+    assert!(proof.verify(&tree.root(), &leaf.get_proof_node()));
+}
+
+#[test]
+fn build_dapol_tree_with_wide_digest() {
+    // 64-byte digests should be usable both directly and when streaming liabilities
+    let tree = Dapol::<sha2::Sha512, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+    assert_eq!(64, tree.root().get_hash().len());
+    assert_eq!(26, tree.root_raw().get_value());
+
+    let liabilities = build_test_liabilities().into_iter().map(Ok::<_, String>);
+    let options = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(8)
+        .aggregation_factor(2)
+        .chunk_size(1)
+        .build()
+        .unwrap();
+    let streamed = Dapol::<sha2::Sha512, RangeProofPadding>::from_stream(liabilities, options);
+    assert_eq!(tree.id_to_idx_map, streamed.unwrap().id_to_idx_map);
+
+    let id = LiabilityId::from_str("d");
+    let idx = tree.id_to_idx_map[&id];
+    let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    let proof =
+        DapolProof::<sha2::Sha512, RangeProofPadding>::deserialize(&proof.serialize()).unwrap();
+    assert!(proof.verify(&tree.root(), &leaf.get_proof_node()));
+
+    // digests shorter than 32 bytes should be rejected
+    let result = Dapol::<sha2::Sha224, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    );
+    assert!(matches!(result, Err(DapolError::DigestTooShort(32, 28))));
+}
+
+#[test]
+fn build_dapol_tree_from_stream() {
+    let secret = [7u8; 32];
+    let expected = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options_with_secret(8, 2, &secret),
+    )
+    .unwrap();
+
+    let temp_dir = std::env::temp_dir().join(format!("dapol-test-{}", std::process::id()));
+    std::fs::create_dir_all(&temp_dir).unwrap();
+
+    // trees built with any chunk size should be the same as the one built from a vector; smaller
+    // chunks are sorted via temporary files, which should be removed afterwards
+    for &chunk_size in [1, 3, 16].iter() {
+        let options = DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .tree_height(8)
+            .aggregation_factor(2)
+            .secret(Secret::from_bytes(&secret).unwrap())
+            .chunk_size(chunk_size)
+            .temp_dir(&temp_dir)
+            .build()
+            .unwrap();
+        let liabilities = build_test_liabilities().into_iter().map(Ok::<_, String>);
+        let tree =
+            Dapol::<blake2::Blake2s, RangeProofPadding>::from_stream(liabilities, options).unwrap();
+        assert_eq!(expected.root(), tree.root());
+        assert_eq!(expected.id_to_idx_map, tree.id_to_idx_map);
+        assert_eq!(0, std::fs::read_dir(&temp_dir).unwrap().count());
+    }
+    std::fs::remove_dir(&temp_dir).unwrap();
+}
+
+#[test]
+fn build_dapol_tree_from_invalid_stream() {
+    let options = || {
+        DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .tree_height(8)
+            .chunk_size(2)
+            .build()
+            .unwrap()
+    };
+
+    // errors of the underlying stream should be reported together with the liability position
+    let liabilities = build_test_liabilities()
+        .into_iter()
+        .map(Ok)
+        .chain(std::iter::once(Err("broken record")));
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::from_stream(liabilities, options());
+    assert!(matches!(
+        result,
+        Err(DapolError::LiabilityIngestFailed(4, message)) if message == "broken record"
+    ));
+
+    // duplicated internal IDs should be detected across chunks
+    let mut liabilities = build_test_liabilities();
+    liabilities.push(build_liability("a", "v", 13));
+    let liabilities = liabilities.into_iter().map(Ok::<_, String>);
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::from_stream(liabilities, options());
+    assert!(matches!(result, Err(DapolError::DuplicatedInternalId(_))));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serialize_liabilities() {
+    let liability = build_liability("a", "w", 3);
+    let json = serde_json::to_string(&liability).unwrap();
+    assert_eq!(r#"{"internal_id":"61","external_id":"77","value":3}"#, json);
+    let decoded: Liability = serde_json::from_str(&json).unwrap();
+    assert_eq!(liability.internal_id, decoded.internal_id);
+    assert_eq!(liability.external_id, decoded.external_id);
+    assert_eq!(liability.value, decoded.value);
+
+    let result = serde_json::from_str::<LiabilityId>(r#""6""#);
+    assert!(result.is_err());
+}
+
+// LIABILITY UPDATES
+// ================================================================================================
+
+#[test]
+fn insert_liability() {
+    let secret = [7u8; 32];
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options_with_secret(8, 2, &secret),
+    )
+    .unwrap();
+
+    let index = tree
+        .insert_liability(build_liability("e", "v", 13))
+        .unwrap();
+    assert_eq!(39, tree.root_raw().get_value());
+
+    // the tree should be the same as the one built from all liabilities at once
+    let mut liabilities = build_test_liabilities();
+    liabilities.push(build_liability("e", "v", 13));
+    let expected = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        liabilities,
+        build_test_options_with_secret(8, 2, &secret),
+    )
+    .unwrap();
+    assert_eq!(expected.root(), tree.root());
+
+    let proof = tree
+        .generate_proof_for_id(&LiabilityId::from_str("e"))
+        .unwrap();
+    assert_eq!(
+        expected
+            .generate_proof(&index)
+            .unwrap()
+            .get_merkle_path()
+            .serialize(),
+        proof.get_merkle_path().serialize()
+    );
+
+    // inserting a liability with the same internal ID should fail
+    let result = tree.insert_liability(build_liability("e", "u", 17));
+    assert!(matches!(result, Err(DapolError::DuplicatedInternalId(_))));
+}
+
+#[test]
+fn update_liability() {
+    let secret = [7u8; 32];
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options_with_secret(8, 2, &secret),
+    )
+    .unwrap();
+
+    tree.update_liability(build_liability("b", "x", 50))
+        .unwrap();
+    assert_eq!(71, tree.root_raw().get_value());
+
+    // the tree should be the same as the one built from updated liabilities
+    let mut liabilities = build_test_liabilities();
+    liabilities[1].value = 50;
+    let expected = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        liabilities,
+        build_test_options_with_secret(8, 2, &secret),
+    )
+    .unwrap();
+    assert_eq!(expected.root(), tree.root());
+
+    // updating a liability which does not exist should fail
+    let result = tree.update_liability(build_liability("e", "v", 13));
+    assert!(matches!(result, Err(DapolError::UnknownInternalId(_))));
+
+    // a liability which is not mapped to a leaf should be reported instead of panicking
+    let empty_idx = (0..256)
+        .map(|pos| TreeIndex::from_u64(8, pos))
+        .find(|idx| tree.smt.get_leaf_by_index(idx).is_none())
+        .unwrap();
+    tree.id_to_idx_map
+        .insert(LiabilityId::from_str("e"), empty_idx);
+    let result = tree.update_liability(build_liability("e", "v", 13));
+    assert!(matches!(result, Err(DapolError::UnmappedInternalId(_))));
+}
+
+#[test]
+fn remove_liability() {
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+
+    tree.remove_liability(&LiabilityId::from_str("c")).unwrap();
+    assert_eq!(19, tree.root_raw().get_value());
+    assert!(tree
+        .generate_proof_for_id(&LiabilityId::from_str("c"))
+        .is_none());
+
+    // proofs for the remaining liabilities should still be valid
+    for id in ["a", "b", "d"].iter() {
+        let idx = tree.id_to_idx_map[&LiabilityId::from_str(id)];
+        let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+        let proof = tree
+            .generate_proof_for_id(&LiabilityId::from_str(id))
+            .unwrap();
+        assert!(proof.verify(&tree.root(), &leaf.get_proof_node()));
+    }
+
+    // removing a liability which does not exist should fail
+    let result = tree.remove_liability(&LiabilityId::from_str("c"));
+    assert!(matches!(result, Err(DapolError::UnknownInternalId(_))));
+}
+
+// SNAPSHOTS
+// ================================================================================================
+
+#[test]
+fn save_and_load_snapshot() {
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+    tree.remove_liability(&LiabilityId::from_str("c")).unwrap();
+
+    let mut snapshot = Vec::new();
+    tree.write_snapshot(&mut snapshot).unwrap();
+    let mut loaded =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut snapshot.as_slice())
+            .unwrap();
+
+    assert_eq!(tree.root(), loaded.root());
+    assert_eq!(tree.root_raw().get_value(), loaded.root_raw().get_value());
+    assert_eq!(
+        tree.root_raw().get_blinding(),
+        loaded.root_raw().get_blinding()
+    );
+    assert_eq!(tree.id_to_idx_map, loaded.id_to_idx_map);
+    assert_eq!(tree.aggregation_factor, loaded.aggregation_factor);
+    assert_eq!(tree.bit_size, loaded.bit_size);
+
+    // proofs generated by the loaded tree should be valid
+    let id = LiabilityId::from_str("b");
+    let idx = loaded.id_to_idx_map[&id];
+    let leaf = loaded.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    let proof = loaded.generate_proof_for_id(&id).unwrap();
+    assert!(proof.verify(&tree.root(), &leaf.get_proof_node()));
+
+    // updates applied to both trees should result in the same root
+    tree.insert_liability(build_liability("e", "v", 13))
+        .unwrap();
+    loaded
+        .insert_liability(build_liability("e", "v", 13))
+        .unwrap();
+    assert_eq!(tree.root(), loaded.root());
+
+    // the same should work via a file
+    let path = std::env::temp_dir().join(format!("dapol-snapshot-{}", std::process::id()));
+    tree.save_snapshot(&path).unwrap();
+    let loaded = Dapol::<blake2::Blake2s, RangeProofPadding>::load_snapshot(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(tree.root(), loaded.root());
+}
+
+#[test]
+fn load_invalid_snapshot() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+    let mut snapshot = Vec::new();
+    tree.write_snapshot(&mut snapshot).unwrap();
+
+    // corrupted contents should be detected via checksum
+    let mut corrupted = snapshot.clone();
+    corrupted[snapshot.len() - 33] ^= 1;
+    let result =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut corrupted.as_slice());
+    assert!(matches!(result, Err(DapolError::SnapshotChecksumMismatch)));
+
+    // truncated snapshot should be rejected
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut &snapshot[..10]);
+    assert!(matches!(result, Err(DapolError::MalformedSnapshot(_))));
+
+    // snapshot written for a different digest should be rejected
+    let result =
+        Dapol::<blake2::Blake2b, RangeProofPadding>::read_snapshot(&mut snapshot.as_slice());
+    assert!(matches!(result, Err(DapolError::InvalidDigestSize(64, 32))));
+
+    // a forged length should be rejected once the bytes run out, without allocating memory for
+    // the forged length up front
+    let audit_seed_pos = 23;
+    let mut forged = snapshot[..audit_seed_pos].to_vec();
+    forged.extend_from_slice(&u32::MAX.to_be_bytes());
+    forged.extend_from_slice(&[1u8; 100]);
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut forged.as_slice());
+    assert!(matches!(result, Err(DapolError::MalformedSnapshot(_))));
+
+    // a root which does not match the nodes should be rejected even with a valid checksum
+    let root_pos = audit_seed_pos + 4 + tree.audit_seed.len() + 4 + tree.context.len() + 32;
+    let mut forged = snapshot[..snapshot.len() - 32].to_vec();
+    forged[root_pos] ^= 1;
+    let checksum = blake3::hash(&forged);
+    forged.extend_from_slice(checksum.as_bytes());
+    let result = Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut forged.as_slice());
+    assert!(matches!(result, Err(DapolError::MalformedSnapshot(_))));
+}
+
+#[test]
+fn load_inconsistent_snapshot() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 2),
+    )
+    .unwrap();
+    let mut snapshot = Vec::new();
+    tree.write_snapshot(&mut snapshot).unwrap();
+    let snapshot = snapshot[..snapshot.len() - 32].to_vec();
+    let read = |mut forged: Vec<u8>| {
+        let checksum = blake3::hash(&forged);
+        forged.extend_from_slice(checksum.as_bytes());
+        Dapol::<blake2::Blake2s, RangeProofPadding>::read_snapshot(&mut forged.as_slice())
+    };
+
+    // each node takes 114 bytes: type, index, value, blinding factor, commitment and hash
+    let nodes_pos = 23 + 4 + tree.audit_seed.len() + 4 + tree.context.len() + 32 + 32;
+    let num_nodes = u64::from_be_bytes(snapshot[nodes_pos..nodes_pos + 8].try_into().unwrap());
+    let node_pos = |i: usize| nodes_pos + 8 + i * 114;
+    let leaf_pos = (0..num_nodes as usize)
+        .map(node_pos)
+        .find(|&pos| snapshot[pos] == 2)
+        .unwrap();
+    let padding_pos = (0..num_nodes as usize)
+        .map(node_pos)
+        .find(|&pos| snapshot[pos] == 1 && snapshot[pos + 1] == 8)
+        .unwrap();
+    let ids_pos = node_pos(num_nodes as usize);
+    assert!(read(snapshot.clone()).is_ok());
+
+    // the value of a leaf should match its commitment, even if the root is unchanged
+    let mut forged = snapshot.clone();
+    forged[leaf_pos + 17] ^= 1;
+    assert!(matches!(
+        read(forged),
+        Err(DapolError::MalformedSnapshot(_))
+    ));
+
+    // the value of a padding node should be 0
+    let mut forged = snapshot.clone();
+    forged[padding_pos + 17] ^= 1;
+    assert!(matches!(
+        read(forged),
+        Err(DapolError::MalformedSnapshot(_))
+    ));
+
+    // a padding node which is not part of the tree should be rejected
+    let mut forged = snapshot[..ids_pos].to_vec();
+    forged[nodes_pos..nodes_pos + 8].copy_from_slice(&(num_nodes + 1).to_be_bytes());
+    forged.extend_from_slice(&snapshot[padding_pos..padding_pos + 114]);
+    forged[ids_pos + 1..ids_pos + 10].copy_from_slice(&snapshot[leaf_pos + 1..leaf_pos + 10]);
+    forged.extend_from_slice(&snapshot[ids_pos..]);
+    assert!(matches!(
+        read(forged),
+        Err(DapolError::MalformedSnapshot(_))
+    ));
+
+    // a liability mapped to a padding node should be rejected
+    let id_len = u32::from_be_bytes(snapshot[ids_pos + 8..ids_pos + 12].try_into().unwrap());
+    let idx_pos = ids_pos + 12 + id_len as usize;
+    let mut forged = snapshot.clone();
+    forged[idx_pos..idx_pos + 9].copy_from_slice(&snapshot[padding_pos + 1..padding_pos + 10]);
+    assert!(matches!(
+        read(forged),
+        Err(DapolError::MalformedSnapshot(_))
+    ));
+}
+
 // PROOF GENERATION
 // ================================================================================================
 
@@ -35,51 +701,51 @@ fn generate_proof_for_id() {
     let liabilities = build_test_liabilities();
     let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(liabilities, options).unwrap();
 
-    // ID "a" should map to index 7
+    // ID "a" should map to index 5
     let proof_a = tree
         .generate_proof_for_id(&LiabilityId::from_str("a"))
         .unwrap();
-    let proof_7 = tree
-        .generate_proof(&TreeIndex::from_u64(tree_height, 7))
+    let proof_5 = tree
+        .generate_proof(&TreeIndex::from_u64(tree_height, 5))
         .unwrap();
     assert_eq!(
-        proof_7.get_merkle_path().serialize(),
+        proof_5.get_merkle_path().serialize(),
         proof_a.get_merkle_path().serialize()
     );
 
-    // ID "b" should map to index 12
+    // ID "b" should map to index 9
     let proof_b = tree
         .generate_proof_for_id(&LiabilityId::from_str("b"))
         .unwrap();
-    let proof_12 = tree
-        .generate_proof(&TreeIndex::from_u64(tree_height, 12))
+    let proof_9 = tree
+        .generate_proof(&TreeIndex::from_u64(tree_height, 9))
         .unwrap();
     assert_eq!(
-        proof_12.get_merkle_path().serialize(),
+        proof_9.get_merkle_path().serialize(),
         proof_b.get_merkle_path().serialize()
     );
 
-    // ID "c" should map to index 2
+    // ID "c" should map to index 0
     let proof_c = tree
         .generate_proof_for_id(&LiabilityId::from_str("c"))
         .unwrap();
-    let proof_2 = tree
-        .generate_proof(&TreeIndex::from_u64(tree_height, 2))
+    let proof_0 = tree
+        .generate_proof(&TreeIndex::from_u64(tree_height, 0))
         .unwrap();
     assert_eq!(
-        proof_2.get_merkle_path().serialize(),
+        proof_0.get_merkle_path().serialize(),
         proof_c.get_merkle_path().serialize()
     );
 
-    // ID "d" should map to index 4
+    // ID "d" should map to index 12
     let proof_d = tree
         .generate_proof_for_id(&LiabilityId::from_str("d"))
         .unwrap();
-    let proof_4 = tree
-        .generate_proof(&TreeIndex::from_u64(tree_height, 4))
+    let proof_12 = tree
+        .generate_proof(&TreeIndex::from_u64(tree_height, 12))
         .unwrap();
     assert_eq!(
-        proof_4.get_merkle_path().serialize(),
+        proof_12.get_merkle_path().serialize(),
         proof_d.get_merkle_path().serialize()
     );
 }
@@ -92,11 +758,11 @@ fn generate_proof_batch_for_ids() {
     let liabilities = build_test_liabilities();
     let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(liabilities, options).unwrap();
 
-    // IDs ["a", "b"] should map to indexes [7, 12]
+    // IDs ["a", "b"] should map to indexes [5, 9]
     let ids = [LiabilityId::from_str("a"), LiabilityId::from_str("b")];
     let idx = [
-        TreeIndex::from_u64(tree_height, 7),
-        TreeIndex::from_u64(tree_height, 12),
+        TreeIndex::from_u64(tree_height, 5),
+        TreeIndex::from_u64(tree_height, 9),
     ];
 
     let actual = tree.generate_proof_batch_for_ids(&ids).unwrap();
@@ -107,15 +773,607 @@ fn generate_proof_batch_for_ids() {
     );
 }
 
+#[test]
+fn generate_proof_with_context() {
+    let options = |context: &str| {
+        DapolOptions::builder()
+            .audit_seed("test".as_bytes())
+            .context(context.as_bytes())
+            .tree_height(4)
+            .aggregation_factor(2)
+            .secret(Secret::from_bytes(&[7u8; 32]).unwrap())
+            .build()
+            .unwrap()
+    };
+    let tree =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options("A"))
+            .unwrap();
+    let other =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options("B"))
+            .unwrap();
+    assert_eq!(b"A", tree.context());
+    assert_ne!(tree.root(), other.root());
+
+    // users should be able to recompute their leaf from the commitment and the context
+    let id = LiabilityId::from_str("a");
+    let idx = tree.id_to_idx_map[&id];
+    let leaf = tree.smt.get_leaf_by_index(&idx).unwrap().get_value();
+    let user_leaf = DapolProofNode::new_leaf(*leaf.get_commitment(), b"A");
+    assert_eq!(leaf.get_proof_node(), user_leaf);
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    assert!(proof.verify(&tree.root(), &user_leaf));
+    let wrong_leaf = DapolProofNode::new_leaf(*leaf.get_commitment(), b"B");
+    assert!(!proof.verify(&tree.root(), &wrong_leaf));
+
+    // a padding node should not hash the same as a leaf with the same commitment
+    let padding =
+        DapolNode::<blake2::Blake2s>::new_padding(&idx, &Secret::from_bytes(&[7u8; 32]).unwrap());
+    let leaf = DapolNode::<blake2::Blake2s>::new(0, padding.get_blinding());
+    assert_eq!(padding.get_commitment(), leaf.get_commitment());
+    assert_ne!(padding.get_hash(), leaf.get_hash());
+
+    // proof envelopes should record the hash layout version
+    let header = proof.envelope_header();
+    assert_eq!(
+        crate::hashing::HASH_LAYOUT_VERSION,
+        header.hash_layout_version()
+    );
+    let mut bytes = proof.serialize_envelope();
+    bytes[9] = 1;
+    let result = DapolProof::<blake2::Blake2s, RangeProofPadding>::deserialize_envelope(&bytes);
+    assert!(result.is_err());
+}
+
+#[test]
+fn generate_solvency_proof() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(4, 2),
+    )
+    .unwrap();
+    let reserves_blinding = Scalar::from(42u64);
+
+    // reserves which cover the total of 26 should be provable
+    let reserves_com = SolvencyProof::commit_reserves(30, &reserves_blinding);
+    let proof = tree
+        .generate_solvency_proof(30, &reserves_blinding)
+        .unwrap();
+    let proof = SolvencyProof::deserialize(&proof.serialize()).unwrap();
+    assert!(proof.verify(&tree.root(), &reserves_com));
+
+    // the proof should not verify against different reserves
+    let other_com = SolvencyProof::commit_reserves(31, &reserves_blinding);
+    assert!(!proof.verify(&tree.root(), &other_com));
+
+    // reserves which do not cover the total should be rejected
+    let result = tree.generate_solvency_proof(25, &reserves_blinding);
+    assert!(matches!(
+        result,
+        Err(DapolError::InsufficientReserves(25, 26))
+    ));
+}
+
+#[test]
+fn bind_range_proofs_to_context() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofSplitting>::new(
+        build_test_liabilities(),
+        build_test_options(8, 3),
+    )
+    .unwrap();
+    let other = Dapol::<blake2::Blake2s, RangeProofSplitting>::new(
+        build_test_liabilities(),
+        build_test_options(8, 3),
+    )
+    .unwrap();
+
+    let idx = tree.id_to_idx_map[&LiabilityId::from_str("a")];
+    let proof = tree.generate_proof(&idx).unwrap();
+    let commitments: Vec<_> = (0..proof.get_merkle_path().get_siblings_num())
+        .map(|i| {
+            proof
+                .get_merkle_path()
+                .get_sibling_at_idx(i)
+                .get_com()
+                .compress()
+        })
+        .collect();
+    let positions = get_sibling_indexes(&[idx]);
+    assert_eq!(8, positions.len());
+
+    // range proofs should verify only against the root, height and positions they were bound to
+    let root = tree.root();
+    let context = RangeProofContext::new(root.get_hash(), 8, &positions);
+    assert!(proof.get_range_proofs().verify(&commitments, &context));
+    let other_root = other.root();
+    let context = RangeProofContext::new(other_root.get_hash(), 8, &positions);
+    assert!(!proof.get_range_proofs().verify(&commitments, &context));
+    let context = RangeProofContext::new(root.get_hash(), 9, &positions);
+    assert!(!proof.get_range_proofs().verify(&commitments, &context));
+    let mut shifted = positions.clone();
+    shifted[7] = shifted[7].get_sibling_index();
+    let context = RangeProofContext::new(root.get_hash(), 8, &shifted);
+    assert!(!proof.get_range_proofs().verify(&commitments, &context));
+    let context = RangeProofContext::new(root.get_hash(), 8, &positions[1..]);
+    assert!(!proof.get_range_proofs().verify(&commitments, &context));
+
+    // batch proofs should bind range proofs to the positions of siblings of all leaves
+    let mut indexes: Vec<TreeIndex> = tree.id_to_idx_map.values().copied().collect();
+    indexes.sort();
+    let leaves: Vec<DapolProofNode<blake2::Blake2s>> = indexes
+        .iter()
+        .map(|idx| {
+            let leaf = tree.smt.get_leaf_by_index(idx).unwrap();
+            leaf.get_value().get_proof_node()
+        })
+        .collect();
+    let proof = tree.generate_proof_batch(&indexes).unwrap();
+    assert!(proof.verify_batch(&root, &leaves));
+    assert!(!proof.verify_batch(&other_root, &leaves));
+}
+
+#[test]
+fn verify_user_proof_from_credentials() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(16, 3),
+    )
+    .unwrap();
+    let root = tree.root();
+    let audit_id = blake2::Blake2s::digest(b"testa").to_vec();
+    let external_id = LiabilityId::from_str("w");
+    let proof = tree
+        .generate_proof_for_id(&LiabilityId::from_str("a"))
+        .unwrap()
+        .serialize();
+
+    // the user should be able to verify the proof knowing only their credentials
+    verify_user_proof::<_, RangeProofPadding>(&audit_id, &external_id, 3, b"", &proof, &root)
+        .unwrap();
+
+    // a wrong value should not verify
+    let result =
+        verify_user_proof::<_, RangeProofPadding>(&audit_id, &external_id, 4, b"", &proof, &root);
+    assert!(matches!(result, Err(DapolError::InvalidProof)));
+
+    // a proof for a leaf of another user should be rejected
+    let other_proof = tree
+        .generate_proof_for_id(&LiabilityId::from_str("b"))
+        .unwrap()
+        .serialize();
+    let result = verify_user_proof::<_, RangeProofPadding>(
+        &audit_id,
+        &external_id,
+        3,
+        b"",
+        &other_proof,
+        &root,
+    );
+    assert!(matches!(
+        result,
+        Err(DapolError::UnexpectedLeafIndex(16, _))
+    ));
+
+    // a batch proof should be rejected
+    let mut indexes = vec![
+        tree.id_to_idx_map[&LiabilityId::from_str("a")],
+        tree.id_to_idx_map[&LiabilityId::from_str("b")],
+    ];
+    indexes.sort();
+    let batch_proof = tree.generate_proof_batch(&indexes).unwrap().serialize();
+    let result = verify_user_proof::<_, RangeProofPadding>(
+        &audit_id,
+        &external_id,
+        3,
+        b"",
+        &batch_proof,
+        &root,
+    );
+    assert!(matches!(result, Err(DapolError::NotSingleLeafProof(2))));
+
+    // malformed bytes should be rejected
+    let result = verify_user_proof::<_, RangeProofPadding>(
+        &audit_id,
+        &external_id,
+        3,
+        b"",
+        &proof[..10],
+        &root,
+    );
+    assert!(matches!(result, Err(DapolError::ProofDecodingFailed(_))));
+}
+
+#[test]
+fn generate_inclusion_package() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 3),
+    )
+    .unwrap();
+    let root = tree.root();
+    let package = tree
+        .generate_inclusion_package(&LiabilityId::from_str("c"))
+        .unwrap();
+    assert_eq!(&blake2::Blake2s::digest(b"testc")[..], package.audit_id());
+    assert_eq!(7, package.value());
+    assert_eq!(8, package.tree_height());
+    assert!(tree
+        .generate_inclusion_package(&LiabilityId::from_str("e"))
+        .is_none());
+
+    // the package should survive a round trip and verify with the external ID of the user
+    let bytes = package.serialize();
+    let package =
+        UserInclusionPackage::<blake2::Blake2s, RangeProofPadding>::deserialize(&bytes).unwrap();
+    assert_eq!(bytes, package.serialize());
+    package.verify(&LiabilityId::from_str("y"), &root).unwrap();
+
+    // a wrong external ID or a different published root should be rejected
+    assert!(package.verify(&LiabilityId::from_str("z"), &root).is_err());
+    let other = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(8, 3),
+    )
+    .unwrap();
+    let result = package.verify(&LiabilityId::from_str("y"), &other.root());
+    assert!(matches!(result, Err(DapolError::RootMismatch)));
+
+    // truncated or extended bytes should be rejected
+    let result =
+        UserInclusionPackage::<blake2::Blake2s, RangeProofPadding>::deserialize(&bytes[..50]);
+    assert!(matches!(
+        result,
+        Err(DapolError::MalformedInclusionPackage(_))
+    ));
+    let mut extended = bytes.clone();
+    extended.push(0);
+    let result = UserInclusionPackage::<blake2::Blake2s, RangeProofPadding>::deserialize(&extended);
+    assert!(matches!(
+        result,
+        Err(DapolError::MalformedInclusionPackage(_))
+    ));
+}
+
+#[test]
+fn export_all_proofs() {
+    let mut tree = Dapol::<blake2::Blake2s, RangeProofSplitting>::new(
+        build_test_liabilities(),
+        build_test_options(8, 3),
+    )
+    .unwrap();
+    tree.remove_liability(&LiabilityId::from_str("d")).unwrap();
+    let root = tree.root();
+
+    // proofs should be exported for all remaining liabilities and verify for their owners
+    let proofs = std::sync::Mutex::new(Vec::new());
+    let num_exported = tree
+        .export_all_proofs(
+            &|id: &LiabilityId, proof: &DapolProof<blake2::Blake2s, RangeProofSplitting>| {
+                proofs.lock().unwrap().push((id.clone(), proof.serialize()));
+                Ok(())
+            },
+            Some(2),
+        )
+        .unwrap();
+    assert_eq!(3, num_exported);
+    let mut proofs = proofs.into_inner().unwrap();
+    proofs.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+    for ((id, proof), (external_id, value)) in proofs.iter().zip([("w", 3), ("x", 5), ("y", 7)]) {
+        let audit_id = blake2::Blake2s::digest(&[b"test", id.as_bytes()].concat()).to_vec();
+        verify_user_proof::<_, RangeProofSplitting>(
+            &audit_id,
+            &LiabilityId::from_str(external_id),
+            value,
+            b"",
+            proof,
+            &root,
+        )
+        .unwrap();
+    }
+
+    // proofs should be written into separate files of a directory
+    let dir = std::env::temp_dir().join(format!("dapol-export-{}", std::process::id()));
+    let sink = DirectorySink::new(&dir).unwrap();
+    assert_eq!(3, tree.export_all_proofs(&sink, None).unwrap());
+    let bytes = std::fs::read(sink.proof_path(&LiabilityId::from_str("a"))).unwrap();
+    let proof = DapolProof::<blake2::Blake2s, RangeProofSplitting>::deserialize(&bytes).unwrap();
+    let leaf = tree
+        .generate_proof_for_id(&LiabilityId::from_str("a"))
+        .unwrap();
+    assert_eq!(
+        leaf.get_merkle_path().get_indexes(),
+        proof.get_merkle_path().get_indexes()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // or into records of a single archive
+    let path = std::env::temp_dir().join(format!("dapol-archive-{}", std::process::id()));
+    let sink = ArchiveSink::create(&path).unwrap();
+    assert_eq!(3, tree.export_all_proofs(&sink, None).unwrap());
+    sink.finish().unwrap();
+    let records = ArchiveSink::read_archive(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(3, records.len());
+    for (_, bytes) in records.iter() {
+        assert!(DapolProof::<blake2::Blake2s, RangeProofSplitting>::deserialize(bytes).is_ok());
+    }
+}
+
+#[test]
+fn generate_proofs_with_range_proof_cache() {
+    let options = DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(8)
+        .aggregation_factor(3)
+        .range_proof_cache_size(16)
+        .build()
+        .unwrap();
+    let mut tree =
+        Dapol::<blake2::Blake2s, RangeProofPadding>::new(build_test_liabilities(), options)
+            .unwrap();
+    let cache = tree.range_proof_cache().unwrap();
+    assert!(cache.is_empty());
+
+    // a proof consists of an aggregated proof for the top 3 siblings and 5 individual proofs
+    let id = LiabilityId::from_str("a");
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    assert_eq!(6, cache.len());
+
+    // the same range proofs should be handed out again
+    let cached_proof = tree.generate_proof_for_id(&id).unwrap();
+    assert_eq!(6, cache.len());
+    assert_eq!(proof.serialize(), cached_proof.serialize());
+
+    // proofs for other leaves should reuse the shared proofs and still verify
+    for (internal_id, external_id, value) in [("b", "x", 5), ("c", "y", 7), ("d", "z", 11)] {
+        let internal_id = LiabilityId::from_str(internal_id);
+        let proof = tree
+            .generate_proof_for_id(&internal_id)
+            .unwrap()
+            .serialize();
+        let audit_id = blake2::Blake2s::digest(&[b"test", internal_id.as_bytes()].concat());
+        verify_user_proof::<_, RangeProofPadding>(
+            &audit_id,
+            &LiabilityId::from_str(external_id),
+            value,
+            b"",
+            &proof,
+            &tree.root(),
+        )
+        .unwrap();
+    }
+    assert!(cache.len() <= 16);
+
+    // updates should invalidate the cache
+    tree.insert_liability(build_liability("e", "v", 13))
+        .unwrap();
+    assert!(tree.range_proof_cache().unwrap().is_empty());
+    let proof = tree.generate_proof_for_id(&id).unwrap();
+    let leaf = tree
+        .smt
+        .get_leaf_by_index(&tree.id_to_idx_map[&id])
+        .unwrap();
+    assert!(proof.verify(&tree.root(), &leaf.get_value().get_proof_node()));
+
+    // a cache of a small size should never exceed its capacity
+    tree.set_range_proof_cache_size(4);
+    tree.generate_proof_for_id(&id).unwrap();
+    assert_eq!(4, tree.range_proof_cache().unwrap().len());
+    tree.set_range_proof_cache_size(0);
+    assert!(tree.range_proof_cache().is_none());
+}
+
+// AUDITOR TOKENS
+// ================================================================================================
+
+#[test]
+fn verify_leaves_with_auditor_tokens() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofPadding>::new(
+        build_test_liabilities(),
+        build_test_options(16, 3),
+    )
+    .unwrap();
+    let internal_id = LiabilityId::from_str("a");
+    let external_id = LiabilityId::from_str("w");
+    let leaf_index = tree.id_to_idx_map[&internal_id];
+    let leaf = tree
+        .smt
+        .get_leaf_by_index(&leaf_index)
+        .unwrap()
+        .get_value()
+        .get_proof_node();
+
+    // tokens should be derived from the audit ID of the user
+    let audit_id = blake2::Blake2s::digest(b"testa").to_vec();
+    let index_token = tree
+        .generate_auditor_token(&internal_id, AuditorTokenKind::Index)
+        .unwrap();
+    let blind_token = tree
+        .generate_auditor_token(&internal_id, AuditorTokenKind::Blind)
+        .unwrap();
+    assert_eq!(
+        kdf::derive_subkey(SubkeyPurpose::IndexSeed, &audit_id).to_vec(),
+        index_token
+    );
+    assert_eq!(
+        kdf::derive_subkey(SubkeyPurpose::BlindSeed, &audit_id).to_vec(),
+        blind_token
+    );
+    assert!(tree
+        .generate_auditor_token(&LiabilityId::from_str("e"), AuditorTokenKind::Index)
+        .is_none());
+
+    // the auditor should be able to check the placement and the commitment of the leaf
+    verify_leaf_index::<blake2::Blake2s>(&index_token, &external_id, &leaf_index).unwrap();
+    verify_leaf_commitment(&blind_token, &external_id, 3, tree.context(), &leaf).unwrap();
+
+    // but not for another user, another value or another kind of token
+    let other_id = LiabilityId::from_str("x");
+    let result = verify_leaf_index::<blake2::Blake2s>(&index_token, &other_id, &leaf_index);
+    assert!(matches!(
+        result,
+        Err(DapolError::UnexpectedLeafIndex(16, _))
+    ));
+    let result = verify_leaf_commitment(&blind_token, &external_id, 4, tree.context(), &leaf);
+    assert!(matches!(result, Err(DapolError::CommitmentMismatch)));
+    let result = verify_leaf_commitment(&index_token, &external_id, 3, tree.context(), &leaf);
+    assert!(matches!(result, Err(DapolError::CommitmentMismatch)));
+
+    // nor for a leaf with the right commitment but a hash from another context
+    let result = verify_leaf_commitment(&blind_token, &external_id, 3, b"other", &leaf);
+    assert!(matches!(result, Err(DapolError::LeafHashMismatch)));
+    let wrong_leaf = DapolProofNode::<blake2::Blake2s>::new(leaf.get_com(), vec![0u8; 32]);
+    let result = verify_leaf_commitment(&blind_token, &external_id, 3, tree.context(), &wrong_leaf);
+    assert!(matches!(result, Err(DapolError::LeafHashMismatch)));
+
+    // tokens for all liabilities should be exported together with their IDs and leaves
+    let tokens = tree.generate_auditor_tokens(AuditorTokenKind::Index);
+    assert_eq!(4, tokens.len());
+    assert_eq!(Some(&(leaf_index, index_token)), tokens.get(&internal_id));
+}
+
+// SAMPLE OPENINGS
+// ================================================================================================
+
+#[test]
+fn generate_sample_opening() {
+    let tree = Dapol::<blake2::Blake2s, RangeProofSplitting>::new(
+        build_test_liabilities(),
+        build_test_options_with_secret(4, 2, &[7u8; 32]),
+    )
+    .unwrap();
+    let root = tree.root();
+    let commitment = tree.leaf_list_commitment();
+    let seed = derive_sample_seed(&root, &commitment, b"2026-10-17");
+    assert_ne!(seed, derive_sample_seed(&root, &commitment, b"2026-10-18"));
+    assert_ne!(seed, derive_sample_seed(&root, &[0u8; 32], b"2026-10-17"));
+
+    // ranks should be reproducible from the seed and select distinct occupied leaves
+    let ranks = select_sample_ranks::<blake2::Blake2s>(&seed, 4, 3);
+    assert_eq!(ranks, select_sample_ranks::<blake2::Blake2s>(&seed, 4, 3));
+    assert_eq!(3, ranks.len());
+    assert!(ranks.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(ranks.iter().all(|rank| *rank < 4));
+    assert_eq!(
+        vec![0, 1, 2, 3],
+        select_sample_ranks::<blake2::Blake2s>(&seed, 4, 4)
+    );
+
+    // each opened leaf should be the occupied leaf at its sampled rank
+    let opening = tree.generate_sample_opening(b"2026-10-17", 3).unwrap();
+    assert_eq!(b"2026-10-17", opening.nonce());
+    assert_eq!(4, opening.num_leaves());
+    let mut occupied: Vec<TreeIndex> = tree.id_to_idx_map.values().cloned().collect();
+    occupied.sort();
+    for (leaf, rank) in opening.leaves().iter().zip(ranks.iter()) {
+        assert_eq!(*rank, leaf.rank());
+        assert_eq!(occupied[*rank as usize], *leaf.index());
+        let node = tree
+            .smt
+            .get_leaf_by_index(leaf.index())
+            .unwrap()
+            .get_value();
+        assert_eq!(node.get_value(), leaf.value());
+        assert_eq!(node.get_blinding(), *leaf.blinding());
+    }
+    opening.verify(&root, &commitment).unwrap();
+
+    // the opening should survive serialization
+    let bytes = opening.serialize();
+    let decoded =
+        SampleOpening::<blake2::Blake2s, RangeProofSplitting>::deserialize(&bytes).unwrap();
+    assert_eq!(opening.leaves(), decoded.leaves());
+    assert_eq!(bytes, decoded.serialize());
+    decoded.verify(&root, &commitment).unwrap();
+    let result = SampleOpening::<blake2::Blake2s, RangeProofSplitting>::deserialize(
+        &bytes[..bytes.len() - 1],
+    );
+    assert!(matches!(result, Err(DapolError::MalformedSampleOpening(_))));
+
+    // a sample size which does not match the openings should be rejected when decoding
+    let sample_size_pos = 8 + 4 + 4 + 1;
+    let mut forged = bytes.clone();
+    forged[sample_size_pos..sample_size_pos + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    let result = SampleOpening::<blake2::Blake2s, RangeProofSplitting>::deserialize(&forged);
+    assert!(matches!(result, Err(DapolError::MalformedSampleOpening(_))));
+
+    // an opening for another nonce should not match the ranks sampled for this nonce
+    let mut bytes = tree
+        .generate_sample_opening(b"2026-10-17", 1)
+        .unwrap()
+        .serialize();
+    let nonce_pos = sample_size_pos + 4 + 4;
+    bytes[nonce_pos] ^= 1;
+    let forged =
+        SampleOpening::<blake2::Blake2s, RangeProofSplitting>::deserialize(&bytes).unwrap();
+    assert!(matches!(
+        forged.verify(&root, &commitment),
+        Err(DapolError::SampleMismatch)
+    ));
+
+    // the opening should not verify against the root of another tree, or against another
+    // commitment to the list of leaves
+    let other = Dapol::<blake2::Blake2s, RangeProofSplitting>::new(
+        build_test_liabilities(),
+        build_test_options(4, 2),
+    )
+    .unwrap();
+    assert!(opening.verify(&other.root(), &commitment).is_err());
+    let mut other_commitment = commitment.clone();
+    other_commitment[0] ^= 1;
+    assert!(matches!(
+        opening.verify(&root, &other_commitment),
+        Err(DapolError::SampleMismatch)
+    ));
+
+    // a sample cannot be empty or larger than the number of liabilities
+    assert!(tree.generate_sample_opening(b"2026-10-17", 0).is_none());
+    assert!(tree.generate_sample_opening(b"2026-10-17", 5).is_none());
+    let opening = tree.generate_sample_opening(b"2026-10-17", 4).unwrap();
+    assert_eq!(occupied.len(), opening.leaves().len());
+    opening.verify(&root, &commitment).unwrap();
+
+    // vacated leaves should not be sampled, and lists of any length should be provable
+    let mut tree = tree;
+    let removed = tree.remove_liability(&LiabilityId::from_str("a")).unwrap();
+    let root = tree.root();
+    let commitment = tree.leaf_list_commitment();
+    let opening = tree.generate_sample_opening(b"2026-10-17", 3).unwrap();
+    assert!(opening.leaves().iter().all(|leaf| *leaf.index() != removed));
+    opening.verify(&root, &commitment).unwrap();
+    assert!(tree.generate_sample_opening(b"2026-10-17", 4).is_none());
+}
+
 // HELPER FUNCTIONS
 // ================================================================================================
 
 fn build_test_options(tree_height: usize, aggregation_factor: usize) -> DapolOptions {
-    DapolOptions {
-        audit_seed: "test".as_bytes().to_vec(),
-        tree_height,
-        aggregation_factor,
-        secret: get_secret(),
+    DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(tree_height)
+        .aggregation_factor(aggregation_factor)
+        .build()
+        .unwrap()
+}
+
+fn build_test_options_with_secret(
+    tree_height: usize,
+    aggregation_factor: usize,
+    secret: &[u8],
+) -> DapolOptions {
+    DapolOptions::builder()
+        .audit_seed("test".as_bytes())
+        .tree_height(tree_height)
+        .aggregation_factor(aggregation_factor)
+        .secret(Secret::from_bytes(secret).unwrap())
+        .build()
+        .unwrap()
+}
+
+fn build_liability(internal_id: &str, external_id: &str, value: u64) -> Liability {
+    Liability {
+        internal_id: LiabilityId::from_str(internal_id),
+        external_id: LiabilityId::from_str(external_id),
+        value,
     }
 }
 
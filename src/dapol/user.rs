@@ -0,0 +1,330 @@
+use super::{
+    blinding_from_seed, check_leaf_index, derive_audit_id, derive_blind_seed, derive_index_seed,
+    snapshot::{serialize_index, ByteReader},
+    Dapol, LiabilityId, MAX_TREE_HEIGHT,
+};
+use crate::{
+    errors::DapolError, range::is_valid_bit_size, DapolProof, DapolProofNode, RangeProvable,
+    RangeVerifiable,
+};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::scalar::Scalar;
+use digest::Digest;
+use smtree::{index::TreeIndex, traits::Serializable};
+use std::convert::TryFrom;
+
+// CONSTANTS
+// ================================================================================================
+
+const PACKAGE_MAGIC: &[u8; 8] = b"DAPOLUIP";
+const PACKAGE_VERSION: u32 = 1;
+
+// USER-SIDE VERIFICATION
+// ================================================================================================
+
+/// Verifies that the liability of a user is included in the tree with the specified root, using
+/// only the information known to the user: their `audit_id`, `external_id` and the value of the
+/// liability, as well as the context string of the tree.
+///
+/// The commitment of the leaf is recomputed from the blinding factor derived from the audit ID
+/// and the external ID in the same way as when the tree is built. The position of the leaf is
+/// recomputed the same way too; as the position depends on collisions with other liabilities,
+/// the proof is accepted if the leaf is at any of the positions the liability could have been
+/// mapped to.
+///
+/// Returns an error if:
+/// * The proof cannot be decoded or is not a proof for a single leaf.
+/// * The leaf of the proof is not at a position the liability could have been mapped to.
+/// * The proof does not verify against the root for the recomputed leaf.
+pub fn verify_user_proof<D, R>(
+    audit_id: &[u8],
+    external_id: &LiabilityId,
+    value: u64,
+    context: &[u8],
+    proof: &[u8],
+    root: &DapolProofNode<D>,
+) -> Result<(), DapolError>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    let proof = DapolProof::<D, R>::deserialize(proof)
+        .map_err(|err| DapolError::ProofDecodingFailed(format!("{:?}", err)))?;
+    verify_inclusion(audit_id, external_id, value, context, &proof, root)
+}
+
+// USER INCLUSION PACKAGE
+// ================================================================================================
+
+/// Everything a user needs to verify that their liability is included in a DAPOL tree: their
+/// audit ID, the leaf their liability is located at, the value of the liability, the proof of
+/// inclusion, the root of the tree and the parameters of the tree.
+///
+/// The package does not contain the external ID of the user, which the user has to provide
+/// during verification. The root contained in the package must be checked against the root
+/// published by the audited system, as otherwise the package only proves inclusion into a tree
+/// of the producer's choice.
+#[derive(Debug)]
+pub struct UserInclusionPackage<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    audit_id: Vec<u8>,
+    leaf_index: TreeIndex,
+    value: u64,
+    proof: DapolProof<D, R>,
+    root: DapolProofNode<D>,
+    tree_height: usize,
+    bit_size: usize,
+    context: Vec<u8>,
+}
+
+impl<D, R> UserInclusionPackage<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    // PUBLIC ACCESSORS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns the audit ID of the user, computed as hash(audit_seed || internal_id).
+    pub fn audit_id(&self) -> &[u8] {
+        &self.audit_id
+    }
+
+    /// Returns the index of the leaf the liability of the user is located at.
+    pub fn leaf_index(&self) -> &TreeIndex {
+        &self.leaf_index
+    }
+
+    /// Returns the value of the liability of the user.
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    /// Returns the proof of inclusion of the liability of the user.
+    pub fn proof(&self) -> &DapolProof<D, R> {
+        &self.proof
+    }
+
+    /// Returns the root of the tree the proof was generated for.
+    pub fn root(&self) -> &DapolProofNode<D> {
+        &self.root
+    }
+
+    /// Returns the height of the tree.
+    pub fn tree_height(&self) -> usize {
+        self.tree_height
+    }
+
+    /// Returns the bit width of range proofs of the tree.
+    pub fn bit_size(&self) -> usize {
+        self.bit_size
+    }
+
+    /// Returns the context string bound into the hashes of all leaves of the tree.
+    pub fn context(&self) -> &[u8] {
+        &self.context
+    }
+
+    // VERIFICATION
+    // --------------------------------------------------------------------------------------------
+
+    /// Verifies that the liability of the user with the specified external ID is included in the
+    /// tree with the specified published root; see [verify_user_proof()] for details.
+    ///
+    /// Returns an error if:
+    /// * The root of the package is not the published root.
+    /// * The proof is not a proof for the leaf or for the parameters stated in the package.
+    /// * The proof does not verify for the liability of the user.
+    pub fn verify(
+        &self,
+        external_id: &LiabilityId,
+        published_root: &DapolProofNode<D>,
+    ) -> Result<(), DapolError> {
+        if self.root != *published_root {
+            return Err(DapolError::RootMismatch);
+        }
+        if self.proof.get_merkle_path().get_indexes() != [self.leaf_index] {
+            return Err(malformed("proof is not for the leaf of the package"));
+        }
+        if self.leaf_index.get_height() != self.tree_height {
+            return Err(malformed("leaf is not at the height of the tree"));
+        }
+        if self.proof.get_range_proofs().get_bit_size() != self.bit_size {
+            return Err(malformed(
+                "range proofs are not for the bit width of the tree",
+            ));
+        }
+        verify_inclusion(
+            &self.audit_id,
+            external_id,
+            self.value,
+            &self.context,
+            &self.proof,
+            &self.root,
+        )
+    }
+
+    // SERIALIZATION / DESERIALIZATION
+    // --------------------------------------------------------------------------------------------
+
+    /// Serializes the package with all integers encoded in big-endian order as:
+    /// * magic bytes "DAPOLUIP" and a 4-byte format version;
+    /// * 4-byte digest size, 1-byte tree height and 1-byte bit width of range proofs;
+    /// * length-prefixed context string and length-prefixed audit ID;
+    /// * leaf index encoded as its height followed by the first 8 bytes of its path;
+    /// * 8-byte value of the liability;
+    /// * the root encoded as its commitment followed by its hash;
+    /// * 8-byte length of the proof followed by the serialized proof.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(PACKAGE_MAGIC);
+        bytes.extend_from_slice(&PACKAGE_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&(D::output_size() as u32).to_be_bytes());
+        bytes.push(self.tree_height as u8);
+        bytes.push(self.bit_size as u8);
+        bytes.extend_from_slice(&(self.context.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.context);
+        bytes.extend_from_slice(&(self.audit_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.audit_id);
+        bytes.extend_from_slice(&serialize_index(&self.leaf_index));
+        bytes.extend_from_slice(&self.value.to_be_bytes());
+        bytes.append(&mut self.root.serialize());
+        let mut proof = self.proof.serialize();
+        bytes.extend_from_slice(&(proof.len() as u64).to_be_bytes());
+        bytes.append(&mut proof);
+        bytes
+    }
+
+    /// Deserializes a package serialized via [UserInclusionPackage::serialize()].
+    ///
+    /// Returns an error if the package was serialized by an unsupported version of the format or
+    /// for a digest with a different output size, or if the bytes do not describe a valid
+    /// package.
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DapolError> {
+        let mut reader = ByteReader::new(bytes, malformed);
+        if reader.read_bytes(PACKAGE_MAGIC.len())? != PACKAGE_MAGIC {
+            return Err(malformed("package does not start with magic bytes"));
+        }
+        let version = reader.read_u32()?;
+        if version != PACKAGE_VERSION {
+            return Err(malformed(&format!("unsupported version {}", version)));
+        }
+        let digest_size = reader.read_u32()? as usize;
+        if digest_size != D::output_size() {
+            return Err(DapolError::InvalidDigestSize(D::output_size(), digest_size));
+        }
+        let tree_height = reader.read_u8()? as usize;
+        if tree_height == 0 || tree_height > MAX_TREE_HEIGHT {
+            return Err(malformed("invalid tree height"));
+        }
+        let bit_size = reader.read_u8()? as usize;
+        if !is_valid_bit_size(bit_size) {
+            return Err(DapolError::InvalidRangeProofBits(bit_size));
+        }
+        let context_len = reader.read_u32()? as usize;
+        let context = reader.read_bytes(context_len)?.to_vec();
+        let audit_id_len = reader.read_u32()? as usize;
+        let audit_id = reader.read_bytes(audit_id_len)?.to_vec();
+        let leaf_index = reader.read_index()?;
+        let value = reader.read_u64()?;
+        let root = DapolProofNode::<D>::deserialize(reader.read_bytes(32 + D::output_size())?)
+            .map_err(|err| malformed(&format!("invalid root: {:?}", err)))?;
+        let proof_len = reader.read_u64()?;
+        let proof_len = usize::try_from(proof_len).map_err(|_| malformed("proof is too long"))?;
+        let proof = DapolProof::<D, R>::deserialize(reader.read_bytes(proof_len)?)
+            .map_err(|err| DapolError::ProofDecodingFailed(format!("{:?}", err)))?;
+        if !reader.is_empty() {
+            return Err(malformed("unexpected bytes at the end of package"));
+        }
+
+        Ok(UserInclusionPackage {
+            audit_id,
+            leaf_index,
+            value,
+            proof,
+            root,
+            tree_height,
+            bit_size,
+            context,
+        })
+    }
+}
+
+// PACKAGE GENERATION
+// ================================================================================================
+
+impl<D, R> Dapol<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeProvable + RangeVerifiable + Serializable,
+{
+    /// Returns the inclusion package for the liability identified by the specified `internal_id`,
+    /// which can be handed out to the owner of the liability for self-verification. If a
+    /// liability for the specified ID does not exist in the tree, None is returned.
+    pub fn generate_inclusion_package(
+        &self,
+        internal_id: &LiabilityId,
+    ) -> Option<UserInclusionPackage<D, R>> {
+        let leaf_index = *self.id_to_idx_map.get(internal_id)?;
+        let value = self
+            .smt
+            .get_leaf_by_index(&leaf_index)?
+            .get_value()
+            .get_value();
+        let proof = self.generate_proof(&leaf_index)?;
+
+        let audit_id = derive_audit_id::<D>(&self.audit_seed, internal_id);
+
+        Some(UserInclusionPackage {
+            audit_id,
+            leaf_index,
+            value,
+            proof,
+            root: self.root(),
+            tree_height: self.smt.get_height(),
+            bit_size: self.bit_size,
+            context: self.context.clone(),
+        })
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Verifies that the specified proof is a proof of inclusion of the leaf of the user with the
+/// specified credentials into the tree with the specified root.
+fn verify_inclusion<D, R>(
+    audit_id: &[u8],
+    external_id: &LiabilityId,
+    value: u64,
+    context: &[u8],
+    proof: &DapolProof<D, R>,
+    root: &DapolProofNode<D>,
+) -> Result<(), DapolError>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    // make sure the proof is for a single leaf located at one of the positions of the user
+    let indexes = proof.get_merkle_path().get_indexes();
+    if indexes.len() != 1 {
+        return Err(DapolError::NotSingleLeafProof(indexes.len()));
+    }
+    check_leaf_index::<D>(&derive_index_seed::<D>(audit_id, external_id), &indexes[0])?;
+
+    // recompute the leaf from the value and the blinding factor of the user
+    let blinding = blinding_from_seed(&derive_blind_seed::<D>(audit_id, external_id));
+    let com = PedersenGens::default().commit(Scalar::from(value), blinding);
+    let leaf = DapolProofNode::new_leaf(com, context);
+    if !proof.verify(root, &leaf) {
+        return Err(DapolError::InvalidProof);
+    }
+    Ok(())
+}
+
+fn malformed(reason: &str) -> DapolError {
+    DapolError::MalformedInclusionPackage(reason.to_string())
+}
@@ -4,14 +4,80 @@ use thiserror::Error;
 /// Represents a generic error type
 #[derive(Debug, Display, Error)]
 pub enum DapolError {
+    /// Expected master secret to be {0} bytes, but was {1}
+    InvalidMasterSecretLength(usize, usize),
+    /// Audit seed must not be empty
+    EmptyAuditSeed,
     /// DAPOL tree height must not exceed {0}, but was {1}
     TreeHeightTooBig(usize, usize),
+    /// Aggregation factor must be between 1 and tree height {0}, but was {1}
+    InvalidAggregationFactor(usize, usize),
     /// For a liability set of {0} accounts, tree height must be at least {1}, but was {2}
     SparsityTooSmall(usize, usize, usize),
+    /// Range proof bit width must be one of 8, 16, 32 or 64, but was {0}
+    InvalidRangeProofBits(usize),
+    /// Chunk size must be greater than zero
+    InvalidChunkSize,
     /// Expected digest size to be {0}, but was {1}
     InvalidDigestSize(usize, usize),
+    /// Digest size must be at least {0} bytes, but was {1}
+    DigestTooShort(usize, usize),
     /// Liability set contains a duplicated internal ID {0:?}
     DuplicatedInternalId(Vec<u8>),
+    /// Liability with internal ID {0:?} does not exist
+    UnknownInternalId(Vec<u8>),
+    /// Liability with internal ID {0:?} is not mapped to a leaf of the tree
+    UnmappedInternalId(Vec<u8>),
+    /// Total of all liabilities {0} does not fit into the {1}-bit range covered by range proofs
+    TotalOutOfRange(u128, usize),
+    /// Value {1} of liability {0:?} does not fit into the {2}-bit range covered by range proofs
+    LiabilityOutOfRange(Vec<u8>, u64, usize),
+    /// Reserves {0} are smaller than the total of all liabilities {1}
+    InsufficientReserves(u64, u64),
+    /// Sum of liabilities in the subtree rooted at level {0} and position {1} overflows
+    SubtreeSumOverflow(usize, u64),
+    /// Expected commitments to {0} assets, but got {1}
+    InvalidAssetCount(usize, usize),
+    /// Failed to read liability #{0}: {1}
+    LiabilityIngestFailed(usize, String),
+    /// Failed to read or write a temporary file: {0}
+    TempFileIoFailed(String),
+    /// Failed to build a thread pool: {0}
+    ThreadPoolBuildFailed(String),
+    /// Failed to read or write a snapshot: {0}
+    SnapshotIoFailed(String),
+    /// Expected snapshot version {0}, but was {1}
+    UnsupportedSnapshotVersion(u32, u32),
+    /// Snapshot checksum does not match its contents
+    SnapshotChecksumMismatch,
+    /// Snapshot is malformed: {0}
+    MalformedSnapshot(String),
     /// Failed to map audit ID {0:?} to a tree index within {1} tries
     FailedToMapIndex(Vec<u8>, usize),
+    /// Failed to decode a proof: {0}
+    ProofDecodingFailed(String),
+    /// Expected a proof for a single leaf, but the proof was for {0} leaves
+    NotSingleLeafProof(usize),
+    /// Leaf at height {0} and position {1} cannot hold the liability of the user
+    UnexpectedLeafIndex(usize, u64),
+    /// Proof does not verify against the root
+    InvalidProof,
+    /// Root of the inclusion package does not match the published root
+    RootMismatch,
+    /// Inclusion package is malformed: {0}
+    MalformedInclusionPackage(String),
+    /// Failed to export proofs: {0}
+    ProofExportFailed(String),
+    /// Commitment of the leaf does not match the disclosed value
+    CommitmentMismatch,
+    /// Hash of the leaf does not match the hash of its commitment in the tree context
+    LeafHashMismatch,
+    /// Sample opening is malformed: {0}
+    MalformedSampleOpening(String),
+    /// Sample opening does not open the leaves sampled for the published root and leaf list
+    SampleMismatch,
+    /// Proof envelope is malformed: {0}
+    MalformedProofEnvelope(String),
+    /// Proof envelope holds a proof for digest {0} and range proof strategy {1}
+    ProofEnvelopeMismatch(String, String),
 }
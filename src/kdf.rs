@@ -0,0 +1,140 @@
+use crate::errors::DapolError;
+use rand::prelude::*;
+use smtree::pad_secret::{Secret, SECRET_LENGTH};
+use zeroize::Zeroize;
+
+// CONSTANTS
+// ================================================================================================
+
+/// The length of a master secret, in bytes.
+pub const MASTER_SECRET_LENGTH: usize = 32;
+
+/// The length of a derived key or subkey, in bytes.
+pub const SUBKEY_LENGTH: usize = 32;
+
+// Context strings for all derived keys. Each string must be hardcoded and globally unique as
+// required by BLAKE3 key derivation. Every derived key depends on these strings, so they are
+// protocol constants: a change to the derivation scheme must come with a new version in all of
+// them, as it changes all audit IDs, leaf positions and padding nodes of a given master secret.
+const AUDIT_SEED_CONTEXT: &str = "DAPOL+ v1 audit seed";
+const PADDING_SECRET_CONTEXT: &str = "DAPOL+ v1 padding secret";
+const INDEX_SEED_CONTEXT: &str = "DAPOL+ v1 index seed";
+const BLIND_SEED_CONTEXT: &str = "DAPOL+ v1 blind seed";
+
+// MASTER SECRET
+// ================================================================================================
+
+/// A single secret from which all other secrets of an audit are derived. This way, operators
+/// need to back up and rotate only one secret per audit.
+///
+/// Derived keys are computed using BLAKE3 in key derivation mode, and each purpose is separated
+/// by its own context string:
+/// * audit seed - used to compute audit IDs as hash(audit_seed || internal_id); the index and
+///   blinding seeds of each liability are in turn derived from its audit ID as subkeys (see
+///   [derive_subkey]).
+/// * padding secret - used to compute blinding factors of padding nodes.
+///
+/// The secret is overwritten with zeros when it goes out of scope.
+pub struct MasterSecret([u8; MASTER_SECRET_LENGTH]);
+
+impl MasterSecret {
+    // CONSTRUCTORS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a master secret constructed from the specified bytes.
+    ///
+    /// Returns an error if the number of bytes is not equal to 32.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DapolError> {
+        if bytes.len() != MASTER_SECRET_LENGTH {
+            return Err(DapolError::InvalidMasterSecretLength(
+                MASTER_SECRET_LENGTH,
+                bytes.len(),
+            ));
+        }
+        let mut secret = [0u8; MASTER_SECRET_LENGTH];
+        secret.copy_from_slice(bytes);
+        Ok(MasterSecret(secret))
+    }
+
+    /// Returns a new randomly generated master secret.
+    pub fn random() -> Self {
+        MasterSecret(thread_rng().gen())
+    }
+
+    // PUBLIC ACCESSORS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns byte representation of this secret.
+    pub fn as_bytes(&self) -> &[u8; MASTER_SECRET_LENGTH] {
+        &self.0
+    }
+
+    // KEY DERIVATION
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns the seed from which audit IDs of all liabilities are derived.
+    pub fn derive_audit_seed(&self) -> Vec<u8> {
+        self.derive(AUDIT_SEED_CONTEXT).to_vec()
+    }
+
+    /// Returns the secret used to generate padding nodes of the tree.
+    pub fn derive_padding_secret(&self) -> Secret {
+        let mut key = self.derive(PADDING_SECRET_CONTEXT);
+        let secret =
+            Secret::from_bytes(&key[..SECRET_LENGTH]).expect("failed to build secret from bytes");
+        key.zeroize();
+        secret
+    }
+
+    // HELPER METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Computes BLAKE3 derive_key(context, master_secret).
+    fn derive(&self, context: &str) -> [u8; SUBKEY_LENGTH] {
+        derive_key(context, &self.0)
+    }
+}
+
+impl Drop for MasterSecret {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// SUBKEYS
+// ================================================================================================
+
+/// Purposes of the subkeys derived from the audit ID of a liability when its leaf is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubkeyPurpose {
+    /// Subkey from which the position of the leaf in the tree is derived.
+    IndexSeed,
+    /// Subkey from which the blinding factor of the leaf commitment is derived.
+    BlindSeed,
+}
+
+impl SubkeyPurpose {
+    fn context(&self) -> &'static str {
+        match self {
+            SubkeyPurpose::IndexSeed => INDEX_SEED_CONTEXT,
+            SubkeyPurpose::BlindSeed => BLIND_SEED_CONTEXT,
+        }
+    }
+}
+
+/// Returns the subkey for the specified purpose derived from the specified key material, usually
+/// the audit ID of a liability. Subkeys for different purposes are independent, so that learning
+/// one of them reveals nothing about the others or about the key material.
+pub fn derive_subkey(purpose: SubkeyPurpose, key_material: &[u8]) -> [u8; SUBKEY_LENGTH] {
+    derive_key(purpose.context(), key_material)
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Computes BLAKE3 derive_key(context, key_material).
+fn derive_key(context: &str, key_material: &[u8]) -> [u8; SUBKEY_LENGTH] {
+    let mut key = [0u8; SUBKEY_LENGTH];
+    blake3::derive_key(context, key_material, &mut key);
+    key
+}
@@ -1,13 +1,28 @@
 mod dapol;
-pub use dapol::{Dapol, DapolNode};
+pub use dapol::{
+    derive_sample_seed, verify_leaf_commitment, verify_leaf_index, verify_user_proof, ArchiveSink,
+    AuditorTokenKind, Dapol, DapolNode, DapolOptions, DapolOptionsBuilder, DirectorySink,
+    LeafOpening, Liability, LiabilityId, MultiAssetDapol, MultiAssetLiability, MultiAssetNode,
+    ProofSink, SampleOpening, UserInclusionPackage,
+};
 
 mod proof;
-pub use proof::{DapolProof, DapolProofNode};
+pub use proof::{
+    AnyDapolProof, DapolProof, DapolProofNode, DigestAlgorithm, IdentifiedDigest,
+    IdentifiedRangeProof, MultiAssetProof, MultiAssetProofNode, ProofEnvelopeHeader,
+    RangeProofStrategy, SolvencyProof, TotalProof,
+};
 
 mod range;
-pub use range::{RangeProofPadding, RangeProofSplitting, RangeProvable, RangeVerifiable};
+pub use range::{
+    RangeProofCache, RangeProofContext, RangeProofPadding, RangeProofSplitting, RangeProvable,
+    RangeStatement, RangeVerifiable,
+};
+
+mod hashing;
 
 pub mod errors;
+pub mod kdf;
 pub mod utils;
 
 #[cfg(test)]
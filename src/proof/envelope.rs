@@ -0,0 +1,382 @@
+use super::DapolProof;
+use crate::{
+    dapol::MAX_TREE_HEIGHT, errors::DapolError, hashing::HASH_LAYOUT_VERSION,
+    range::is_valid_bit_size, DapolProofNode, RangeProofPadding, RangeProofSplitting,
+    RangeVerifiable,
+};
+use digest::Digest;
+use smtree::traits::Serializable;
+
+// CONSTANTS
+// ================================================================================================
+
+const ENVELOPE_MAGIC: &[u8; 8] = b"DAPOLPRF";
+const ENVELOPE_VERSION: u8 = 1;
+const HEADER_BYTE_NUM: usize = ENVELOPE_MAGIC.len() + 6;
+
+// PROOF PARAMETERS
+// ================================================================================================
+
+/// Digest algorithms which can be identified in a proof envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestAlgorithm {
+    Blake2b,
+    Blake2s,
+    Blake3,
+    Sha256,
+    Sha512,
+    Sha3_256,
+    Sha3_512,
+}
+
+impl DigestAlgorithm {
+    fn to_byte(self) -> u8 {
+        match self {
+            DigestAlgorithm::Blake2b => 1,
+            DigestAlgorithm::Blake2s => 2,
+            DigestAlgorithm::Blake3 => 3,
+            DigestAlgorithm::Sha256 => 4,
+            DigestAlgorithm::Sha512 => 5,
+            DigestAlgorithm::Sha3_256 => 6,
+            DigestAlgorithm::Sha3_512 => 7,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(DigestAlgorithm::Blake2b),
+            2 => Some(DigestAlgorithm::Blake2s),
+            3 => Some(DigestAlgorithm::Blake3),
+            4 => Some(DigestAlgorithm::Sha256),
+            5 => Some(DigestAlgorithm::Sha512),
+            6 => Some(DigestAlgorithm::Sha3_256),
+            7 => Some(DigestAlgorithm::Sha3_512),
+            _ => None,
+        }
+    }
+}
+
+/// Strategies of generating range proofs which can be identified in a proof envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeProofStrategy {
+    Padding,
+    Splitting,
+}
+
+impl RangeProofStrategy {
+    fn to_byte(self) -> u8 {
+        match self {
+            RangeProofStrategy::Padding => 1,
+            RangeProofStrategy::Splitting => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(RangeProofStrategy::Padding),
+            2 => Some(RangeProofStrategy::Splitting),
+            _ => None,
+        }
+    }
+}
+
+/// A digest which can be identified in a proof envelope.
+pub trait IdentifiedDigest: Digest + Default + Clone + std::fmt::Debug {
+    const ALGORITHM: DigestAlgorithm;
+}
+
+impl IdentifiedDigest for blake2::Blake2b {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Blake2b;
+}
+
+impl IdentifiedDigest for blake2::Blake2s {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Blake2s;
+}
+
+impl IdentifiedDigest for blake3::Hasher {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Blake3;
+}
+
+impl IdentifiedDigest for sha2::Sha256 {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Sha256;
+}
+
+impl IdentifiedDigest for sha2::Sha512 {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Sha512;
+}
+
+impl IdentifiedDigest for sha3::Sha3_256 {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Sha3_256;
+}
+
+impl IdentifiedDigest for sha3::Sha3_512 {
+    const ALGORITHM: DigestAlgorithm = DigestAlgorithm::Sha3_512;
+}
+
+/// A range proof type which can be identified in a proof envelope.
+pub trait IdentifiedRangeProof: Clone + RangeVerifiable + Serializable {
+    const STRATEGY: RangeProofStrategy;
+}
+
+impl IdentifiedRangeProof for RangeProofPadding {
+    const STRATEGY: RangeProofStrategy = RangeProofStrategy::Padding;
+}
+
+impl IdentifiedRangeProof for RangeProofSplitting {
+    const STRATEGY: RangeProofStrategy = RangeProofStrategy::Splitting;
+}
+
+// PROOF ENVELOPE
+// ================================================================================================
+
+/// The header of a proof envelope, describing how the enclosed proof is to be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProofEnvelopeHeader {
+    version: u8,
+    hash_layout_version: u8,
+    digest: DigestAlgorithm,
+    strategy: RangeProofStrategy,
+    bit_size: usize,
+    tree_height: usize,
+}
+
+impl ProofEnvelopeHeader {
+    /// Returns the version of the envelope format.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    /// Returns the version of the layout of node hashes in the tree of the proof; only proofs for
+    /// trees hashed with the layout used by this crate can be decoded.
+    pub fn hash_layout_version(&self) -> u8 {
+        self.hash_layout_version
+    }
+
+    /// Returns the digest algorithm the tree of the proof is hashed with.
+    pub fn digest(&self) -> DigestAlgorithm {
+        self.digest
+    }
+
+    /// Returns the strategy the range proofs of the proof were generated with.
+    pub fn strategy(&self) -> RangeProofStrategy {
+        self.strategy
+    }
+
+    /// Returns the bit size of the range proofs of the proof.
+    pub fn bit_size(&self) -> usize {
+        self.bit_size
+    }
+
+    /// Returns the height of the tree of the proof.
+    pub fn tree_height(&self) -> usize {
+        self.tree_height
+    }
+
+    /// magic || version || hash_layout_version || digest || strategy || bit_size || tree_height
+    fn serialize(&self) -> Vec<u8> {
+        let mut bytes = ENVELOPE_MAGIC.to_vec();
+        bytes.push(self.version);
+        bytes.push(self.hash_layout_version);
+        bytes.push(self.digest.to_byte());
+        bytes.push(self.strategy.to_byte());
+        bytes.push(self.bit_size as u8);
+        bytes.push(self.tree_height as u8);
+        bytes
+    }
+
+    /// magic || version || hash_layout_version || digest || strategy || bit_size || tree_height
+    fn deserialize(bytes: &[u8]) -> Result<Self, DapolError> {
+        if bytes.len() < HEADER_BYTE_NUM || &bytes[..ENVELOPE_MAGIC.len()] != ENVELOPE_MAGIC {
+            return Err(malformed("not a DAPOL proof envelope"));
+        }
+        let fields = &bytes[ENVELOPE_MAGIC.len()..HEADER_BYTE_NUM];
+        let version = fields[0];
+        if version != ENVELOPE_VERSION {
+            return Err(malformed(&format!("unsupported version {}", version)));
+        }
+        let hash_layout_version = fields[1];
+        if hash_layout_version != HASH_LAYOUT_VERSION {
+            return Err(malformed(&format!(
+                "unsupported hash layout version {}",
+                hash_layout_version
+            )));
+        }
+        let digest = DigestAlgorithm::from_byte(fields[2])
+            .ok_or_else(|| malformed(&format!("unknown digest algorithm {}", fields[2])))?;
+        let strategy = RangeProofStrategy::from_byte(fields[3])
+            .ok_or_else(|| malformed(&format!("unknown range proof strategy {}", fields[3])))?;
+        let bit_size = fields[4] as usize;
+        if !is_valid_bit_size(bit_size) {
+            return Err(malformed(&format!("unsupported bit size {}", bit_size)));
+        }
+        let tree_height = fields[5] as usize;
+        if tree_height == 0 || tree_height > MAX_TREE_HEIGHT {
+            return Err(malformed(&format!(
+                "unsupported tree height {}",
+                tree_height
+            )));
+        }
+        Ok(ProofEnvelopeHeader {
+            version,
+            hash_layout_version,
+            digest,
+            strategy,
+            bit_size,
+            tree_height,
+        })
+    }
+}
+
+impl<D, R> DapolProof<D, R>
+where
+    D: IdentifiedDigest,
+    R: IdentifiedRangeProof,
+{
+    /// Returns the header describing this proof in a proof envelope.
+    pub fn envelope_header(&self) -> ProofEnvelopeHeader {
+        ProofEnvelopeHeader {
+            version: ENVELOPE_VERSION,
+            hash_layout_version: HASH_LAYOUT_VERSION,
+            digest: D::ALGORITHM,
+            strategy: R::STRATEGY,
+            bit_size: self.range_proofs.get_bit_size(),
+            tree_height: self.tree_height(),
+        }
+    }
+
+    /// Returns the proof in a self-describing envelope: header || proof, where the header
+    /// identifies the hash layout version, the digest algorithm, the range proof strategy, the bit
+    /// size and the tree height (see [ProofEnvelopeHeader]), and the proof is in the format of
+    /// [DapolProof::serialize].
+    pub fn serialize_envelope(&self) -> Vec<u8> {
+        let mut bytes = self.envelope_header().serialize();
+        bytes.append(&mut self.serialize());
+        bytes
+    }
+
+    /// Decodes a proof from an envelope produced by [DapolProof::serialize_envelope].
+    ///
+    /// Returns an error if the envelope is malformed, or if it holds a proof for a tree hashed
+    /// with another layout of node hashes, or for another digest algorithm or range proof
+    /// strategy, or if the bit size and the tree height in the header do not match the enclosed
+    /// proof.
+    pub fn deserialize_envelope(bytes: &[u8]) -> Result<Self, DapolError> {
+        let header = ProofEnvelopeHeader::deserialize(bytes)?;
+        if header.digest != D::ALGORITHM || header.strategy != R::STRATEGY {
+            return Err(DapolError::ProofEnvelopeMismatch(
+                format!("{:?}", header.digest),
+                format!("{:?}", header.strategy),
+            ));
+        }
+        let proof = DapolProof::deserialize(&bytes[HEADER_BYTE_NUM..])
+            .map_err(|err| DapolError::ProofDecodingFailed(format!("{:?}", err)))?;
+        if proof.envelope_header() != header {
+            return Err(malformed("header does not match the proof"));
+        }
+        Ok(proof)
+    }
+
+    fn tree_height(&self) -> usize {
+        self.merkle
+            .get_indexes()
+            .first()
+            .map_or(0, |idx| idx.get_height())
+    }
+}
+
+// DYNAMIC DECODING
+// ================================================================================================
+
+macro_rules! any_dapol_proof {
+    ($($variant:ident($digest:ty, $range:ty)),* $(,)?) => {
+        /// A DAPOL proof for any of the supported combinations of digest algorithms and range
+        /// proof strategies, decoded from a proof envelope without knowing the combination ahead
+        /// of time.
+        #[derive(Debug)]
+        pub enum AnyDapolProof {
+            $($variant(DapolProof<$digest, $range>),)*
+        }
+
+        impl AnyDapolProof {
+            /// Decodes a proof from an envelope produced by [DapolProof::serialize_envelope],
+            /// using the digest algorithm and the range proof strategy named in the header of the
+            /// envelope.
+            pub fn deserialize_any(bytes: &[u8]) -> Result<Self, DapolError> {
+                let header = ProofEnvelopeHeader::deserialize(bytes)?;
+                $(
+                    if header.digest == <$digest>::ALGORITHM
+                        && header.strategy == <$range>::STRATEGY
+                    {
+                        return DapolProof::<$digest, $range>::deserialize_envelope(bytes)
+                            .map(AnyDapolProof::$variant);
+                    }
+                )*
+                Err(DapolError::ProofEnvelopeMismatch(
+                    format!("{:?}", header.digest),
+                    format!("{:?}", header.strategy),
+                ))
+            }
+
+            /// Returns the header describing the proof in a proof envelope.
+            pub fn envelope_header(&self) -> ProofEnvelopeHeader {
+                match self {
+                    $(AnyDapolProof::$variant(proof) => proof.envelope_header(),)*
+                }
+            }
+
+            /// Returns the proof in a self-describing envelope.
+            pub fn serialize_envelope(&self) -> Vec<u8> {
+                match self {
+                    $(AnyDapolProof::$variant(proof) => proof.serialize_envelope(),)*
+                }
+            }
+
+            /// Verifies the proof against the specified root and leaf, both serialized as
+            /// commitment || hash. Returns false if the nodes cannot be decoded for the digest
+            /// algorithm of the proof.
+            pub fn verify(&self, root: &[u8], leaf: &[u8]) -> bool {
+                match self {
+                    $(AnyDapolProof::$variant(proof) => verify_serialized(proof, root, leaf),)*
+                }
+            }
+        }
+    };
+}
+
+any_dapol_proof!(
+    Blake2bPadding(blake2::Blake2b, RangeProofPadding),
+    Blake2bSplitting(blake2::Blake2b, RangeProofSplitting),
+    Blake2sPadding(blake2::Blake2s, RangeProofPadding),
+    Blake2sSplitting(blake2::Blake2s, RangeProofSplitting),
+    Blake3Padding(blake3::Hasher, RangeProofPadding),
+    Blake3Splitting(blake3::Hasher, RangeProofSplitting),
+    Sha256Padding(sha2::Sha256, RangeProofPadding),
+    Sha256Splitting(sha2::Sha256, RangeProofSplitting),
+    Sha512Padding(sha2::Sha512, RangeProofPadding),
+    Sha512Splitting(sha2::Sha512, RangeProofSplitting),
+    Sha3_256Padding(sha3::Sha3_256, RangeProofPadding),
+    Sha3_256Splitting(sha3::Sha3_256, RangeProofSplitting),
+    Sha3_512Padding(sha3::Sha3_512, RangeProofPadding),
+    Sha3_512Splitting(sha3::Sha3_512, RangeProofSplitting),
+);
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+fn verify_serialized<D, R>(proof: &DapolProof<D, R>, root: &[u8], leaf: &[u8]) -> bool
+where
+    D: IdentifiedDigest,
+    R: IdentifiedRangeProof,
+{
+    match (
+        DapolProofNode::<D>::deserialize(root),
+        DapolProofNode::<D>::deserialize(leaf),
+    ) {
+        (Ok(root), Ok(leaf)) => proof.verify(&root, &leaf),
+        _ => false,
+    }
+}
+
+fn malformed(reason: &str) -> DapolError {
+    DapolError::MalformedProofEnvelope(reason.to_string())
+}
@@ -1,13 +1,49 @@
-use crate::{DapolNode, RangeVerifiable};
+use crate::{
+    dapol::MAX_TREE_HEIGHT,
+    range::locate_invalid_range_statements,
+    utils::{bytes_to_usize_with_error, remaining_bytes},
+    DapolNode, RangeProofContext, RangeVerifiable,
+};
+use curve25519_dalek_ng::ristretto::CompressedRistretto;
 use digest::Digest;
-use smtree::{error::DecodingError, proof::MerkleProof, traits::Serializable};
+use smtree::{
+    error::DecodingError,
+    index::TreeIndex,
+    proof::MerkleProof,
+    traits::{Mergeable, Paddable, ProofExtractable, Serializable},
+};
+use std::collections::{BTreeMap, BTreeSet};
 
 mod node;
 pub use node::DapolProofNode;
+use node::COM_BYTE_NUM;
+
+mod multi_asset;
+pub use multi_asset::{MultiAssetProof, MultiAssetProofNode};
+
+mod solvency;
+pub use solvency::SolvencyProof;
+
+mod total;
+pub use total::TotalProof;
+
+mod envelope;
+pub use envelope::{
+    AnyDapolProof, DigestAlgorithm, IdentifiedDigest, IdentifiedRangeProof, ProofEnvelopeHeader,
+    RangeProofStrategy,
+};
 
 #[cfg(test)]
 mod tests;
 
+// CONSTANTS
+// ================================================================================================
+
+// Sizes of the fields of a Merkle path serialized by smtree.
+const BATCH_NUM_BYTE_NUM: usize = 8;
+const HEIGHT_BYTE_NUM: usize = 2;
+const SIBLING_NUM_BYTE_NUM: usize = 8;
+
 // DAPOL PROOF
 // ================================================================================================
 
@@ -43,14 +79,54 @@ where
         if !self.merkle.verify(leaf, root) {
             return false;
         }
-        self.verify_proof()
+        self.verify_proof(root)
     }
 
     pub fn verify_batch(&self, root: &DapolProofNode<D>, leaves: &[DapolProofNode<D>]) -> bool {
-        if !self.merkle.verify_batch(leaves, root) {
+        if !verify_merkle_batch(&self.merkle, leaves, root) {
             return false;
         }
-        self.verify_proof()
+        self.verify_proof(root)
+    }
+
+    /// Verifies many proofs, each given together with the leaf it was generated for and the root
+    /// of its tree; returns the positions of the invalid items in increasing order, if any.
+    ///
+    /// Merkle paths are checked one by one, while the range proofs of all items are checked
+    /// together in a single batch. If the batch fails, it is split until the items with invalid
+    /// range proofs are located.
+    pub fn verify_many(
+        items: &[(&Self, &DapolProofNode<D>, &DapolProofNode<D>)],
+    ) -> Result<(), Vec<usize>> {
+        let mut invalid = Vec::new();
+        let mut statements = Vec::new();
+        let mut statement_items = Vec::new();
+        for (i, (proof, leaf, root)) in items.iter().enumerate() {
+            if !proof.merkle.verify(leaf, root) {
+                invalid.push(i);
+                continue;
+            }
+            let (commitments, positions, tree_height) = proof.get_range_inputs();
+            let context = RangeProofContext::new(root.get_hash(), tree_height, &positions);
+            match proof.range_proofs.get_statements(&commitments, &context) {
+                Some(item_statements) => {
+                    statements.push(item_statements);
+                    statement_items.push(i);
+                }
+                None => invalid.push(i),
+            }
+        }
+
+        invalid.extend(
+            locate_invalid_range_statements(&statements)
+                .into_iter()
+                .map(|group| statement_items[group]),
+        );
+        if invalid.is_empty() {
+            return Ok(());
+        }
+        invalid.sort_unstable();
+        Err(invalid)
     }
 
     pub fn get_range_proofs(&self) -> &R {
@@ -76,7 +152,7 @@ where
     pub fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
         let mut begin = 0;
         let range_proofs = R::deserialize_as_a_unit(bytes, &mut begin)?;
-        let merkle = MerkleProof::<DapolNode<D>>::deserialize_as_a_unit(bytes, &mut begin)?;
+        let merkle = deserialize_merkle_path(bytes, &mut begin, COM_BYTE_NUM + D::output_size())?;
         Ok(DapolProof {
             merkle,
             range_proofs,
@@ -86,11 +162,185 @@ where
     // HELPER METHODS
     // --------------------------------------------------------------------------------------------
 
-    fn verify_proof(&self) -> bool {
+    /// Verifies range proofs of all siblings in the Merkle proof; range proofs must have been
+    /// generated for the tree with the specified root and for the positions of the siblings.
+    fn verify_proof(&self, root: &DapolProofNode<D>) -> bool {
+        let (commitments, positions, tree_height) = self.get_range_inputs();
+        let context = RangeProofContext::new(root.get_hash(), tree_height, &positions);
+        self.range_proofs.verify(&commitments[..], &context)
+    }
+
+    /// Returns the commitments of the siblings in the Merkle proof, the positions of the
+    /// siblings and the height of the tree, which range proofs are verified against.
+    fn get_range_inputs(&self) -> (Vec<CompressedRistretto>, Vec<TreeIndex>, usize) {
         let mut commitments = Vec::new();
         for i in 0..self.merkle.get_siblings_num() {
             commitments.push((*self.merkle.get_sibling_at_idx(i)).get_com().compress());
         }
-        self.range_proofs.verify(&commitments[..])
+        let indexes = self.merkle.get_indexes();
+        let positions = get_sibling_indexes(indexes);
+        let tree_height = indexes.first().map_or(0, |idx| idx.get_height());
+        (commitments, positions, tree_height)
+    }
+}
+
+/// With the `serde` feature, proofs are serialized as a hex string of their binary format.
+#[cfg(feature = "serde")]
+impl<D, R> serde::Serialize for DapolProof<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::utils::hex::serialize(&DapolProof::serialize(self), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, D, R> serde::Deserialize<'de> for DapolProof<D, R>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    fn deserialize<De: serde::Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        crate::utils::hex::decode(deserializer, DapolProof::deserialize)
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Returns positions of the siblings in a Merkle proof for the specified leaves, in the same order
+/// as the siblings are included into the proof: level by level from the top of the tree, and from
+/// left to right within each level.
+///
+/// An empty list is returned if the leaves are located at different heights.
+pub(crate) fn get_sibling_indexes(leaves: &[TreeIndex]) -> Vec<TreeIndex> {
+    let height = match leaves.first() {
+        Some(leaf) => leaf.get_height(),
+        None => return Vec::new(),
+    };
+    if leaves.iter().any(|leaf| leaf.get_height() != height) {
+        return Vec::new();
+    }
+
+    // a sibling is a child of an ancestor of some leaf which is not itself an ancestor of a leaf
+    let mut siblings = Vec::new();
+    for depth in 1..=height {
+        let ancestors: BTreeSet<TreeIndex> =
+            leaves.iter().map(|leaf| leaf.get_prefix(depth)).collect();
+        for ancestor in ancestors.iter() {
+            let sibling = ancestor.get_sibling_index();
+            if !ancestors.contains(&sibling) {
+                siblings.push(sibling);
+            }
+        }
+    }
+    siblings
+}
+
+/// Verifies a batched Merkle proof of the specified leaves against the specified root.
+///
+/// This replaces MerkleProof::verify_batch() of smtree, which writes to stdout when a proof is
+/// invalid. Nodes are merged layer by layer from the leaves, with siblings expected in the order
+/// given by [get_sibling_indexes()].
+pub(crate) fn verify_merkle_batch<P>(
+    merkle: &MerkleProof<P>,
+    leaves: &[P::ProofNode],
+    root: &P::ProofNode,
+) -> bool
+where
+    P: Clone + Default + Mergeable + Paddable + ProofExtractable,
+    P::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+{
+    let indexes = merkle.get_indexes();
+    if leaves.len() != indexes.len() {
+        return false;
+    }
+    let height = match indexes.first() {
+        Some(index) => index.get_height(),
+        None => return merkle.get_siblings_num() == 0,
+    };
+    if indexes.iter().any(|index| index.get_height() != height)
+        || indexes.windows(2).any(|pair| pair[0] >= pair[1])
+    {
+        return false;
+    }
+    let positions = get_sibling_indexes(indexes);
+    if positions.len() != merkle.get_siblings_num() {
+        return false;
+    }
+
+    let mut nodes: BTreeMap<TreeIndex, P::ProofNode> = indexes
+        .iter()
+        .copied()
+        .zip(leaves.iter().cloned())
+        .collect();
+    for (i, position) in positions.into_iter().enumerate() {
+        nodes.insert(position, merkle.get_sibling_at_idx(i).clone());
+    }
+    for depth in (1..=height).rev() {
+        let layer: Vec<TreeIndex> = nodes
+            .keys()
+            .filter(|index| index.get_height() == depth)
+            .copied()
+            .collect();
+        for index in layer.into_iter().filter(|index| index.get_last_bit() == 0) {
+            let lch = nodes.remove(&index);
+            let rch = nodes.remove(&index.get_sibling_index());
+            match (lch, rch) {
+                (Some(lch), Some(rch)) => {
+                    nodes.insert(index.get_parent_index(), Mergeable::merge(&lch, &rch));
+                }
+                _ => return false,
+            }
+        }
+    }
+    nodes.len() == 1 && nodes.get(&TreeIndex::zero(0)) == Some(root)
+}
+
+/// Decodes a Merkle path serialized by smtree as batch_num || height || paths || sibling_num ||
+/// siblings, where each sibling takes `node_size` bytes.
+///
+/// smtree trusts the encoded counts and height, and so a hostile path can make it overflow or
+/// allocate without bound. The counts and the height are therefore checked against the number of
+/// remaining bytes first, and smtree decodes only the bytes of a path which passed the checks.
+fn deserialize_merkle_path<P>(
+    bytes: &[u8],
+    begin: &mut usize,
+    node_size: usize,
+) -> Result<MerkleProof<P>, DecodingError>
+where
+    P: Clone + Default + Mergeable + Paddable + ProofExtractable,
+    P::ProofNode: Clone + Default + Eq + Mergeable + Serializable,
+{
+    let start = *begin;
+    let mut end = start;
+    if remaining_bytes(bytes, start) > 0 {
+        let batch_num = bytes_to_usize_with_error(bytes, BATCH_NUM_BYTE_NUM, &mut end)?;
+        let height = bytes_to_usize_with_error(bytes, HEIGHT_BYTE_NUM, &mut end)?;
+        if height == 0 || height > MAX_TREE_HEIGHT {
+            return Err(DecodingError::ValueDecodingError {
+                msg: format!("Invalid height {} of Merkle path indexes", height),
+            });
+        }
+        let path_size = height.div_ceil(8);
+        if batch_num == 0 || batch_num > remaining_bytes(bytes, end) / path_size {
+            return Err(DecodingError::BytesNotEnough);
+        }
+        end += batch_num * path_size;
+        let sibling_num = bytes_to_usize_with_error(bytes, SIBLING_NUM_BYTE_NUM, &mut end)?;
+        if sibling_num > remaining_bytes(bytes, end) / node_size {
+            return Err(DecodingError::BytesNotEnough);
+        }
+        end += sibling_num * node_size;
+    }
+
+    let mut path_begin = 0;
+    let merkle = MerkleProof::<P>::deserialize_as_a_unit(&bytes[start..end], &mut path_begin)?;
+    if path_begin != end - start {
+        return Err(DecodingError::TooManyEncodedBytes);
     }
+    *begin = end;
+    Ok(merkle)
 }
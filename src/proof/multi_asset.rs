@@ -0,0 +1,275 @@
+use super::{deserialize_merkle_path, get_sibling_indexes, verify_merkle_batch};
+use crate::{
+    errors::DapolError, hashing::hash_internal, MultiAssetNode, RangeProofContext, RangeVerifiable,
+};
+use curve25519_dalek_ng::ristretto::{CompressedRistretto, RistrettoPoint};
+use digest::Digest;
+use smtree::{
+    error::DecodingError,
+    proof::MerkleProof,
+    traits::{Mergeable, Serializable, TypeName},
+};
+use std::marker::PhantomData;
+
+const COM_BYTE_NUM: usize = 32;
+
+// MULTI-ASSET PROOF NODE
+// ================================================================================================
+
+/// A node of the multi-asset DAPOL proof, consisting of the Pedersen commitments of all `N`
+/// assets and the hash.
+#[derive(Clone, Debug)]
+pub struct MultiAssetProofNode<D, const N: usize> {
+    coms: Vec<RistrettoPoint>, // The Pedersen commitments of each asset.
+    hash: Vec<u8>,             // The hash.
+    _phantom_hash_function: PhantomData<D>, // The hash function.
+}
+
+impl<D, const N: usize> MultiAssetProofNode<D, N> {
+    /// The constructor.
+    ///
+    /// Returns an error if the number of commitments is not equal to the number of assets `N`.
+    pub fn new(coms: Vec<RistrettoPoint>, hash: Vec<u8>) -> Result<Self, DapolError> {
+        if coms.len() != N {
+            return Err(DapolError::InvalidAssetCount(N, coms.len()));
+        }
+        Ok(Self::from_parts(coms, hash))
+    }
+
+    /// Returns a proof node with the specified commitments, which must contain exactly `N`
+    /// commitments.
+    pub(crate) fn from_parts(coms: Vec<RistrettoPoint>, hash: Vec<u8>) -> Self {
+        debug_assert_eq!(N, coms.len());
+        MultiAssetProofNode {
+            coms,
+            hash,
+            _phantom_hash_function: PhantomData,
+        }
+    }
+
+    /// Returns the Pedersen commitments of all assets.
+    pub fn get_coms(&self) -> &[RistrettoPoint] {
+        &self.coms
+    }
+
+    /// Returns the hash.
+    pub fn get_hash(&self) -> &Vec<u8> {
+        &self.hash
+    }
+}
+
+impl<D, const N: usize> Default for MultiAssetProofNode<D, N> {
+    fn default() -> Self {
+        MultiAssetProofNode::from_parts(vec![RistrettoPoint::default(); N], Vec::new())
+    }
+}
+
+impl<D, const N: usize> PartialEq for MultiAssetProofNode<D, N> {
+    /// Two proof nodes are equal iff both the commitments and the hashes are the same.
+    fn eq(&self, other: &Self) -> bool {
+        self.coms == other.coms && self.hash == other.hash
+    }
+}
+
+impl<D, const N: usize> Eq for MultiAssetProofNode<D, N> {}
+
+impl<D: Digest, const N: usize> Mergeable for MultiAssetProofNode<D, N> {
+    /// Returns the parent node by merging two child nodes.
+    ///
+    /// The commitment of each asset of the parent is the homomorphic sum of the commitments of
+    /// the same asset of the two children. The hash of the parent is computed by hashing the
+    /// concatenated commitments and hashes of two children, prefixed by the domain-separation tag
+    /// of internal nodes.
+    fn merge(
+        lch: &MultiAssetProofNode<D, N>,
+        rch: &MultiAssetProofNode<D, N>,
+    ) -> MultiAssetProofNode<D, N> {
+        // C(parent)_i = C(L)_i + C(R)_i
+        let coms = lch
+            .coms
+            .iter()
+            .zip(rch.coms.iter())
+            .map(|(l, r)| l + r)
+            .collect();
+
+        // H(parent) = Hash("DAPOL/v2/internal" || C(L)_1 || ... || C(L)_N || C(R)_1 || ... ||
+        //                  C(R)_N || H(L) || H(R))
+        let hash = hash_internal::<D>(&lch.coms, &rch.coms, &lch.hash, &rch.hash);
+
+        MultiAssetProofNode::from_parts(coms, hash)
+    }
+}
+
+impl<D: Digest, const N: usize> Serializable for MultiAssetProofNode<D, N> {
+    /// Com_1 || ... || Com_N || Hash
+    fn serialize(&self) -> Vec<u8> {
+        let mut result = Vec::new();
+        for com in self.coms.iter() {
+            result.extend_from_slice(com.compress().as_bytes());
+        }
+        result.extend_from_slice(self.get_hash());
+        result
+    }
+
+    fn deserialize_as_a_unit(bytes: &[u8], begin: &mut usize) -> Result<Self, DecodingError> {
+        let unit_byte_num = COM_BYTE_NUM * N + D::output_size();
+        if bytes.len().saturating_sub(*begin) < unit_byte_num {
+            return Err(DecodingError::BytesNotEnough);
+        }
+
+        let mut coms = Vec::with_capacity(N);
+        for _ in 0..N {
+            let com = CompressedRistretto::from_slice(&bytes[*begin..*begin + COM_BYTE_NUM])
+                .decompress()
+                .ok_or_else(|| DecodingError::ValueDecodingError {
+                    msg: "Not the canonical encoding of a point.".to_string(),
+                })?;
+            coms.push(com);
+            *begin += COM_BYTE_NUM;
+        }
+        let hash = bytes[*begin..*begin + D::output_size()].to_vec();
+        *begin += D::output_size();
+        Ok(MultiAssetProofNode::from_parts(coms, hash))
+    }
+
+    /// Com_1 || ... || Com_N || Hash
+    fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
+        let mut begin = 0;
+        Self::deserialize_as_a_unit(bytes, &mut begin)
+    }
+}
+
+impl<D: TypeName, const N: usize> TypeName for MultiAssetProofNode<D, N> {
+    /// Returns the type name of multi-asset proof nodes with corresponding hash function (for
+    /// logging purpose).
+    fn get_name() -> String {
+        format!(
+            "DAPOL Multi-Asset Proof Node ({}, {} assets)",
+            D::get_name(),
+            N
+        )
+    }
+}
+
+// MULTI-ASSET PROOF
+// ================================================================================================
+
+/// A proof of inclusion of one or more leaves in a multi-asset DAPOL tree. The proof consists of
+/// a Merkle path and a set of range proofs for each asset; range proofs for an asset cover the
+/// commitments of that asset of all siblings along the path.
+#[derive(Debug)]
+pub struct MultiAssetProof<D, R, const N: usize>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    merkle: MerkleProof<MultiAssetNode<D, N>>,
+    range_proofs: Vec<R>,
+}
+
+impl<D, R, const N: usize> MultiAssetProof<D, R, N>
+where
+    D: Digest + Default + Clone + std::fmt::Debug,
+    R: Clone + RangeVerifiable + Serializable,
+{
+    // CONSTRUCTOR
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a new proof; `range_proofs` must contain one set of range proofs per asset.
+    pub fn new(merkle_proof: MerkleProof<MultiAssetNode<D, N>>, range_proofs: Vec<R>) -> Self {
+        MultiAssetProof {
+            merkle: merkle_proof,
+            range_proofs,
+        }
+    }
+
+    // PUBLIC METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns true if the specified leaf is included in the tree with the specified root, and
+    /// the values of all assets of all siblings along the path are within the valid range.
+    pub fn verify(
+        &self,
+        root: &MultiAssetProofNode<D, N>,
+        leaf: &MultiAssetProofNode<D, N>,
+    ) -> bool {
+        if !self.merkle.verify(leaf, root) {
+            return false;
+        }
+        self.verify_proof(root)
+    }
+
+    /// Returns true if all of the specified leaves are included in the tree with the specified
+    /// root, and the values of all assets of all siblings are within the valid range.
+    pub fn verify_batch(
+        &self,
+        root: &MultiAssetProofNode<D, N>,
+        leaves: &[MultiAssetProofNode<D, N>],
+    ) -> bool {
+        if !verify_merkle_batch(&self.merkle, leaves, root) {
+            return false;
+        }
+        self.verify_proof(root)
+    }
+
+    /// Returns range proofs for each asset.
+    pub fn get_range_proofs(&self) -> &[R] {
+        &self.range_proofs
+    }
+
+    pub fn get_merkle_path(&self) -> &MerkleProof<MultiAssetNode<D, N>> {
+        &self.merkle
+    }
+
+    // SERIALIZATION / DESERIALIZATION
+    // --------------------------------------------------------------------------------------------
+
+    /// range_proof_1 || ... || range_proof_N || merkle_path
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for range_proof in self.range_proofs.iter() {
+            bytes.append(&mut range_proof.serialize());
+        }
+        bytes.append(&mut self.merkle.serialize());
+        bytes
+    }
+
+    /// range_proof_1 || ... || range_proof_N || merkle_path
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
+        let mut begin = 0;
+        let mut range_proofs = Vec::with_capacity(N);
+        for _ in 0..N {
+            range_proofs.push(R::deserialize_as_a_unit(bytes, &mut begin)?);
+        }
+        let node_size = COM_BYTE_NUM * N + D::output_size();
+        let merkle = deserialize_merkle_path(bytes, &mut begin, node_size)?;
+        Ok(MultiAssetProof {
+            merkle,
+            range_proofs,
+        })
+    }
+
+    // HELPER METHODS
+    // --------------------------------------------------------------------------------------------
+
+    fn verify_proof(&self, root: &MultiAssetProofNode<D, N>) -> bool {
+        if self.range_proofs.len() != N {
+            return false;
+        }
+        let indexes = self.merkle.get_indexes();
+        let positions = get_sibling_indexes(indexes);
+        let tree_height = indexes.first().map_or(0, |idx| idx.get_height());
+        let context = RangeProofContext::new(root.get_hash(), tree_height, &positions);
+        for (asset_idx, range_proof) in self.range_proofs.iter().enumerate() {
+            let mut commitments = Vec::new();
+            for i in 0..self.merkle.get_siblings_num() {
+                let sibling = self.merkle.get_sibling_at_idx(i);
+                commitments.push(sibling.get_coms()[asset_idx].compress());
+            }
+            if !range_proof.verify(&commitments[..], &context) {
+                return false;
+            }
+        }
+        true
+    }
+}
@@ -1,3 +1,7 @@
+use crate::{
+    hashing::{hash_internal, hash_leaf},
+    utils::remaining_bytes,
+};
 use curve25519_dalek_ng::{ristretto::CompressedRistretto, ristretto::RistrettoPoint};
 use digest::Digest;
 use smtree::{
@@ -6,7 +10,7 @@ use smtree::{
 };
 use std::marker::PhantomData;
 
-const COM_BYTE_NUM: usize = 32;
+pub(super) const COM_BYTE_NUM: usize = 32;
 
 // DAPOL PROOF NODE
 // ================================================================================================
@@ -48,22 +52,27 @@ impl<D> DapolProofNode<D> {
     }
 }
 
+impl<D: Digest> DapolProofNode<D> {
+    /// Returns the proof node of a leaf with the specified commitment in a tree with the
+    /// specified context string; this allows users to recompute their leaf from the commitment.
+    pub fn new_leaf(com: RistrettoPoint, context: &[u8]) -> DapolProofNode<D> {
+        let hash = hash_leaf::<D>(context, &[com]);
+        DapolProofNode::new(com, hash)
+    }
+}
+
 impl<D: Digest> Mergeable for DapolProofNode<D> {
     /// Returns the parent node by merging two child nodes.
     ///
     /// The commitment of the parent is the homomorphic sum of the two children.
-    /// The hash of the parent is computed by hashing the concatenated commitments and hashes of two children.
+    /// The hash of the parent is computed by hashing the concatenated commitments and hashes of two
+    /// children, prefixed by the domain-separation tag of internal nodes.
     fn merge(lch: &DapolProofNode<D>, rch: &DapolProofNode<D>) -> DapolProofNode<D> {
         // C(parent) = C(L) + C(R)
         let com = lch.com + rch.com;
 
-        // H(parent) = Hash(C(L) || C(R) || H(L) || H(R))
-        let mut hasher = D::new();
-        hasher.update(&(lch.com.compress().as_bytes().to_vec()));
-        hasher.update(&(rch.com.compress().as_bytes().to_vec()));
-        hasher.update(&lch.hash);
-        hasher.update(&rch.hash);
-        let hash = hasher.finalize().to_vec();
+        // H(parent) = Hash("DAPOL/v2/internal" || C(L) || C(R) || H(L) || H(R))
+        let hash = hash_internal::<D>(&[lch.com], &[rch.com], &lch.hash, &rch.hash);
 
         DapolProofNode::new(com, hash)
     }
@@ -74,13 +83,13 @@ impl<D: Digest> Serializable for DapolProofNode<D> {
     fn serialize(&self) -> Vec<u8> {
         let mut result = Vec::new();
         result.extend_from_slice(&self.get_com().compress().as_bytes()[..]);
-        result.extend_from_slice(&self.get_hash());
+        result.extend_from_slice(self.get_hash());
         result
     }
 
     fn deserialize_as_a_unit(bytes: &[u8], begin: &mut usize) -> Result<Self, DecodingError> {
         let unit_byte_num = COM_BYTE_NUM + D::output_size();
-        if bytes.len() - *begin < unit_byte_num {
+        if remaining_bytes(bytes, *begin) < unit_byte_num {
             return Err(DecodingError::BytesNotEnough);
         }
 
@@ -108,6 +117,46 @@ impl<D: Digest> Serializable for DapolProofNode<D> {
     }
 }
 
+/// With the `serde` feature, a proof node is serialized as the compressed commitment and the
+/// hash, both as hex strings.
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerdeDapolProofNode {
+    #[serde(with = "crate::utils::hex")]
+    com: Vec<u8>,
+    #[serde(with = "crate::utils::hex")]
+    hash: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl<D> serde::Serialize for DapolProofNode<D> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let node = SerdeDapolProofNode {
+            com: self.com.compress().as_bytes().to_vec(),
+            hash: self.hash.clone(),
+        };
+        serde::Serialize::serialize(&node, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, D: Digest> serde::Deserialize<'de> for DapolProofNode<D> {
+    fn deserialize<De: serde::Deserializer<'de>>(deserializer: De) -> Result<Self, De::Error> {
+        use serde::de::Error;
+        let node = SerdeDapolProofNode::deserialize(deserializer)?;
+        if node.com.len() != COM_BYTE_NUM {
+            return Err(De::Error::custom("Invalid length of the commitment."));
+        }
+        let com = CompressedRistretto::from_slice(&node.com)
+            .decompress()
+            .ok_or_else(|| De::Error::custom("Not the canonical encoding of a point."))?;
+        if node.hash.len() != D::output_size() {
+            return Err(De::Error::custom("Invalid length of the hash."));
+        }
+        Ok(DapolProofNode::new(com, node.hash))
+    }
+}
+
 impl<D: TypeName> TypeName for DapolProofNode<D> {
     /// Returns the type name of DAPOL proof nodes with corresponding hash function (for logging purpose).
     fn get_name() -> String {
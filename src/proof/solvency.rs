@@ -0,0 +1,123 @@
+use crate::{
+    range::{generate_single_range_proof, verify_single_range_proof, DEFAULT_BIT_SIZE},
+    DapolProofNode,
+};
+use bulletproofs::{PedersenGens, RangeProof};
+use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
+use merlin::Transcript;
+use smtree::error::DecodingError;
+
+// CONSTANTS
+// ================================================================================================
+
+const TRANSCRIPT_LABEL: &[u8] = b"DAPOL solvency proof";
+
+// SOLVENCY PROOF
+// ================================================================================================
+
+/// A proof that the total of liabilities committed to by a tree root does not exceed the reserves
+/// committed to by a separate Pedersen commitment, without revealing either of them.
+///
+/// Commitments are additively homomorphic, and so C(reserves) - C(root) is a commitment to
+/// reserves - liabilities. The proof is a range proof showing that this difference is within
+/// [0, 2^64). The verifier must obtain the commitment to reserves from a trusted source (e.g.
+/// the custodian of the reserves), as the proof says nothing about how it was computed.
+#[derive(Debug, Clone)]
+pub struct SolvencyProof {
+    range_proof: RangeProof,
+}
+
+impl SolvencyProof {
+    // CONSTRUCTOR
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a proof that `reserves` are at least as large as `total`, where the blinding
+    /// factors are those of the commitments to reserves and to the total; the latter would usually
+    /// be the value and the blinding factor of the tree root. Returns None if reserves are smaller
+    /// than the total.
+    pub fn generate(
+        reserves: u64,
+        reserves_blinding: &Scalar,
+        total: u64,
+        total_blinding: &Scalar,
+    ) -> Option<Self> {
+        let surplus = reserves.checked_sub(total)?;
+        let blinding = reserves_blinding - total_blinding;
+        let pc_gens = PedersenGens::default();
+        let transcript = build_transcript(
+            &pc_gens
+                .commit(Scalar::from(reserves), *reserves_blinding)
+                .compress(),
+            &pc_gens
+                .commit(Scalar::from(total), *total_blinding)
+                .compress(),
+        );
+        Some(SolvencyProof {
+            range_proof: generate_single_range_proof(
+                surplus,
+                &blinding,
+                DEFAULT_BIT_SIZE,
+                transcript,
+            ),
+        })
+    }
+
+    /// Returns a Pedersen commitment to the specified reserves, which can be published alongside
+    /// a proof generated with the same blinding factor.
+    pub fn commit_reserves(reserves: u64, blinding: &Scalar) -> CompressedRistretto {
+        PedersenGens::default()
+            .commit(Scalar::from(reserves), *blinding)
+            .compress()
+    }
+
+    // PUBLIC METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns true if the reserves hidden by the specified commitment are at least as large as
+    /// the total of liabilities committed to by the specified root.
+    pub fn verify<D>(&self, root: &DapolProofNode<D>, reserves_com: &CompressedRistretto) -> bool {
+        let reserves_point = match reserves_com.decompress() {
+            Some(com) => com,
+            None => return false,
+        };
+        let surplus_com = (reserves_point - root.get_com()).compress();
+        let transcript = build_transcript(reserves_com, &root.get_com().compress());
+        verify_single_range_proof(
+            &self.range_proof,
+            &surplus_com,
+            DEFAULT_BIT_SIZE,
+            transcript,
+        )
+    }
+
+    // SERIALIZATION / DESERIALIZATION
+    // --------------------------------------------------------------------------------------------
+
+    /// range_proof
+    pub fn serialize(&self) -> Vec<u8> {
+        self.range_proof.to_bytes()
+    }
+
+    /// range_proof
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
+        let range_proof =
+            RangeProof::from_bytes(bytes).map_err(|err| DecodingError::ValueDecodingError {
+                msg: err.to_string(),
+            })?;
+        Ok(SolvencyProof { range_proof })
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Returns a transcript bound to the commitments to reserves and to the total of liabilities.
+fn build_transcript(
+    reserves_com: &CompressedRistretto,
+    total_com: &CompressedRistretto,
+) -> Transcript {
+    let mut transcript = Transcript::new(TRANSCRIPT_LABEL);
+    transcript.append_message(b"reserves_commitment", reserves_com.as_bytes());
+    transcript.append_message(b"total_commitment", total_com.as_bytes());
+    transcript
+}
@@ -1,6 +1,14 @@
-use crate::{utils::get_secret, Dapol, DapolNode, DapolProof, RangeProofSplitting};
+use crate::{
+    errors::DapolError, hashing::HASH_LAYOUT_VERSION, utils::get_secret, AnyDapolProof, Dapol,
+    DapolNode, DapolProof, DapolProofNode, DigestAlgorithm, MultiAssetProof, RangeProofContext,
+    RangeProofPadding, RangeProofSplitting, RangeProofStrategy, RangeProvable, RangeVerifiable,
+    TotalProof,
+};
+use curve25519_dalek_ng::scalar::Scalar;
 use smtree::{
-    index::TreeIndex, traits::ProofExtractable, utils::generate_sorted_index_value_pairs,
+    index::TreeIndex,
+    traits::{ProofExtractable, Serializable},
+    utils::{generate_sorted_index_value_pairs, usize_to_bytes},
 };
 
 #[test]
@@ -33,3 +41,309 @@ fn test_serialization() {
     // implement PartialEq, and there doesn't seem to be an easy way to implement it.
     assert!(deserialized_proof.verify_batch(&dapol.root(), &batch_leaves));
 }
+
+#[test]
+fn test_total_proof() {
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<blake3::Hasher>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<blake3::Hasher, RangeProofSplitting>::new_blank(tree_height, 1);
+    dapol.build(&list, &secret);
+
+    // the proof should verify against the root after serialization
+    let total = dapol.root_raw().get_value();
+    let proof = dapol.generate_total_proof();
+    let proof = TotalProof::deserialize(&proof.serialize()).unwrap();
+    assert_eq!(total, proof.get_total());
+    assert!(proof.verify(&dapol.root()));
+
+    // a proof for a different total should not verify
+    let mut bytes = proof.serialize();
+    bytes[..8].copy_from_slice(&(total + 1).to_be_bytes());
+    let forged = TotalProof::deserialize(&bytes).unwrap();
+    assert!(!forged.verify(&dapol.root()));
+
+    // neither should a proof for a different root
+    let root_blinding = dapol.root_raw().get_blinding() + Scalar::one();
+    let other = TotalProof::generate(total, &root_blinding);
+    assert!(!other.verify(&dapol.root()));
+}
+
+#[test]
+fn test_envelope() {
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<blake2::Blake2s>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<blake2::Blake2s, RangeProofSplitting>::new_blank(tree_height, 2);
+    dapol.build(&list, &secret);
+    let root = dapol.root();
+    let leaf = list[5].1.get_proof_node();
+    let proof = dapol.generate_proof(&list[5].0).unwrap();
+
+    // the header should describe the proof
+    let header = proof.envelope_header();
+    assert_eq!(1, header.version());
+    assert_eq!(HASH_LAYOUT_VERSION, header.hash_layout_version());
+    assert_eq!(DigestAlgorithm::Blake2s, header.digest());
+    assert_eq!(RangeProofStrategy::Splitting, header.strategy());
+    assert_eq!(64, header.bit_size());
+    assert_eq!(tree_height, header.tree_height());
+
+    // the proof should survive a round trip through the envelope
+    let bytes = proof.serialize_envelope();
+    let decoded =
+        DapolProof::<blake2::Blake2s, RangeProofSplitting>::deserialize_envelope(&bytes).unwrap();
+    assert_eq!(proof.serialize(), decoded.serialize());
+    assert!(decoded.verify(&root, &leaf));
+
+    // and it should be decoded without knowing the digest and the range proof strategy
+    let decoded = AnyDapolProof::deserialize_any(&bytes).unwrap();
+    assert!(matches!(decoded, AnyDapolProof::Blake2sSplitting(_)));
+    assert_eq!(header, decoded.envelope_header());
+    assert_eq!(bytes, decoded.serialize_envelope());
+    assert!(decoded.verify(&root.serialize(), &leaf.serialize()));
+    assert!(!decoded.verify(&leaf.serialize(), &leaf.serialize()));
+    assert!(!decoded.verify(&root.serialize()[..10], &leaf.serialize()));
+
+    // envelopes for other proof types should be rejected
+    let result = DapolProof::<blake2::Blake2s, RangeProofPadding>::deserialize_envelope(&bytes);
+    assert!(matches!(
+        result,
+        Err(DapolError::ProofEnvelopeMismatch(_, _))
+    ));
+    let result = DapolProof::<blake3::Hasher, RangeProofSplitting>::deserialize_envelope(&bytes);
+    assert!(matches!(
+        result,
+        Err(DapolError::ProofEnvelopeMismatch(_, _))
+    ));
+
+    // and so should malformed headers, and envelopes of proofs for trees hashed with an older
+    // layout
+    let result = AnyDapolProof::deserialize_any(&proof.serialize());
+    assert!(matches!(result, Err(DapolError::MalformedProofEnvelope(_))));
+    for (pos, value) in [(8, 2), (9, 1), (10, 0), (11, 3), (12, 24), (13, 0), (13, 9)] {
+        let mut malformed = bytes.clone();
+        malformed[pos] = value;
+        let result = AnyDapolProof::deserialize_any(&malformed);
+        assert!(matches!(result, Err(DapolError::MalformedProofEnvelope(_))));
+    }
+}
+
+#[test]
+fn test_envelope_sha3_512() {
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<sha3::Sha3_512>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<sha3::Sha3_512, RangeProofPadding>::new_blank(tree_height, 2);
+    dapol.build(&list, &secret);
+    let root = dapol.root();
+    let leaf = list[5].1.get_proof_node();
+    let proof = dapol.generate_proof(&list[5].0).unwrap();
+    assert_eq!(DigestAlgorithm::Sha3_512, proof.envelope_header().digest());
+
+    // the proof should survive a round trip through the envelope
+    let bytes = proof.serialize_envelope();
+    let decoded =
+        DapolProof::<sha3::Sha3_512, RangeProofPadding>::deserialize_envelope(&bytes).unwrap();
+    assert_eq!(proof.serialize(), decoded.serialize());
+    assert!(decoded.verify(&root, &leaf));
+
+    let decoded = AnyDapolProof::deserialize_any(&bytes).unwrap();
+    assert!(matches!(decoded, AnyDapolProof::Sha3_512Padding(_)));
+    assert_eq!(bytes, decoded.serialize_envelope());
+    assert!(decoded.verify(&root.serialize(), &leaf.serialize()));
+}
+
+#[test]
+fn test_verify_many() {
+    verify_many::<RangeProofSplitting>(3);
+    verify_many::<RangeProofPadding>(2);
+}
+
+fn verify_many<R>(aggregation_factor: usize)
+where
+    R: Clone + Serializable + RangeProvable + RangeVerifiable,
+{
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<blake3::Hasher>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<blake3::Hasher, R>::new_blank(tree_height, aggregation_factor);
+    dapol.build(&list, &secret);
+    let root = dapol.root();
+
+    let leaves: Vec<_> = list
+        .iter()
+        .take(6)
+        .map(|item| item.1.get_proof_node())
+        .collect();
+    let mut proofs: Vec<_> = list
+        .iter()
+        .take(6)
+        .map(|item| dapol.generate_proof(&item.0).unwrap())
+        .collect();
+
+    // all proofs should verify in a batch
+    let items: Vec<_> = proofs
+        .iter()
+        .zip(leaves.iter())
+        .map(|(p, l)| (p, l, &root))
+        .collect();
+    assert!(DapolProof::verify_many(&items).is_ok());
+    assert!(DapolProof::<blake3::Hasher, R>::verify_many(&[]).is_ok());
+
+    // range proofs generated for another leaf should be located as invalid, and so should a
+    // Merkle path for another leaf
+    proofs[1] = DapolProof::new(
+        proofs[1].get_merkle_path().clone(),
+        proofs[5].get_range_proofs().clone(),
+    );
+    proofs[4] = dapol.generate_proof(&list[0].0).unwrap();
+    let items: Vec<_> = proofs
+        .iter()
+        .zip(leaves.iter())
+        .map(|(p, l)| (p, l, &root))
+        .collect();
+    assert_eq!(DapolProof::verify_many(&items), Err(vec![1, 4]));
+    for (i, (proof, leaf, root)) in items.iter().enumerate() {
+        assert_eq!(proof.verify(root, leaf), i != 1 && i != 4);
+    }
+}
+
+#[test]
+fn test_malformed_proofs() {
+    malformed_proofs::<RangeProofSplitting>();
+    malformed_proofs::<RangeProofPadding>();
+
+    // padding proofs hold at most one aggregated proof
+    let positions: Vec<TreeIndex> = (0..4).map(|i| TreeIndex::from_u64(8, i)).collect();
+    let context = RangeProofContext::new(&[0u8; 32], 8, &positions);
+    let proofs = RangeProofPadding::generate_proof(&[1; 4], &[Scalar::one(); 4], &context, 4, 8);
+    let aggregated = proofs.get_aggregated().unwrap().clone();
+    assert!(RangeProofPadding::new(&[aggregated.clone(), aggregated], &[], 8).is_none());
+}
+
+fn malformed_proofs<R>()
+where
+    R: Clone + Serializable + RangeProvable + RangeVerifiable,
+{
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<blake3::Hasher>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<blake3::Hasher, R>::new_blank(tree_height, 2);
+    dapol.build(&list, &secret);
+    let root = dapol.root();
+    let leaf = list[3].1.get_proof_node();
+    let proof = dapol.generate_proof(&list[3].0).unwrap();
+
+    // truncated bytes should fail to decode, or decode into a proof which does not verify
+    let bytes = proof.serialize();
+    for len in 0..bytes.len() {
+        if let Ok(decoded) = DapolProof::<blake3::Hasher, R>::deserialize(&bytes[..len]) {
+            assert!(!decoded.verify(&root, &leaf));
+        }
+    }
+    let mut begin = bytes.len() + 1;
+    assert!(R::deserialize_as_a_unit(&bytes, &mut begin).is_err());
+    assert!(DapolProofNode::<blake3::Hasher>::deserialize_as_a_unit(&bytes, &mut begin).is_err());
+
+    // range proofs which do not match the siblings of the Merkle path should not verify
+    let positions: Vec<TreeIndex> = (0..10).map(|i| TreeIndex::from_u64(8, i)).collect();
+    let context = RangeProofContext::new(root.get_hash(), tree_height, &positions);
+    let mismatched = vec![
+        R::new(&[], &[], 64).unwrap(),
+        R::generate_proof(&[1; 10], &[Scalar::one(); 10], &context, 0, 64),
+        R::generate_proof(&[1; 8], &[Scalar::one(); 8], &context, 8, 64),
+    ];
+    for range_proofs in mismatched {
+        let proof = DapolProof::new(proof.get_merkle_path().clone(), range_proofs);
+        assert!(!proof.verify(&root, &leaf));
+        assert_eq!(
+            DapolProof::verify_many(&[(&proof, &leaf, &root)]),
+            Err(vec![0])
+        );
+    }
+    assert!(R::new(&[], &[], 7).is_none());
+}
+
+#[test]
+fn test_hostile_merkle_paths() {
+    // Merkle paths claiming more indexes or siblings than there are bytes should be rejected
+    // before smtree decodes them, instead of overflowing or exhausting memory
+    let range_proof = RangeProofSplitting::new(&[], &[], 64).unwrap().serialize();
+    assert!(DapolProof::<blake3::Hasher, RangeProofSplitting>::deserialize(&range_proof).is_ok());
+    for (batch_num, height, sibling_num) in [
+        (0x7fff_ffff_ffff_ffff, 64, 0),
+        (1 << 28, 0, 0),
+        (1, 8, 1 << 60),
+    ] {
+        let mut path = usize_to_bytes(batch_num, 8);
+        path.extend_from_slice(&usize_to_bytes(height, 2));
+        path.push(0);
+        path.extend_from_slice(&usize_to_bytes(sibling_num, 8));
+        path.extend_from_slice(&[0u8; 64]);
+
+        let mut bytes = range_proof.clone();
+        bytes.extend_from_slice(&path);
+        let result = DapolProof::<blake3::Hasher, RangeProofSplitting>::deserialize(&bytes);
+        assert!(result.is_err());
+
+        let mut bytes = range_proof.clone();
+        bytes.extend_from_slice(&range_proof);
+        bytes.extend_from_slice(&path);
+        let result = MultiAssetProof::<blake3::Hasher, RangeProofSplitting, 2>::deserialize(&bytes);
+        assert!(result.is_err());
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    let tree_height = 8;
+    let list: Vec<(TreeIndex, DapolNode<blake3::Hasher>)> =
+        generate_sorted_index_value_pairs(tree_height, 20);
+    let secret = get_secret();
+    let mut dapol = Dapol::<blake3::Hasher, RangeProofPadding>::new_blank(tree_height, 2);
+    dapol.build(&list, &secret);
+    let root = dapol.root();
+    let leaf = list[3].1.get_proof_node();
+    let proof = dapol.generate_proof(&list[3].0).unwrap();
+
+    // proofs should be encoded as hex strings of the binary format
+    let hex = |bytes: Vec<u8>| -> String { bytes.iter().map(|b| format!("{:02x}", b)).collect() };
+    let json = serde_json::to_string(&proof).unwrap();
+    assert_eq!(format!("\"{}\"", hex(proof.serialize())), json);
+    let decoded: DapolProof<blake3::Hasher, RangeProofPadding> =
+        serde_json::from_str(&json).unwrap();
+    assert_eq!(proof.serialize(), decoded.serialize());
+    assert!(decoded.verify(&root, &leaf));
+
+    let range_proofs = proof.get_range_proofs();
+    let json = serde_json::to_string(range_proofs).unwrap();
+    assert_eq!(format!("\"{}\"", hex(range_proofs.serialize())), json);
+    let decoded: RangeProofPadding = serde_json::from_str(&json).unwrap();
+    assert_eq!(range_proofs.serialize(), decoded.serialize());
+
+    // proof nodes should be encoded as the commitment and the hash
+    let json = serde_json::to_string(&root).unwrap();
+    let expected = format!(
+        "{{\"com\":\"{}\",\"hash\":\"{}\"}}",
+        hex(root.get_com().compress().as_bytes().to_vec()),
+        hex(root.get_hash().clone())
+    );
+    assert_eq!(expected, json);
+    assert_eq!(root, serde_json::from_str(&json).unwrap());
+
+    // malformed strings should be rejected
+    assert!(serde_json::from_str::<RangeProofPadding>("\"0\"").is_err());
+    assert!(serde_json::from_str::<RangeProofPadding>("\"zz\"").is_err());
+    let json = serde_json::to_string(&proof).unwrap();
+    let truncated = format!("{}\"", &json[..json.len() - 3]);
+    assert!(
+        serde_json::from_str::<DapolProof<blake3::Hasher, RangeProofPadding>>(&truncated).is_err()
+    );
+}
@@ -0,0 +1,158 @@
+use crate::{DapolProofNode, MultiAssetProofNode};
+use bulletproofs::PedersenGens;
+use curve25519_dalek_ng::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+};
+use merlin::Transcript;
+use smtree::error::DecodingError;
+use std::convert::TryInto;
+
+// CONSTANTS
+// ================================================================================================
+
+const TOTAL_BYTE_NUM: usize = 8;
+const POINT_BYTE_NUM: usize = 32;
+const SCALAR_BYTE_NUM: usize = 32;
+const TOTAL_PROOF_BYTE_NUM: usize = TOTAL_BYTE_NUM + POINT_BYTE_NUM + SCALAR_BYTE_NUM;
+
+const TRANSCRIPT_LABEL: &[u8] = b"DAPOL disclosed total";
+
+// TOTAL PROOF
+// ================================================================================================
+
+/// A proof that the Pedersen commitment of a tree root opens to a publicly disclosed total.
+///
+/// The commitment is C = total * G + blinding * H. The proof is a Schnorr proof of knowledge of
+/// the discrete log of C - total * G with respect to H, and so it convinces the verifier that
+/// the commitment hides the stated total without revealing the blinding factor of the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotalProof {
+    total: u64,
+    nonce_com: CompressedRistretto,
+    response: Scalar,
+}
+
+impl TotalProof {
+    // CONSTRUCTOR
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns a proof that the commitment to `total` with the specified blinding factor opens to
+    /// `total`; these would usually be the value and the blinding factor of the tree root.
+    pub fn generate(total: u64, blinding: &Scalar) -> Self {
+        let pc_gens = PedersenGens::default();
+        let com = pc_gens.commit(Scalar::from(total), *blinding).compress();
+
+        // the nonce is bound to the witness and the statement, and is additionally randomized
+        let mut transcript = build_transcript(&com, total);
+        let mut rng = transcript
+            .build_rng()
+            .rekey_with_witness_bytes(b"blinding", blinding.as_bytes())
+            .finalize(&mut rand::thread_rng());
+        let nonce = Scalar::random(&mut rng);
+        let nonce_com = (nonce * pc_gens.B_blinding).compress();
+
+        let challenge = compute_challenge(&mut transcript, &nonce_com);
+        TotalProof {
+            total,
+            nonce_com,
+            response: nonce + challenge * blinding,
+        }
+    }
+
+    // PUBLIC METHODS
+    // --------------------------------------------------------------------------------------------
+
+    /// Returns the disclosed total.
+    pub fn get_total(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns true if the commitment of the specified root opens to the disclosed total. The root
+    /// is the same one user proofs are verified against.
+    pub fn verify<D>(&self, root: &DapolProofNode<D>) -> bool {
+        self.verify_commitment(&root.get_com())
+    }
+
+    /// Returns true if the commitment of the specified asset of the root of a multi-asset tree
+    /// opens to the disclosed total; returns false if the root has no such asset.
+    pub fn verify_asset<D, const N: usize>(
+        &self,
+        root: &MultiAssetProofNode<D, N>,
+        asset_idx: usize,
+    ) -> bool {
+        match root.get_coms().get(asset_idx) {
+            Some(com) => self.verify_commitment(com),
+            None => false,
+        }
+    }
+
+    /// Returns true if the specified commitment opens to the disclosed total.
+    pub fn verify_commitment(&self, com: &RistrettoPoint) -> bool {
+        let nonce_point = match self.nonce_com.decompress() {
+            Some(point) => point,
+            None => return false,
+        };
+        let pc_gens = PedersenGens::default();
+        let mut transcript = build_transcript(&com.compress(), self.total);
+        let challenge = compute_challenge(&mut transcript, &self.nonce_com);
+
+        // s * H == R + c * (C - total * G)
+        let blinding_com = com - Scalar::from(self.total) * pc_gens.B;
+        self.response * pc_gens.B_blinding == nonce_point + challenge * blinding_com
+    }
+
+    // SERIALIZATION / DESERIALIZATION
+    // --------------------------------------------------------------------------------------------
+
+    /// total || nonce_commitment || response
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(TOTAL_PROOF_BYTE_NUM);
+        bytes.extend_from_slice(&self.total.to_be_bytes());
+        bytes.extend_from_slice(self.nonce_com.as_bytes());
+        bytes.extend_from_slice(self.response.as_bytes());
+        bytes
+    }
+
+    /// total || nonce_commitment || response
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
+        if bytes.len() < TOTAL_PROOF_BYTE_NUM {
+            return Err(DecodingError::BytesNotEnough);
+        }
+        if bytes.len() > TOTAL_PROOF_BYTE_NUM {
+            return Err(DecodingError::TooManyEncodedBytes);
+        }
+        let total = u64::from_be_bytes(bytes[..TOTAL_BYTE_NUM].try_into().unwrap());
+        let nonce_com = CompressedRistretto::from_slice(
+            &bytes[TOTAL_BYTE_NUM..TOTAL_BYTE_NUM + POINT_BYTE_NUM],
+        );
+        let response = Scalar::from_canonical_bytes(
+            bytes[TOTAL_BYTE_NUM + POINT_BYTE_NUM..].try_into().unwrap(),
+        )
+        .ok_or_else(|| DecodingError::ValueDecodingError {
+            msg: "Not the canonical encoding of a scalar.".to_string(),
+        })?;
+        Ok(TotalProof {
+            total,
+            nonce_com,
+            response,
+        })
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+fn build_transcript(com: &CompressedRistretto, total: u64) -> Transcript {
+    let mut transcript = Transcript::new(TRANSCRIPT_LABEL);
+    transcript.append_message(b"commitment", com.as_bytes());
+    transcript.append_u64(b"total", total);
+    transcript
+}
+
+fn compute_challenge(transcript: &mut Transcript, nonce_com: &CompressedRistretto) -> Scalar {
+    transcript.append_message(b"nonce", nonce_com.as_bytes());
+    let mut challenge = [0u8; 64];
+    transcript.challenge_bytes(b"challenge", &mut challenge);
+    Scalar::from_bytes_mod_order_wide(&challenge)
+}
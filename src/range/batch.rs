@@ -0,0 +1,336 @@
+use bulletproofs::{PedersenGens, RangeProof};
+use curve25519_dalek_ng::{
+    ristretto::{CompressedRistretto, RistrettoPoint},
+    scalar::Scalar,
+    traits::{IsIdentity, VartimeMultiscalarMul},
+};
+use digest::{ExtendableOutput, Update, XofReader};
+use merlin::Transcript;
+use rand::thread_rng;
+use sha3::Shake256;
+use std::{convert::TryFrom, iter};
+
+use super::{is_valid_bit_size, verify_aggregated_range_proof, verify_single_range_proof};
+
+// RANGE STATEMENT
+// ================================================================================================
+
+/// A range proof together with the commitments it covers and the transcript it is bound to.
+///
+/// Statements can be verified one by one, or many statements can be verified together in a single
+/// batch, as done by [DapolProof::verify_many](crate::DapolProof::verify_many), which is
+/// considerably faster than verifying them one by one.
+#[derive(Clone)]
+pub struct RangeStatement<'a> {
+    proof: &'a RangeProof,
+    commitments: Vec<CompressedRistretto>,
+    bit_size: usize,
+    transcript: Transcript,
+}
+
+impl<'a> RangeStatement<'a> {
+    pub(super) fn new(
+        proof: &'a RangeProof,
+        commitments: Vec<CompressedRistretto>,
+        bit_size: usize,
+        transcript: Transcript,
+    ) -> Self {
+        RangeStatement {
+            proof,
+            commitments,
+            bit_size,
+            transcript,
+        }
+    }
+
+    /// Returns true if the range proof is valid for the commitments and the transcript.
+    pub fn verify(&self) -> bool {
+        match self.commitments.as_slice() {
+            [commitment] => verify_single_range_proof(
+                self.proof,
+                commitment,
+                self.bit_size,
+                self.transcript.clone(),
+            ),
+            commitments => verify_aggregated_range_proof(
+                self.proof,
+                commitments,
+                self.bit_size,
+                self.transcript.clone(),
+            ),
+        }
+    }
+
+    /// Replays the transcript of the proof and adds the terms of the verification equation of
+    /// the proof, scaled by `weight`, to the batch check. Returns None if the proof is malformed.
+    ///
+    /// This follows the verifier of the Bulletproofs lib, which checks a single multiscalar
+    /// multiplication; since the lib keeps the proof internals private, the proof elements are
+    /// read from its serialization: A || S || T_1 || T_2 || t_x || t_x_blinding || e_blinding ||
+    /// L_1 || R_1 || ... || L_k || R_k || a || b.
+    // usize::is_multiple_of() is not available on toolchains older than 1.87
+    #[allow(unknown_lints, clippy::manual_is_multiple_of)]
+    fn add_to_batch(&self, weight: Scalar, batch: &mut BatchCheck) -> Option<()> {
+        let n = self.bit_size;
+        let m = self.commitments.len();
+        if !is_valid_bit_size(n) || m == 0 {
+            return None;
+        }
+
+        let bytes = self.proof.to_bytes();
+        let element = |i: usize| <[u8; 32]>::try_from(&bytes[i * 32..(i + 1) * 32]).ok();
+        let point = |i: usize| element(i).map(CompressedRistretto);
+        let scalar = |i: usize| element(i).and_then(Scalar::from_canonical_bytes);
+
+        let element_num = bytes.len() / 32;
+        if element_num < 9 || element_num % 2 == 0 {
+            return None;
+        }
+        let lg_nm = (element_num - 9) / 2;
+        if lg_nm >= 32 || (1usize << lg_nm) != n * m {
+            return None;
+        }
+        let nm = n * m;
+
+        let (a_com, s_com, t1_com, t2_com) = (point(0)?, point(1)?, point(2)?, point(3)?);
+        let (t_x, t_x_blinding, e_blinding) = (scalar(4)?, scalar(5)?, scalar(6)?);
+        let l_vec: Vec<CompressedRistretto> = (0..lg_nm)
+            .map(|i| point(7 + 2 * i))
+            .collect::<Option<_>>()?;
+        let r_vec: Vec<CompressedRistretto> = (0..lg_nm)
+            .map(|i| point(8 + 2 * i))
+            .collect::<Option<_>>()?;
+        let a = scalar(7 + 2 * lg_nm)?;
+        let b = scalar(8 + 2 * lg_nm)?;
+
+        // replay the transcript to recompute the challenges
+        let mut transcript = self.transcript.clone();
+        transcript.append_message(b"dom-sep", b"rangeproof v1");
+        transcript.append_u64(b"n", n as u64);
+        transcript.append_u64(b"m", m as u64);
+        for commitment in self.commitments.iter() {
+            transcript.append_message(b"V", commitment.as_bytes());
+        }
+        append_point(&mut transcript, b"A", &a_com)?;
+        append_point(&mut transcript, b"S", &s_com)?;
+        let y = challenge_scalar(&mut transcript, b"y");
+        let z = challenge_scalar(&mut transcript, b"z");
+        let zz = z * z;
+        append_point(&mut transcript, b"T_1", &t1_com)?;
+        append_point(&mut transcript, b"T_2", &t2_com)?;
+        let x = challenge_scalar(&mut transcript, b"x");
+        transcript.append_message(b"t_x", t_x.as_bytes());
+        transcript.append_message(b"t_x_blinding", t_x_blinding.as_bytes());
+        transcript.append_message(b"e_blinding", e_blinding.as_bytes());
+        let w = challenge_scalar(&mut transcript, b"w");
+
+        // inner product proof challenges
+        transcript.append_message(b"dom-sep", b"ipp v1");
+        transcript.append_u64(b"n", nm as u64);
+        let mut challenges = Vec::with_capacity(lg_nm);
+        for (l, r) in l_vec.iter().zip(r_vec.iter()) {
+            append_point(&mut transcript, b"L", l)?;
+            append_point(&mut transcript, b"R", r)?;
+            challenges.push(challenge_scalar(&mut transcript, b"u"));
+        }
+        let mut challenges_inv = challenges.clone();
+        let all_inv = Scalar::batch_invert(&mut challenges_inv);
+        let challenges_sq: Vec<Scalar> = challenges.iter().map(|u| u * u).collect();
+        let challenges_inv_sq: Vec<Scalar> = challenges_inv.iter().map(|u| u * u).collect();
+        let mut s = Vec::with_capacity(nm);
+        s.push(all_inv);
+        for i in 1..nm {
+            let lg_i = (usize::BITS - 1 - i.leading_zeros()) as usize;
+            s.push(s[i - (1 << lg_i)] * challenges_sq[(lg_nm - 1) - lg_i]);
+        }
+
+        // random challenge combining the two checks of the proof
+        let c = Scalar::random(&mut thread_rng());
+
+        let powers_of_2: Vec<Scalar> = powers(Scalar::from(2u64)).take(n).collect();
+        let powers_of_z: Vec<Scalar> = powers(z).take(m).collect();
+        let sum_y: Scalar = powers(y).take(nm).sum();
+        let sum_2: Scalar = powers_of_2.iter().sum();
+        let sum_z: Scalar = powers_of_z.iter().sum();
+        let delta = (z - zz) * sum_y - zz * z * sum_2 * sum_z;
+
+        batch.add(weight, a_com, Scalar::one());
+        batch.add(weight, s_com, x);
+        batch.add(weight, t1_com, c * x);
+        batch.add(weight, t2_com, c * x * x);
+        for (l, u_sq) in l_vec.iter().zip(challenges_sq.iter()) {
+            batch.add(weight, *l, *u_sq);
+        }
+        for (r, u_inv_sq) in r_vec.iter().zip(challenges_inv_sq.iter()) {
+            batch.add(weight, *r, *u_inv_sq);
+        }
+        for (commitment, z_exp) in self.commitments.iter().zip(powers_of_z.iter()) {
+            batch.add(weight, *commitment, c * zz * z_exp);
+        }
+        batch.b_blinding += weight * (-e_blinding - c * t_x_blinding);
+        batch.b += weight * (w * (t_x - a * b) + c * (delta - t_x));
+
+        batch.reserve_gens(n, m);
+        for (i, y_inv_exp) in (0..nm).zip(powers(y.invert())) {
+            let (party, gen) = (i / n, i % n);
+            let z_and_2 = powers_of_z[party] * powers_of_2[gen];
+            batch.g[party][gen] += weight * (-z - a * s[i]);
+            batch.h[party][gen] += weight * (z + y_inv_exp * (zz * z_and_2 - b * s[nm - 1 - i]));
+        }
+        Some(())
+    }
+}
+
+// BATCH VERIFICATION
+// ================================================================================================
+
+/// Returns true if all the specified range statements are valid.
+///
+/// The verification equations of all proofs are combined with random weights and checked with a
+/// single multiscalar multiplication, in which the terms for the generators shared by all proofs
+/// are merged. A batch with an invalid statement fails except with negligible probability, but
+/// the failing statement is not identified; see [locate_invalid_range_statements].
+fn verify_range_statements(statements: &[RangeStatement]) -> bool {
+    let mut batch = BatchCheck::default();
+    let mut rng = thread_rng();
+    for statement in statements.iter() {
+        if statement
+            .add_to_batch(Scalar::random(&mut rng), &mut batch)
+            .is_none()
+        {
+            return false;
+        }
+    }
+    batch.verify()
+}
+
+/// Returns the indexes of the invalid groups of range statements, in increasing order.
+///
+/// Groups are checked together with [verify_range_statements], and a failing batch is split in
+/// halves until the invalid groups are found, so that a few invalid groups among many valid ones
+/// are located with a few batch checks rather than by verifying each group separately.
+pub(crate) fn locate_invalid_range_statements(groups: &[Vec<RangeStatement>]) -> Vec<usize> {
+    let mut invalid = Vec::new();
+    locate_invalid(groups, 0, &mut invalid);
+    invalid
+}
+
+fn locate_invalid(groups: &[Vec<RangeStatement>], offset: usize, invalid: &mut Vec<usize>) {
+    if groups.is_empty() {
+        return;
+    }
+    let statements: Vec<RangeStatement> = groups.iter().flatten().cloned().collect();
+    if verify_range_statements(&statements) {
+        return;
+    }
+    if groups.len() == 1 {
+        invalid.push(offset);
+        return;
+    }
+    let mid = groups.len() / 2;
+    locate_invalid(&groups[..mid], offset, invalid);
+    locate_invalid(&groups[mid..], offset + mid, invalid);
+}
+
+// BATCH CHECK
+// ================================================================================================
+
+/// Terms of the combined verification equation: points specific to single proofs with their
+/// scalars, and the merged scalars of the Pedersen and Bulletproofs generators.
+#[derive(Default)]
+struct BatchCheck {
+    scalars: Vec<Scalar>,
+    points: Vec<CompressedRistretto>,
+    b: Scalar,
+    b_blinding: Scalar,
+    // scalars of the G and H generators, indexed by party and by generator of the party
+    g: Vec<Vec<Scalar>>,
+    h: Vec<Vec<Scalar>>,
+}
+
+impl BatchCheck {
+    fn add(&mut self, weight: Scalar, point: CompressedRistretto, scalar: Scalar) {
+        self.points.push(point);
+        self.scalars.push(weight * scalar);
+    }
+
+    /// Makes sure that there are scalars for n generators of m parties.
+    fn reserve_gens(&mut self, n: usize, m: usize) {
+        for scalars in [&mut self.g, &mut self.h] {
+            if scalars.len() < m {
+                scalars.resize(m, Vec::new());
+            }
+            for party in scalars.iter_mut().take(m) {
+                if party.len() < n {
+                    party.resize(n, Scalar::zero());
+                }
+            }
+        }
+    }
+
+    fn verify(self) -> bool {
+        let pc_gens = PedersenGens::default();
+        let mut scalars = self.scalars;
+        let mut points: Vec<Option<RistrettoPoint>> =
+            self.points.iter().map(|point| point.decompress()).collect();
+        scalars.extend([self.b, self.b_blinding]);
+        points.extend([Some(pc_gens.B), Some(pc_gens.B_blinding)]);
+        for (label, gens) in [(b'G', self.g), (b'H', self.h)] {
+            for (party, party_scalars) in gens.into_iter().enumerate() {
+                points.extend(
+                    generators(label, party, party_scalars.len())
+                        .into_iter()
+                        .map(Some),
+                );
+                scalars.extend(party_scalars);
+            }
+        }
+
+        // the lengths of both iterators must be known to the multiscalar multiplication
+        let check = RistrettoPoint::optional_multiscalar_mul(scalars, points);
+        check.is_some_and(|point| point.is_identity())
+    }
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+/// Returns the first `num` generators of the specified party, derived in the same way as by the
+/// `BulletproofGens` of the Bulletproofs lib.
+fn generators(label: u8, party: usize, num: usize) -> Vec<RistrettoPoint> {
+    let mut shake = Shake256::default();
+    shake.update(b"GeneratorsChain");
+    shake.update([label]);
+    shake.update((party as u32).to_le_bytes());
+    let mut reader = shake.finalize_xof();
+    (0..num)
+        .map(|_| {
+            let mut uniform_bytes = [0u8; 64];
+            reader.read(&mut uniform_bytes);
+            RistrettoPoint::from_uniform_bytes(&uniform_bytes)
+        })
+        .collect()
+}
+
+/// Returns an iterator over 1, x, x^2, ...
+fn powers(x: Scalar) -> impl Iterator<Item = Scalar> {
+    iter::successors(Some(Scalar::one()), move |exp| Some(exp * x))
+}
+
+fn append_point(
+    transcript: &mut Transcript,
+    label: &'static [u8],
+    point: &CompressedRistretto,
+) -> Option<()> {
+    if point.is_identity() {
+        return None;
+    }
+    transcript.append_message(label, point.as_bytes());
+    Some(())
+}
+
+fn challenge_scalar(transcript: &mut Transcript, label: &'static [u8]) -> Scalar {
+    let mut bytes = [0u8; 64];
+    transcript.challenge_bytes(label, &mut bytes);
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
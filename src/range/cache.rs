@@ -0,0 +1,134 @@
+use bulletproofs::RangeProof;
+use smtree::index::TreeIndex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+// RANGE PROOF CACHE
+// ================================================================================================
+
+/// A size-bounded cache of range proofs shared by proof requests for the same tree.
+///
+/// Range proofs are bound to the root hash and to the positions of the nodes they cover (see
+/// [RangeProofContext](super::RangeProofContext)), and within a tree the positions determine the
+/// commitments of the nodes. Thus, a range proof is cached under the root hash, the positions of
+/// the covered nodes, the number of proven values (including values added to pad the proof to a
+/// power of two) and the bit width, and it can be handed out to any later request which needs a
+/// proof for the same siblings with the same aggregation layout.
+///
+/// When the cache is full, the least recently used proof is evicted.
+#[derive(Debug)]
+pub struct RangeProofCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, (RangeProof, u64)>,
+    usage: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(super) struct CacheKey {
+    pub root_hash: Vec<u8>,
+    pub positions: Vec<TreeIndex>,
+    pub num_values: usize,
+    pub bit_size: usize,
+}
+
+impl RangeProofCache {
+    /// Returns a new cache holding at most `capacity` range proofs.
+    pub fn new(capacity: usize) -> Self {
+        RangeProofCache {
+            capacity,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// Returns the maximum number of range proofs held by the cache.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of range proofs currently held by the cache.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Returns true if the cache holds no range proofs.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all range proofs from the cache.
+    pub fn clear(&self) {
+        let mut state = self.lock();
+        state.entries.clear();
+        state.usage.clear();
+    }
+
+    /// Returns the proof cached under the specified key, or generates the proof via `generate`
+    /// and caches it. The cache is not locked while the proof is generated, and so concurrent
+    /// requests for the same key may generate the proof more than once.
+    pub(super) fn get_or_generate<F: FnOnce() -> RangeProof>(
+        &self,
+        key: CacheKey,
+        generate: F,
+    ) -> RangeProof {
+        if let Some(proof) = self.lock().get(&key) {
+            return proof;
+        }
+        let proof = generate();
+        self.lock().insert(key, proof.clone(), self.capacity);
+        proof
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state
+            .lock()
+            .expect("range proof cache lock is poisoned")
+    }
+}
+
+impl CacheState {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn get(&mut self, key: &CacheKey) -> Option<RangeProof> {
+        let tick = self.next_tick();
+        let (proof, last_used) = self.entries.get_mut(key)?;
+        let key = self
+            .usage
+            .remove(last_used)
+            .expect("cached proof should have a usage record");
+        *last_used = tick;
+        let proof = proof.clone();
+        self.usage.insert(tick, key);
+        Some(proof)
+    }
+
+    fn insert(&mut self, key: CacheKey, proof: RangeProof, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (proof, tick)) {
+            self.usage.remove(&last_used);
+        }
+        self.usage.insert(tick, key);
+
+        // evict least recently used proofs
+        while self.entries.len() > capacity {
+            let (_, key) = self
+                .usage
+                .pop_first()
+                .expect("usage records should match cached proofs");
+            self.entries.remove(&key);
+        }
+    }
+}
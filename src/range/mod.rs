@@ -1,8 +1,9 @@
-use crate::utils::bytes_to_usize_with_error;
+use crate::utils::{bytes_to_usize_with_error, remaining_bytes};
 use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
 use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
 use merlin::Transcript;
-use smtree::error::DecodingError;
+use smtree::{error::DecodingError, index::TreeIndex};
+use std::ops::Range;
 
 mod padding;
 pub use padding::RangeProofPadding;
@@ -10,28 +11,52 @@ pub use padding::RangeProofPadding;
 mod splitting;
 pub use splitting::RangeProofSplitting;
 
-// The bit size of Bulletproofs,
+mod cache;
+use cache::CacheKey;
+pub use cache::RangeProofCache;
+
+mod batch;
+pub(crate) use batch::locate_invalid_range_statements;
+pub use batch::RangeStatement;
+
+#[cfg(test)]
+mod tests;
+
+// The default bit size of Bulletproofs,
 // i.e., the range proof proves the value in DAPOL is within [0, 2^BIT_SIZE).
-// Must be a power of 2 as limited by the Bulletproofs lib.
-const BIT_SIZE: usize = 64;
+pub(crate) const DEFAULT_BIT_SIZE: usize = 64;
 
-const SINGLE_PROOF_BYTE_NUM: usize = 672;
+// Supported bit sizes of Bulletproofs; must be powers of 2 as limited by the Bulletproofs lib.
+pub(crate) const VALID_BIT_SIZES: [usize; 4] = [8, 16, 32, 64];
+
+const BIT_SIZE_BYTE_NUM: usize = 1;
 const PROOF_SIZE_BYTE_NUM: usize = 8;
 const AGGREGATED_NUM_BYTE_NUM: usize = 2;
 const INDIVIDUAL_NUM_BYTE_NUM: usize = 8;
 
+const TRANSCRIPT_LABEL: &[u8] = b"DAPOL range proof";
+
 // TRAITS
 // ================================================================================================
 
-pub trait RangeProvable {
-    fn new(aggregated: &[RangeProof], individual: &[RangeProof]) -> Self;
+pub trait RangeProvable: Sized {
+    /// Returns range proofs made of the specified aggregated and individual proofs, or None if
+    /// the bit size is not supported or the proofs cannot be arranged this way.
+    fn new(aggregated: &[RangeProof], individual: &[RangeProof], bit_size: usize) -> Option<Self>;
 
-    fn generate_proof(secrets: &[u64], blindings: &[Scalar], aggregation_factor: usize) -> Self;
+    fn generate_proof(
+        secrets: &[u64],
+        blindings: &[Scalar],
+        context: &RangeProofContext,
+        aggregation_factor: usize,
+        bit_size: usize,
+    ) -> Self;
 
     fn generate_proof_by_new_com(
         &mut self,
         secrets: &[u64],
         blindings: &[Scalar],
+        context: &RangeProofContext,
         aggregation_factor: usize,
     );
 
@@ -39,39 +64,190 @@ pub trait RangeProvable {
 }
 
 pub trait RangeVerifiable {
-    fn verify(&self, commitments: &[CompressedRistretto]) -> bool;
+    /// Returns true if the values hidden by the specified commitments are within the range, and
+    /// the proofs were generated for the specified context.
+    fn verify(&self, commitments: &[CompressedRistretto], context: &RangeProofContext) -> bool {
+        self.get_statements(commitments, context)
+            .is_some_and(|statements| statements.iter().all(|statement| statement.verify()))
+    }
+
+    /// Returns the statements which the range proofs make about the specified commitments in the
+    /// specified context, or None if the proofs do not match the number of commitments.
+    fn get_statements(
+        &self,
+        commitments: &[CompressedRistretto],
+        context: &RangeProofContext,
+    ) -> Option<Vec<RangeStatement<'_>>>;
+
+    /// Returns the bit size of the range proofs, i.e., the proofs show that the committed values
+    /// are within [0, 2^bit_size).
+    fn get_bit_size(&self) -> usize;
+}
+
+// RANGE PROOF CONTEXT
+// ================================================================================================
+
+/// The context which Merlin transcripts of range proofs are bound to: the hash of the tree root,
+/// the height of the tree and the positions of the nodes whose commitments are covered by the
+/// proofs, one position per commitment.
+///
+/// The nodes covered by the range proofs of a Merkle proof are the siblings along the path from
+/// the leaf to the root. Transcripts are bound to the positions of the commitments they cover,
+/// and NOT to the position of the leaf the Merkle proof is generated for: a range proof covering
+/// only upper siblings is identical for all leaves below them, and so it can be shared by their
+/// proofs, e.g. via [RangeProofCache]. A range proof therefore cannot be replayed in another tree
+/// or for commitments at other positions, but it does not attest which user's leaf it was
+/// generated for; the leaf is tied to the Merkle proof only by the path itself.
+#[derive(Debug, Clone, Copy)]
+pub struct RangeProofContext<'a> {
+    root_hash: &'a [u8],
+    tree_height: usize,
+    positions: &'a [TreeIndex],
+    cache: Option<&'a RangeProofCache>,
+}
+
+impl<'a> RangeProofContext<'a> {
+    /// Returns a new context for a tree with the specified root hash and height, and for
+    /// commitments of the nodes at the specified positions.
+    pub fn new(root_hash: &'a [u8], tree_height: usize, positions: &'a [TreeIndex]) -> Self {
+        RangeProofContext {
+            root_hash,
+            tree_height,
+            positions,
+            cache: None,
+        }
+    }
+
+    /// Returns this context with range proofs taken from, and added to, the specified cache.
+    pub fn with_cache(mut self, cache: &'a RangeProofCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Returns the positions of the nodes whose commitments are covered by the range proofs.
+    pub fn positions(&self) -> &[TreeIndex] {
+        self.positions
+    }
+
+    /// Returns a transcript bound to this context and the positions in the specified range.
+    fn transcript(&self, range: Range<usize>) -> Transcript {
+        let mut transcript = Transcript::new(TRANSCRIPT_LABEL);
+        transcript.append_message(b"root_hash", self.root_hash);
+        transcript.append_u64(b"tree_height", self.tree_height as u64);
+        transcript.append_u64(b"num_positions", range.len() as u64);
+        for position in self.positions[range].iter() {
+            transcript.append_message(b"position", &TreeIndex::serialize(&[*position]));
+        }
+        transcript
+    }
+
+    /// Returns a range proof for a single value covering the node at the specified position.
+    fn prove_single(
+        &self,
+        secret: u64,
+        blinding: &Scalar,
+        bit_size: usize,
+        pos: usize,
+    ) -> RangeProof {
+        let generate = || {
+            generate_single_range_proof(secret, blinding, bit_size, self.transcript(pos..pos + 1))
+        };
+        match self.cache {
+            Some(cache) => {
+                cache.get_or_generate(self.cache_key(pos..pos + 1, 1, bit_size), generate)
+            }
+            None => generate(),
+        }
+    }
+
+    /// Returns an aggregated range proof covering the nodes at the positions in the specified
+    /// range; the proven values may include values padding the proof to a power of two.
+    fn prove_aggregated(
+        &self,
+        secrets: &[u64],
+        blindings: &[Scalar],
+        bit_size: usize,
+        range: Range<usize>,
+    ) -> RangeProof {
+        let generate = || {
+            generate_aggregated_range_proof(
+                secrets,
+                blindings,
+                bit_size,
+                self.transcript(range.clone()),
+            )
+        };
+        match self.cache {
+            Some(cache) => cache.get_or_generate(
+                self.cache_key(range.clone(), secrets.len(), bit_size),
+                generate,
+            ),
+            None => generate(),
+        }
+    }
+
+    fn cache_key(&self, range: Range<usize>, num_values: usize, bit_size: usize) -> CacheKey {
+        CacheKey {
+            root_hash: self.root_hash.to_vec(),
+            positions: self.positions[range].to_vec(),
+            num_values,
+            bit_size,
+        }
+    }
+}
+
+// BIT SIZE
+// ================================================================================================
+
+/// Returns true if range proofs can be generated for the specified bit size.
+pub(crate) fn is_valid_bit_size(bit_size: usize) -> bool {
+    VALID_BIT_SIZES.contains(&bit_size)
+}
+
+/// Returns the number of bytes in a serialized range proof for a single value; the proof consists
+/// of 9 + 2 * log2(bit_size) 32-byte elements.
+fn single_proof_byte_num(bit_size: usize) -> usize {
+    32 * (9 + 2 * bit_size.trailing_zeros() as usize)
 }
 
 // PROOF GENERATION
 // ================================================================================================
 
-fn generate_single_range_proof(secret: u64, blinding: &Scalar) -> RangeProof {
+pub(crate) fn generate_single_range_proof(
+    secret: u64,
+    blinding: &Scalar,
+    bit_size: usize,
+    mut prover_transcript: Transcript,
+) -> RangeProof {
     let pc_gens = PedersenGens::default();
-    let bp_gens = BulletproofGens::new(BIT_SIZE, 1);
-    let mut prover_transcript = Transcript::new(&[]);
+    let bp_gens = BulletproofGens::new(bit_size, 1);
     let (proof, _commitments) = RangeProof::prove_single(
         &bp_gens,
         &pc_gens,
         &mut prover_transcript,
         secret,
         blinding,
-        BIT_SIZE,
+        bit_size,
     )
     .expect("Error in generating aggregated range proof");
     proof
 }
 
-fn generate_aggregated_range_proof(secrets: &[u64], blindings: &[Scalar]) -> RangeProof {
+fn generate_aggregated_range_proof(
+    secrets: &[u64],
+    blindings: &[Scalar],
+    bit_size: usize,
+    mut prover_transcript: Transcript,
+) -> RangeProof {
     let pc_gens = PedersenGens::default();
-    let bp_gens = BulletproofGens::new(BIT_SIZE, secrets.len());
-    let mut prover_transcript = Transcript::new(&[]);
+    let bp_gens = BulletproofGens::new(bit_size, secrets.len());
     let (proof, _commitments) = RangeProof::prove_multiple(
         &bp_gens,
         &pc_gens,
         &mut prover_transcript,
-        &secrets,
-        &blindings,
-        BIT_SIZE,
+        secrets,
+        blindings,
+        bit_size,
     )
     .expect("Error in generating aggregated range proof");
     proof
@@ -80,17 +256,21 @@ fn generate_aggregated_range_proof(secrets: &[u64], blindings: &[Scalar]) -> Ran
 // PROOF VERIFICATION
 // ================================================================================================
 
-fn verify_single_range_proof(proof: &RangeProof, commitment: &CompressedRistretto) -> bool {
+pub(crate) fn verify_single_range_proof(
+    proof: &RangeProof,
+    commitment: &CompressedRistretto,
+    bit_size: usize,
+    mut verifier_transcript: Transcript,
+) -> bool {
     let pc_gens = PedersenGens::default();
-    let bp_gens = BulletproofGens::new(BIT_SIZE, 1);
-    let mut verifier_transcript = Transcript::new(&[]);
+    let bp_gens = BulletproofGens::new(bit_size, 1);
     if proof
         .verify_single(
             &bp_gens,
             &pc_gens,
             &mut verifier_transcript,
             commitment,
-            BIT_SIZE,
+            bit_size,
         )
         .is_err()
     {
@@ -99,17 +279,21 @@ fn verify_single_range_proof(proof: &RangeProof, commitment: &CompressedRistrett
     true
 }
 
-fn verify_aggregated_range_proof(proof: &RangeProof, commitments: &[CompressedRistretto]) -> bool {
+fn verify_aggregated_range_proof(
+    proof: &RangeProof,
+    commitments: &[CompressedRistretto],
+    bit_size: usize,
+    mut verifier_transcript: Transcript,
+) -> bool {
     let pc_gens = PedersenGens::default();
-    let bp_gens = BulletproofGens::new(BIT_SIZE, commitments.len());
-    let mut verifier_transcript = Transcript::new(&[]);
+    let bp_gens = BulletproofGens::new(bit_size, commitments.len());
     if proof
         .verify_multiple(
             &bp_gens,
             &pc_gens,
             &mut verifier_transcript,
             commitments,
-            BIT_SIZE,
+            bit_size,
         )
         .is_err()
     {
@@ -121,12 +305,22 @@ fn verify_aggregated_range_proof(proof: &RangeProof, commitments: &[CompressedRi
 // PROOF DESERIALIZATION
 // ================================================================================================
 
+fn deserialize_bit_size(bytes: &[u8], begin: &mut usize) -> Result<usize, DecodingError> {
+    let bit_size = bytes_to_usize_with_error(bytes, BIT_SIZE_BYTE_NUM, begin)?;
+    if !is_valid_bit_size(bit_size) {
+        return Err(DecodingError::ValueDecodingError {
+            msg: format!("Unsupported range proof bit size {}", bit_size),
+        });
+    }
+    Ok(bit_size)
+}
+
 fn deserialize_range_proof(
     bytes: &[u8],
     byte_num: usize,
     begin: &mut usize,
 ) -> Result<RangeProof, DecodingError> {
-    if bytes.len() - *begin < byte_num {
+    if remaining_bytes(bytes, *begin) < byte_num {
         return Err(DecodingError::BytesNotEnough);
     }
     let proof = RangeProof::from_bytes(&bytes[*begin..*begin + byte_num]).map_err(|e| {
@@ -149,12 +343,13 @@ fn deserialize_aggregated_proof(
 
 fn deserialize_individual_proofs(
     bytes: &[u8],
+    bit_size: usize,
     begin: &mut usize,
 ) -> Result<Vec<RangeProof>, DecodingError> {
     let mut individual: Vec<RangeProof> = Vec::new();
     let individual_num = bytes_to_usize_with_error(bytes, INDIVIDUAL_NUM_BYTE_NUM, begin)?;
     for _ in 0..individual_num {
-        let proof = deserialize_range_proof(bytes, SINGLE_PROOF_BYTE_NUM, begin)?;
+        let proof = deserialize_range_proof(bytes, single_proof_byte_num(bit_size), begin)?;
         individual.push(proof);
     }
     Ok(individual)
@@ -1,3 +1,4 @@
+use crate::utils::bytes_to_usize_with_error;
 use bulletproofs::{PedersenGens, RangeProof};
 use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
 use smtree::{
@@ -8,9 +9,9 @@ use smtree::{
 use std::cmp::Ordering;
 
 use super::{
-    deserialize_aggregated_proof, deserialize_individual_proofs, generate_aggregated_range_proof,
-    generate_single_range_proof, verify_aggregated_range_proof, verify_single_range_proof,
-    RangeProvable, RangeVerifiable, INDIVIDUAL_NUM_BYTE_NUM, PROOF_SIZE_BYTE_NUM,
+    deserialize_bit_size, deserialize_individual_proofs, deserialize_range_proof,
+    is_valid_bit_size, RangeProofContext, RangeProvable, RangeStatement, RangeVerifiable,
+    BIT_SIZE_BYTE_NUM, INDIVIDUAL_NUM_BYTE_NUM, PROOF_SIZE_BYTE_NUM,
 };
 
 // RANGE PROOF PADDING
@@ -20,14 +21,13 @@ use super::{
 pub struct RangeProofPadding {
     aggregated: Vec<RangeProof>,
     individual: Vec<RangeProof>,
+    bit_size: usize,
 }
 
 impl RangeProofPadding {
-    pub fn get_aggregated(&self) -> &RangeProof {
-        if self.aggregated.is_empty() {
-            panic!(); // TODO
-        }
-        &self.aggregated[0]
+    /// Returns the aggregated range proof, or None if it has not been generated yet.
+    pub fn get_aggregated(&self) -> Option<&RangeProof> {
+        self.aggregated.first()
     }
 
     pub fn get_individual(&self) -> &Vec<RangeProof> {
@@ -36,10 +36,15 @@ impl RangeProofPadding {
 }
 
 impl Serializable for RangeProofPadding {
-    /// (aggregated_size || aggregated_proof) || (individual_num || proof_1 || ...)
+    /// bit_size || (aggregated_size || aggregated_proof) || (individual_num || proof_1 || ...)
+    ///
+    /// The aggregated size is 0 if the aggregated proof has not been generated yet.
     fn serialize(&self) -> Vec<u8> {
         let mut result: Vec<u8> = Vec::new();
-        let mut bytes = self.get_aggregated().to_bytes();
+        result.append(&mut usize_to_bytes(self.bit_size, BIT_SIZE_BYTE_NUM));
+        let mut bytes = self
+            .get_aggregated()
+            .map_or_else(Vec::new, |proof| proof.to_bytes());
         result.append(&mut usize_to_bytes(bytes.len(), PROOF_SIZE_BYTE_NUM));
         result.append(&mut bytes);
         result.append(&mut usize_to_bytes(
@@ -53,15 +58,21 @@ impl Serializable for RangeProofPadding {
     }
 
     fn deserialize_as_a_unit(bytes: &[u8], begin: &mut usize) -> Result<Self, DecodingError> {
-        let aggregated = deserialize_aggregated_proof(&bytes, begin)?;
-        let individual = deserialize_individual_proofs(bytes, begin)?;
+        let bit_size = deserialize_bit_size(bytes, begin)?;
+        let size = bytes_to_usize_with_error(bytes, PROOF_SIZE_BYTE_NUM, begin)?;
+        let aggregated = match size {
+            0 => Vec::new(),
+            _ => vec![deserialize_range_proof(bytes, size, begin)?],
+        };
+        let individual = deserialize_individual_proofs(bytes, bit_size, begin)?;
         Ok(RangeProofPadding {
-            aggregated: vec![aggregated],
+            aggregated,
             individual,
+            bit_size,
         })
     }
 
-    /// (aggregated_size || aggregated_proof) || (individual_num || proof_1 || ...)
+    /// bit_size || (aggregated_size || aggregated_proof) || (individual_num || proof_1 || ...)
     fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
         let mut begin = 0;
         Self::deserialize_as_a_unit(bytes, &mut begin)
@@ -74,21 +85,40 @@ impl TypeName for RangeProofPadding {
     }
 }
 
+/// With the `serde` feature, range proofs are serialized as a hex string of their binary format.
+#[cfg(feature = "serde")]
+impl serde::Serialize for RangeProofPadding {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::utils::hex::serialize(&Serializable::serialize(self), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RangeProofPadding {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::utils::hex::decode(deserializer, <Self as Serializable>::deserialize)
+    }
+}
+
 impl RangeProvable for RangeProofPadding {
-    fn new(aggregated: &[RangeProof], individual: &[RangeProof]) -> Self {
-        if aggregated.len() > 1 {
-            panic!(); //TODO
+    fn new(aggregated: &[RangeProof], individual: &[RangeProof], bit_size: usize) -> Option<Self> {
+        // padding proofs consist of at most one aggregated proof
+        if aggregated.len() > 1 || !is_valid_bit_size(bit_size) {
+            return None;
         }
-        RangeProofPadding {
+        Some(RangeProofPadding {
             aggregated: aggregated.to_vec(),
             individual: individual.to_vec(),
-        }
+            bit_size,
+        })
     }
 
     fn generate_proof(
         _secrets: &[u64],
         _blindings: &[Scalar],
+        context: &RangeProofContext,
         aggregated: usize,
+        bit_size: usize,
     ) -> RangeProofPadding {
         let mut secrets = Vec::<u64>::new();
         let mut blindings = Vec::<Scalar>::new();
@@ -101,19 +131,29 @@ impl RangeProvable for RangeProofPadding {
             secrets.push(0);
             blindings.push(Scalar::one());
         }
-        let aggregated_proof =
-            generate_aggregated_range_proof(&secrets[0..power], &blindings[0..power]);
+        let aggregated_proof = context.prove_aggregated(
+            &secrets[0..power],
+            &blindings[0..power],
+            bit_size,
+            0..aggregated,
+        );
 
         let mut individual_proofs: Vec<RangeProof> = Vec::new();
         let mut pos = aggregated;
         while pos < _secrets.len() {
-            individual_proofs.push(generate_single_range_proof(_secrets[pos], &_blindings[pos]));
+            individual_proofs.push(context.prove_single(
+                _secrets[pos],
+                &_blindings[pos],
+                bit_size,
+                pos,
+            ));
             pos += 1;
         }
 
         RangeProofPadding {
             aggregated: vec![aggregated_proof],
             individual: individual_proofs,
+            bit_size,
         }
     }
 
@@ -121,14 +161,17 @@ impl RangeProvable for RangeProofPadding {
         &mut self,
         secrets: &[u64],
         blindings: &[Scalar],
+        context: &RangeProofContext,
         aggregation_factor: usize,
     ) {
         let len = secrets.len();
         match len.cmp(&aggregation_factor) {
             Ordering::Greater => {
-                self.individual.push(generate_single_range_proof(
+                self.individual.push(context.prove_single(
                     secrets[len - 1],
                     &blindings[len - 1],
+                    self.bit_size,
+                    len - 1,
                 ));
             }
             Ordering::Equal => {
@@ -143,9 +186,11 @@ impl RangeProvable for RangeProofPadding {
                     _secrets.push(0);
                     _blindings.push(Scalar::one());
                 }
-                self.aggregated.push(generate_aggregated_range_proof(
+                self.aggregated.push(context.prove_aggregated(
                     &_secrets[..],
                     &_blindings[..],
+                    self.bit_size,
+                    0..len,
                 ));
             }
             _ => {}
@@ -166,9 +211,16 @@ impl RangeProvable for RangeProofPadding {
 }
 
 impl RangeVerifiable for RangeProofPadding {
-    fn verify(&self, _commitments: &[CompressedRistretto]) -> bool {
+    fn get_statements(
+        &self,
+        _commitments: &[CompressedRistretto],
+        context: &RangeProofContext,
+    ) -> Option<Vec<RangeStatement<'_>>> {
+        if context.positions().len() != _commitments.len() {
+            return None;
+        }
         let mut commitments = Vec::<CompressedRistretto>::new();
-        let aggregated = _commitments.len() - self.individual.len();
+        let aggregated = _commitments.len().checked_sub(self.individual.len())?;
         for item in _commitments.iter().take(aggregated) {
             commitments.push(*item);
         }
@@ -178,20 +230,30 @@ impl RangeVerifiable for RangeProofPadding {
         for _i in aggregated..power {
             commitments.push(com_padding);
         }
-        if !verify_aggregated_range_proof(&self.get_aggregated(), &commitments[0..power]) {
-            return false;
-        }
+        let mut statements = vec![RangeStatement::new(
+            self.get_aggregated()?,
+            commitments,
+            self.bit_size,
+            context.transcript(0..aggregated),
+        )];
 
         let mut idx = 0;
         let mut pos = aggregated;
         while pos < _commitments.len() {
-            if !verify_single_range_proof(&self.individual[idx], &_commitments[pos]) {
-                return false;
-            }
+            statements.push(RangeStatement::new(
+                self.individual.get(idx)?,
+                vec![_commitments[pos]],
+                self.bit_size,
+                context.transcript(pos..pos + 1),
+            ));
             idx += 1;
             pos += 1;
         }
 
-        true
+        Some(statements)
+    }
+
+    fn get_bit_size(&self) -> usize {
+        self.bit_size
     }
 }
@@ -8,10 +8,9 @@ use smtree::{
 };
 
 use super::{
-    deserialize_aggregated_proof, deserialize_individual_proofs, generate_aggregated_range_proof,
-    generate_single_range_proof, verify_aggregated_range_proof, verify_single_range_proof,
-    RangeProvable, RangeVerifiable, AGGREGATED_NUM_BYTE_NUM, INDIVIDUAL_NUM_BYTE_NUM,
-    PROOF_SIZE_BYTE_NUM,
+    deserialize_aggregated_proof, deserialize_bit_size, deserialize_individual_proofs,
+    is_valid_bit_size, RangeProofContext, RangeProvable, RangeStatement, RangeVerifiable,
+    AGGREGATED_NUM_BYTE_NUM, BIT_SIZE_BYTE_NUM, INDIVIDUAL_NUM_BYTE_NUM, PROOF_SIZE_BYTE_NUM,
 };
 
 // RANGE PROOF SPLITTING
@@ -21,6 +20,7 @@ use super::{
 pub struct RangeProofSplitting {
     aggregated: Vec<RangeProof>,
     individual: Vec<RangeProof>,
+    bit_size: usize,
 }
 
 impl RangeProofSplitting {
@@ -34,9 +34,11 @@ impl RangeProofSplitting {
 }
 
 impl Serializable for RangeProofSplitting {
-    /// (aggregated_num || (size_1 || proof_1) || ...) || (individual_num || proof_1 || ...)
+    /// bit_size || (aggregated_num || (size_1 || proof_1) || ...)
+    ///     || (individual_num || proof_1 || ...)
     fn serialize(&self) -> Vec<u8> {
         let mut result = Vec::new();
+        result.append(&mut usize_to_bytes(self.bit_size, BIT_SIZE_BYTE_NUM));
         // append aggregated proofs to the result
         result.append(&mut usize_to_bytes(
             self.get_aggregated().len(),
@@ -59,6 +61,8 @@ impl Serializable for RangeProofSplitting {
     }
 
     fn deserialize_as_a_unit(bytes: &[u8], begin: &mut usize) -> Result<Self, DecodingError> {
+        let bit_size = deserialize_bit_size(bytes, begin)?;
+
         // parse aggregated proofs
         let mut aggregated: Vec<RangeProof> = Vec::new();
         let aggregated_num = bytes_to_usize_with_error(bytes, AGGREGATED_NUM_BYTE_NUM, begin)?;
@@ -68,15 +72,17 @@ impl Serializable for RangeProofSplitting {
         }
 
         // parse individual proofs
-        let individual = deserialize_individual_proofs(bytes, begin)?;
+        let individual = deserialize_individual_proofs(bytes, bit_size, begin)?;
 
         Ok(RangeProofSplitting {
             aggregated,
             individual,
+            bit_size,
         })
     }
 
-    /// (aggregated_num || (size_1 || proof_1) || ...) || (individual_num || proof_1 || ...)
+    /// bit_size || (aggregated_num || (size_1 || proof_1) || ...)
+    ///     || (individual_num || proof_1 || ...)
     fn deserialize(bytes: &[u8]) -> Result<Self, DecodingError> {
         let mut begin = 0;
         Self::deserialize_as_a_unit(bytes, &mut begin)
@@ -89,27 +95,50 @@ impl TypeName for RangeProofSplitting {
     }
 }
 
+/// With the `serde` feature, range proofs are serialized as a hex string of their binary format.
+#[cfg(feature = "serde")]
+impl serde::Serialize for RangeProofSplitting {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::utils::hex::serialize(&Serializable::serialize(self), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for RangeProofSplitting {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::utils::hex::decode(deserializer, <Self as Serializable>::deserialize)
+    }
+}
+
 impl RangeProvable for RangeProofSplitting {
-    fn new(aggregated: &[RangeProof], individual: &[RangeProof]) -> Self {
-        RangeProofSplitting {
+    fn new(aggregated: &[RangeProof], individual: &[RangeProof], bit_size: usize) -> Option<Self> {
+        if !is_valid_bit_size(bit_size) {
+            return None;
+        }
+        Some(RangeProofSplitting {
             aggregated: aggregated.to_vec(),
             individual: individual.to_vec(),
-        }
+            bit_size,
+        })
     }
 
     fn generate_proof(
         secrets: &[u64],
         blindings: &[Scalar],
+        context: &RangeProofContext,
         aggregated: usize,
+        bit_size: usize,
     ) -> RangeProofSplitting {
         let mut aggregated_proofs: Vec<RangeProof> = Vec::new();
         let mut base = aggregated.next_power_of_two();
         let mut pos = 0usize;
         while pos < aggregated {
             if aggregated & base > 0 {
-                aggregated_proofs.push(generate_aggregated_range_proof(
+                aggregated_proofs.push(context.prove_aggregated(
                     &secrets[pos..pos + base],
                     &blindings[pos..pos + base],
+                    bit_size,
+                    pos..pos + base,
                 ));
                 pos += base;
             }
@@ -118,13 +147,19 @@ impl RangeProvable for RangeProofSplitting {
 
         let mut individual_proofs: Vec<RangeProof> = Vec::new();
         while pos < secrets.len() {
-            individual_proofs.push(generate_single_range_proof(secrets[pos], &blindings[pos]));
+            individual_proofs.push(context.prove_single(
+                secrets[pos],
+                &blindings[pos],
+                bit_size,
+                pos,
+            ));
             pos += 1;
         }
 
         RangeProofSplitting {
             aggregated: aggregated_proofs,
             individual: individual_proofs,
+            bit_size,
         }
     }
 
@@ -132,13 +167,16 @@ impl RangeProvable for RangeProofSplitting {
         &mut self,
         secrets: &[u64],
         blindings: &[Scalar],
+        context: &RangeProofContext,
         aggregation_factor: usize,
     ) {
         let len = secrets.len();
         if len > aggregation_factor {
-            self.individual.push(generate_single_range_proof(
+            self.individual.push(context.prove_single(
                 secrets[len - 1],
                 &blindings[len - 1],
+                self.bit_size,
+                len - 1,
             ));
         } else {
             let mut base = aggregation_factor.next_power_of_two();
@@ -146,9 +184,11 @@ impl RangeProvable for RangeProofSplitting {
             while pos < len {
                 if base & aggregation_factor > 0 {
                     if pos + base == len {
-                        self.aggregated.push(generate_aggregated_range_proof(
+                        self.aggregated.push(context.prove_aggregated(
                             &secrets[pos..pos + base],
                             &blindings[pos..pos + base],
+                            self.bit_size,
+                            pos..pos + base,
                         ));
                     }
                     pos += base;
@@ -178,34 +218,53 @@ impl RangeProvable for RangeProofSplitting {
 }
 
 impl RangeVerifiable for RangeProofSplitting {
-    fn verify(&self, commitments: &[CompressedRistretto]) -> bool {
-        let aggregated = commitments.len() - self.individual.len();
+    fn get_statements(
+        &self,
+        commitments: &[CompressedRistretto],
+        context: &RangeProofContext,
+    ) -> Option<Vec<RangeStatement<'_>>> {
+        if context.positions().len() != commitments.len() {
+            return None;
+        }
+        let mut statements = Vec::new();
+        let aggregated = commitments.len().checked_sub(self.individual.len())?;
         let mut base = aggregated.next_power_of_two();
         let mut pos = 0usize;
         let mut idx = 0usize;
         while pos < aggregated {
             if aggregated & base > 0 {
-                if !verify_aggregated_range_proof(
-                    &self.aggregated[idx],
-                    &commitments[pos..pos + base],
-                ) {
-                    return false;
-                }
+                statements.push(RangeStatement::new(
+                    self.aggregated.get(idx)?,
+                    commitments[pos..pos + base].to_vec(),
+                    self.bit_size,
+                    context.transcript(pos..pos + base),
+                ));
                 idx += 1;
                 pos += base;
             }
             base >>= 1;
         }
+        // there should be no aggregated proofs left over
+        if idx != self.aggregated.len() {
+            return None;
+        }
 
         idx = 0;
         while pos < commitments.len() {
-            if !verify_single_range_proof(&self.individual[idx], &commitments[pos]) {
-                return false;
-            }
+            statements.push(RangeStatement::new(
+                self.individual.get(idx)?,
+                vec![commitments[pos]],
+                self.bit_size,
+                context.transcript(pos..pos + 1),
+            ));
             idx += 1;
             pos += 1;
         }
 
-        true
+        Some(statements)
+    }
+
+    fn get_bit_size(&self) -> usize {
+        self.bit_size
     }
 }
@@ -0,0 +1,96 @@
+use super::{locate_invalid_range_statements, RangeStatement};
+use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
+use curve25519_dalek_ng::{ristretto::CompressedRistretto, scalar::Scalar};
+use merlin::Transcript;
+use rand::{thread_rng, Rng};
+
+// BATCH VERIFICATION
+// ================================================================================================
+
+#[test]
+fn test_batch_verification_matches_bulletproofs() {
+    // batch verification replays the verifier of the Bulletproofs lib, and so it must accept and
+    // reject exactly the same statements as the lib does, for all bit sizes and party counts
+    let mut proofs = Vec::new();
+    for &bit_size in [8, 16, 32, 64].iter() {
+        for &num_parties in [1, 2, 4].iter() {
+            proofs.push(prove(bit_size, num_parties, b"valid"));
+        }
+    }
+    let other = prove(8, 1, b"valid");
+
+    let mut statements = Vec::new();
+    for (proof, commitments, bit_size) in proofs.iter() {
+        // valid statement
+        statements.push(statement(proof, commitments.clone(), *bit_size, b"valid"));
+
+        // statement for a different transcript
+        statements.push(statement(proof, commitments.clone(), *bit_size, b"other"));
+
+        // statement for a different commitment
+        let mut tampered = commitments.clone();
+        tampered[0] = other.1[0];
+        statements.push(statement(proof, tampered, *bit_size, b"valid"));
+
+        // statement with the commitments in a different order
+        if commitments.len() > 1 {
+            let mut reversed = commitments.clone();
+            reversed.reverse();
+            statements.push(statement(proof, reversed, *bit_size, b"valid"));
+        }
+    }
+
+    let expected: Vec<usize> = statements
+        .iter()
+        .enumerate()
+        .filter(|(_, statement)| !statement.verify())
+        .map(|(i, _)| i)
+        .collect();
+    assert_eq!(statements.len() - proofs.len(), expected.len());
+
+    let groups: Vec<Vec<RangeStatement>> = statements.into_iter().map(|s| vec![s]).collect();
+    assert_eq!(expected, locate_invalid_range_statements(&groups));
+
+    // valid statements should also pass when all of them are checked in a single batch
+    let valid: Vec<RangeStatement> = proofs
+        .iter()
+        .map(|(proof, commitments, bit_size)| {
+            statement(proof, commitments.clone(), *bit_size, b"valid")
+        })
+        .collect();
+    assert!(locate_invalid_range_statements(&[valid]).is_empty());
+}
+
+// HELPER FUNCTIONS
+// ================================================================================================
+
+fn prove(
+    bit_size: usize,
+    num_parties: usize,
+    label: &'static [u8],
+) -> (RangeProof, Vec<CompressedRistretto>, usize) {
+    let mut rng = thread_rng();
+    let values: Vec<u64> = (0..num_parties)
+        .map(|_| rng.gen::<u64>() >> (64 - bit_size))
+        .collect();
+    let blindings: Vec<Scalar> = (0..num_parties).map(|_| Scalar::random(&mut rng)).collect();
+    let (proof, commitments) = RangeProof::prove_multiple(
+        &BulletproofGens::new(bit_size, num_parties),
+        &PedersenGens::default(),
+        &mut Transcript::new(label),
+        &values,
+        &blindings,
+        bit_size,
+    )
+    .unwrap();
+    (proof, commitments, bit_size)
+}
+
+fn statement<'a>(
+    proof: &'a RangeProof,
+    commitments: Vec<CompressedRistretto>,
+    bit_size: usize,
+    label: &'static [u8],
+) -> RangeStatement<'a> {
+    RangeStatement::new(proof, commitments, bit_size, Transcript::new(label))
+}
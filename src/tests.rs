@@ -19,8 +19,8 @@ pub struct TesterDapol<D, R> {
 }
 
 impl<
-        D: Digest + Default + Clone + TypeName + Debug,
-        R: Clone + Serializable + RangeProvable + RangeVerifiable + TypeName,
+        D: Digest + Default + Clone + TypeName + Debug + Send + Sync,
+        R: Clone + Serializable + RangeProvable + RangeVerifiable + TypeName + Send + Sync,
     > TesterDapol<D, R>
 {
     pub fn test() {
@@ -122,6 +122,6 @@ fn prove_n_verify() {
     for node in nodes.iter() {
         let proof = dapol.generate_proof(&node.0).unwrap();
         let result = proof.verify(&dapol.root(), &node.1.get_proof_node());
-        assert_eq!(true, result);
+        assert!(result);
     }
 }
@@ -10,12 +10,18 @@ pub fn bytes_to_usize_with_error(
     byte_num: usize,
     begin: &mut usize,
 ) -> Result<usize, DecodingError> {
-    if bytes.len() < byte_num {
+    if remaining_bytes(bytes, *begin) < byte_num {
         return Err(DecodingError::BytesNotEnough);
     }
     bytes_to_usize(bytes, byte_num, begin)
 }
 
+/// Returns the number of bytes left to decode after position `begin`, which is 0 if `begin` is
+/// past the end of the bytes.
+pub(crate) fn remaining_bytes(bytes: &[u8], begin: usize) -> usize {
+    bytes.len().saturating_sub(begin)
+}
+
 pub fn get_secret() -> Secret {
     // building secret from bytes here because DAPOL and smtree use different
     // version of rand crate.
@@ -24,3 +30,40 @@ pub fn get_secret() -> Secret {
     let bytes: [u8; SECRET_LENGTH] = rng.gen();
     Secret::from_bytes(&bytes).expect("failed to build secret from bytes")
 }
+
+/// Serde helpers encoding byte strings as lowercase hex strings; can be used with
+/// `#[serde(with = "crate::utils::hex")]`.
+#[cfg(feature = "serde")]
+pub(crate) mod hex {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use smtree::error::DecodingError;
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 || !hex.is_ascii() {
+            return Err(D::Error::custom("invalid hex string"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                u8::from_str_radix(&hex[i..i + 2], 16)
+                    .map_err(|_| D::Error::custom("invalid hex string"))
+            })
+            .collect()
+    }
+
+    /// Deserializes a hex string and decodes the resulting bytes with the specified function.
+    pub fn decode<'de, D, T, F>(deserializer: D, decode: F) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        F: FnOnce(&[u8]) -> Result<T, DecodingError>,
+    {
+        let bytes = deserialize(deserializer)?;
+        decode(&bytes).map_err(|err| D::Error::custom(format!("{:?}", err)))
+    }
+}